@@ -0,0 +1,69 @@
+use actix_web::{error::BlockingError, http::StatusCode, HttpResponse, ResponseError};
+use serde::Serialize;
+use serde_json::json;
+use std::fmt;
+use std::io;
+
+/// 某个字段没通过校验的原因
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+/// handler 返回的错误, 响应体是 `{"error": "..."}` 格式的 JSON
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    /// 每个没通过校验的字段一条, 响应里会带上 `fields`
+    Invalid(Vec<FieldError>),
+    NotFound(String),
+    Conflict(String),
+    Internal(String),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::Internal(message) => write!(f, "{}", message),
+            ApiError::Invalid(_) => write!(f, "validation failed"),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let body = match self {
+            ApiError::Invalid(fields) => json!({ "error": self.to_string(), "fields": fields }),
+            _ => json!({ "error": self.to_string() }),
+        };
+
+        HttpResponse::build(self.status_code()).json(body)
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> Self {
+        log::error!("failed to write the store: {}", e);
+        ApiError::Internal("failed to save the data".to_string())
+    }
+}
+
+impl From<BlockingError> for ApiError {
+    fn from(_: BlockingError) -> Self {
+        ApiError::Internal("the blocking thread pool is gone".to_string())
+    }
+}
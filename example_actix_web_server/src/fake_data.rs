@@ -0,0 +1,61 @@
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::Deserialize;
+use std::env;
+use std::sync::Mutex;
+
+/// 生成假数据用的随机数生成器
+///
+/// 设置了 `MOCK_SEED`(环境变量或者 .env)时按它播种, 同样的 seed 和同样的请求顺序
+/// 每次启动生成的数据都一样, 包括第一次启动时写进 store 的用户; 没设置时和以前一样是随机的.
+/// 单个请求也可以带 `?seed=`, 这时只用这个 seed, 和其他请求无关, 结果总是一样的.
+///
+/// 同一个 seed 的结果只在 rand 版本不变时稳定(`StdRng` 的算法可能随版本变).
+pub struct FakeData {
+    seed: Option<u64>,
+    rng: Mutex<StdRng>,
+}
+
+/// 带在查询参数里的 seed
+#[derive(Debug, Deserialize)]
+pub struct SeedQuery {
+    pub seed: Option<u64>,
+}
+
+impl FakeData {
+    pub fn new(seed: Option<u64>) -> FakeData {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        FakeData {
+            seed,
+            rng: Mutex::new(rng),
+        }
+    }
+
+    /// 读 `MOCK_SEED`, 不是数字时报错而不是悄悄退回随机模式
+    pub fn from_env() -> Result<FakeData, String> {
+        match env::var("MOCK_SEED") {
+            Ok(seed) => seed
+                .trim()
+                .parse()
+                .map(|seed| FakeData::new(Some(seed)))
+                .map_err(|_| format!("MOCK_SEED must be an unsigned integer, got `{}`", seed)),
+            Err(_) => Ok(FakeData::new(None)),
+        }
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// 给了 `seed` 时用一个按它新播种的生成器, 否则用共享的那个
+    pub fn with_rng<R>(&self, seed: Option<u64>, f: impl FnOnce(&mut StdRng) -> R) -> R {
+        match seed {
+            Some(seed) => f(&mut StdRng::seed_from_u64(seed)),
+            None => f(&mut self.rng.lock().unwrap()),
+        }
+    }
+}
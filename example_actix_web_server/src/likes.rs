@@ -0,0 +1,393 @@
+use crate::error::{ApiError, FieldError};
+use crate::fake_data::FakeData;
+use crate::store::JsonStore;
+use crate::users::{self, UserStore};
+use actix_web::{get, post, put, web, HttpResponse};
+use fake::{faker::boolean::en::Boolean, Fake};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// 以前的接口没有 post_id, 不带时都算这一个帖子
+const DEFAULT_POST_ID: &str = "default";
+
+pub type LikeStore = JsonStore<PostLikes>;
+
+/// 一个帖子被哪些用户点了赞, 按 post_id 存
+///
+/// 点赞数就是 `user_ids` 的个数, 不单独存, 所以不会和实际的点赞对不上.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PostLikes {
+    post_id: String,
+    user_ids: BTreeSet<String>,
+}
+
+/// 返回给前端的点赞状态, `has_liked` 是请求里那个用户的
+#[derive(Debug, Serialize)]
+struct Like {
+    post_id: String,
+    like_count: usize,
+    has_liked: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct LikeQuery {
+    post_id: Option<String>,
+    user_id: Option<String>,
+    seed: Option<u64>,
+}
+
+/// `POST /like` 和 `PUT /like` 的请求体
+///
+/// POST 时 `has_liked` 是前端看到的当前状态, 和 server 上的一样才切换;
+/// PUT 时是想要的状态, 重复发也只生效一次.
+/// 以前的请求体 `{has_liked, like_count}` 没有 `user_id`, 现在会回 422, 见 README;
+/// `like_count` 忽略.
+#[derive(Debug, Deserialize)]
+struct LikeInput {
+    // 不带时是空的, 在 validate 里给出比 serde 更清楚的错误
+    #[serde(default)]
+    user_id: String,
+    post_id: Option<String>,
+    has_liked: bool,
+}
+
+impl LikeInput {
+    fn post_id(&self) -> &str {
+        self.post_id.as_deref().unwrap_or(DEFAULT_POST_ID)
+    }
+
+    // 用户是否存在要在改点赞的时候检查, 见 `update`
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut errors = Vec::new();
+
+        if self.user_id.trim().is_empty() {
+            errors.push(FieldError {
+                field: "user_id",
+                message: "is required, likes are tracked per user".to_string(),
+            });
+        }
+        if self.post_id().trim().is_empty() || self.post_id().chars().count() > 100 {
+            errors.push(FieldError {
+                field: "post_id",
+                message: "must be 1 to 100 characters long".to_string(),
+            });
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(ApiError::Invalid(errors)),
+        }
+    }
+}
+
+impl PostLikes {
+    fn like(&self, user_id: Option<&str>) -> Like {
+        Like {
+            post_id: self.post_id.clone(),
+            like_count: self.user_ids.len(),
+            has_liked: user_id.is_some_and(|user_id| self.user_ids.contains(user_id)),
+        }
+    }
+
+    fn set(&mut self, user_id: &str, liked: bool) {
+        match liked {
+            true => self.user_ids.insert(user_id.to_string()),
+            false => self.user_ids.remove(user_id),
+        };
+    }
+}
+
+// 只有存在的用户才能点赞. 检查和修改都在用户 store 的读锁里做, 删除用户要拿写锁,
+// 所以不会在检查之后, 点赞之前把用户删掉, 留下一个不存在的用户的赞.
+// 改完之后没人点赞的帖子直接删掉, 文件里不留空记录
+fn update(
+    store: &LikeStore,
+    user_store: &UserStore,
+    input: &LikeInput,
+    f: impl FnOnce(&mut PostLikes) -> Result<(), ApiError>,
+) -> Result<Like, ApiError> {
+    let post_id = input.post_id().to_string();
+
+    user_store.read(|registered| {
+        if !registered.contains_key(&input.user_id) {
+            return Err(users::not_found(&input.user_id));
+        }
+        store.write(|posts| {
+            let mut post = posts.remove(&post_id).unwrap_or_else(|| PostLikes {
+                post_id: post_id.clone(),
+                ..Default::default()
+            });
+            let result = f(&mut post);
+            let like = post.like(Some(&input.user_id));
+            if !post.user_ids.is_empty() {
+                posts.insert(post_id, post);
+            }
+            result.map(|_| like)
+        })
+    })
+}
+
+// POST 的修改: 前端看到的状态已经过期(另一个标签页改过, 或者重试了同一个请求)时返回 409,
+// 不会切换两次, 前端重新 GET 一下就能拿到最新的状态
+fn toggle(post: &mut PostLikes, input: &LikeInput) -> Result<(), ApiError> {
+    if post.user_ids.contains(&input.user_id) != input.has_liked {
+        return Err(ApiError::Conflict(format!(
+            "user `{}` has {} post `{}`",
+            input.user_id,
+            match input.has_liked {
+                true => "not liked",
+                false => "already liked",
+            },
+            post.post_id,
+        )));
+    }
+    post.set(&input.user_id, !input.has_liked);
+    Ok(())
+}
+
+// PUT 的修改: 直接设置成想要的状态, 已经是这个状态时什么也不改
+fn set(post: &mut PostLikes, input: &LikeInput) -> Result<(), ApiError> {
+    post.set(&input.user_id, input.has_liked);
+    Ok(())
+}
+
+/// 删除用户时去掉这个用户点过的所有赞
+///
+/// 调用方要拿着用户 store 的写锁, 这样删除的过程中不会有新的赞.
+pub fn forget_user(store: &LikeStore, user_id: &str) -> Result<(), ApiError> {
+    if !store.read(|posts| posts.values().any(|post| post.user_ids.contains(user_id))) {
+        return Ok(());
+    }
+
+    store.write(|posts| {
+        for post in posts.values_mut() {
+            post.user_ids.remove(user_id);
+        }
+        posts.retain(|_, post| !post.user_ids.is_empty());
+        Ok(())
+    })
+}
+
+// 不带 user_id 时 has_liked 总是 false
+// 带了 ?seed= 时和以前一样返回按这个 seed 生成的点赞状态, 不读 store, 这时不能带 user_id
+#[get("/like")]
+async fn get_like(
+    store: web::Data<LikeStore>,
+    fake: web::Data<FakeData>,
+    query: web::Query<LikeQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let post_id = query.post_id.as_deref().unwrap_or(DEFAULT_POST_ID);
+
+    if let Some(seed) = query.seed {
+        if query.user_id.is_some() {
+            return Err(ApiError::BadRequest(
+                "seed cannot be combined with user_id".to_string(),
+            ));
+        }
+        let like = fake.with_rng(Some(seed), |rng| Like {
+            post_id: post_id.to_string(),
+            like_count: rng.gen_range(100..200),
+            has_liked: Boolean(1).fake_with_rng(rng),
+        });
+        return Ok(HttpResponse::Ok().json(like));
+    }
+
+    let like = store.read(|posts| match posts.get(post_id) {
+        Some(post) => post.like(query.user_id.as_deref()),
+        None => Like {
+            post_id: post_id.to_string(),
+            like_count: 0,
+            has_liked: false,
+        },
+    });
+    Ok(HttpResponse::Ok().json(like))
+}
+
+// 切换点赞状态, 见 `toggle`
+#[post("/like")]
+async fn toggle_like(
+    store: web::Data<LikeStore>,
+    user_store: web::Data<UserStore>,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let input: LikeInput = users::parse_json(&body)?;
+    input.validate()?;
+
+    let like = web::block(move || update(&store, &user_store, &input, |post| toggle(post, &input)))
+        .await??;
+
+    Ok(HttpResponse::Ok().json(like))
+}
+
+// 设置点赞状态, 见 `set`
+#[put("/like")]
+async fn set_like(
+    store: web::Data<LikeStore>,
+    user_store: web::Data<UserStore>,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let input: LikeInput = users::parse_json(&body)?;
+    input.validate()?;
+
+    let like =
+        web::block(move || update(&store, &user_store, &input, |post| set(post, &input))).await??;
+
+    Ok(HttpResponse::Ok().json(like))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_like).service(toggle_like).service(set_like);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // 每个测试用自己的文件, 并行跑的时候互不影响
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("likes_{}_{}.json", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn like_store(name: &str, posts: &[(&str, &[&str])]) -> LikeStore {
+        let store = LikeStore::open(temp_path(name)).unwrap();
+        store
+            .write(|records| {
+                for (post_id, user_ids) in posts {
+                    let post = PostLikes {
+                        post_id: post_id.to_string(),
+                        user_ids: user_ids.iter().map(|id| id.to_string()).collect(),
+                    };
+                    records.insert(post_id.to_string(), post);
+                }
+                Ok::<_, ApiError>(())
+            })
+            .unwrap();
+        store
+    }
+
+    fn user_store(name: &str, user_ids: &[&str]) -> UserStore {
+        let store = UserStore::open(temp_path(name)).unwrap();
+        store
+            .write(|records| {
+                for user_id in user_ids {
+                    let user = users::User {
+                        user_id: user_id.to_string(),
+                        username: user_id.to_string(),
+                        full_name: String::new(),
+                        biography: String::new(),
+                        email: format!("{}@example.com", user_id),
+                        profile_pic_url: String::new(),
+                        follower_count: 0,
+                        follow_count: 0,
+                    };
+                    records.insert(user_id.to_string(), user);
+                }
+                Ok::<_, ApiError>(())
+            })
+            .unwrap();
+        store
+    }
+
+    fn input(user_id: &str, post_id: &str) -> LikeInput {
+        LikeInput {
+            user_id: user_id.to_string(),
+            post_id: Some(post_id.to_string()),
+            has_liked: true,
+        }
+    }
+
+    fn like_counts(store: &LikeStore) -> Vec<(String, usize)> {
+        store.read(|posts| {
+            posts
+                .iter()
+                .map(|(post_id, post)| (post_id.clone(), post.user_ids.len()))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn rejects_the_old_body_without_user_id() {
+        let input: LikeInput =
+            users::parse_json(br#"{"has_liked": false, "like_count": 120}"#).unwrap();
+
+        match input.validate() {
+            Err(ApiError::Invalid(fields)) => assert_eq!("user_id", fields[0].field),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn forgets_every_like_of_a_deleted_user() {
+        let store = like_store("forget", &[("a", &["ada", "grace"]), ("b", &["ada"])]);
+
+        forget_user(&store, "ada").unwrap();
+
+        assert_eq!(vec![("a".to_string(), 1)], like_counts(&store));
+        fs::remove_file(store.path()).unwrap();
+    }
+
+    #[test]
+    fn toggles_only_when_the_client_saw_the_current_state() {
+        let store = like_store("toggle", &[("a", &["grace"])]);
+        let user_store = user_store("toggle_users", &["ada"]);
+        let unliked = LikeInput {
+            has_liked: false,
+            ..input("ada", "a")
+        };
+
+        let like = update(&store, &user_store, &unliked, |post| toggle(post, &unliked)).unwrap();
+        assert!(like.has_liked);
+        assert_eq!(2, like.like_count);
+
+        // 同一个请求重试了一次, 前端看到的状态已经过期, 不会再切换回去
+        let result = update(&store, &user_store, &unliked, |post| toggle(post, &unliked));
+        assert!(matches!(result, Err(ApiError::Conflict(_))));
+        assert_eq!(vec![("a".to_string(), 2)], like_counts(&store));
+
+        let liked = input("ada", "a");
+        let like = update(&store, &user_store, &liked, |post| toggle(post, &liked)).unwrap();
+        assert!(!like.has_liked);
+        assert_eq!(1, like.like_count);
+
+        fs::remove_file(store.path()).unwrap();
+        fs::remove_file(user_store.path()).unwrap();
+    }
+
+    #[test]
+    fn put_sent_twice_is_applied_once() {
+        let store = like_store("put", &[]);
+        let user_store = user_store("put_users", &["ada"]);
+        let liked = input("ada", "a");
+
+        for _ in 0..2 {
+            let like = update(&store, &user_store, &liked, |post| set(post, &liked)).unwrap();
+            assert!(like.has_liked);
+            assert_eq!(1, like.like_count);
+        }
+        assert_eq!(vec![("a".to_string(), 1)], like_counts(&store));
+
+        fs::remove_file(store.path()).unwrap();
+        fs::remove_file(user_store.path()).unwrap();
+    }
+
+    #[test]
+    fn only_existing_users_can_like() {
+        let store = like_store("unknown_user", &[]);
+        let user_store = UserStore::open(temp_path("unknown_user_users")).unwrap();
+
+        let result = update(&store, &user_store, &input("ada", "a"), |post| {
+            post.set("ada", true);
+            Ok(())
+        });
+
+        assert!(matches!(result, Err(ApiError::NotFound(_))));
+        assert!(like_counts(&store).is_empty());
+        fs::remove_file(store.path()).unwrap();
+    }
+}
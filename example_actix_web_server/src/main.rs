@@ -3,18 +3,12 @@ use actix_multipart::Multipart;
 use actix_web::{
     get, http::header, middleware, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
-use actix_web_lab::sse;
+use actix_web_lab::{middleware::from_fn, sse};
 use dotenvy::dotenv;
-use fake::{
-    faker::boolean::en::Boolean,
-    faker::internet::en::{SafeEmail, Username},
-    faker::lorem::en::Paragraph,
-    faker::name::en::Name,
-    uuid::UUIDv4,
-    Fake,
-};
+use fake_data::FakeData;
 use futures::{StreamExt, TryStreamExt};
-use rand::Rng;
+use likes::LikeStore;
+use mock::MockConfig;
 use rs_openai::{
     chat::{ChatCompletionMessageRequestBuilder, CreateChatRequestBuilder, Role},
     OpenAI,
@@ -22,8 +16,19 @@ use rs_openai::{
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::env::var;
+use std::io::Write;
 use std::time::Duration;
-use std::{io::Write, thread};
+use users::UserStore;
+
+mod error;
+mod fake_data;
+mod likes;
+mod mock;
+mod store;
+mod users;
+
+// store 是空的时候生成多少个用户
+const SEED_USERS: usize = 20;
 
 #[derive(Serialize)]
 struct Message {
@@ -31,102 +36,12 @@ struct Message {
     body: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct User {
-    user_id: String,
-    username: String,
-    full_name: String,
-    biography: String,
-    email: String,
-    profile_pic_url: String,
-    follower_count: i32,
-    follow_count: i32,
-}
-
-#[derive(Serialize, Deserialize)]
-struct Like {
-    like_count: i32,
-    has_liked: bool,
-}
-
 #[derive(Debug, Deserialize)]
 struct Params {
     question: String,
     user_id: String,
 }
 
-fn generate_user() -> User {
-    User {
-        user_id: UUIDv4.fake(),
-        username: Username().fake(),
-        full_name: Name().fake(),
-        biography: Paragraph(3..5).fake(),
-        email: SafeEmail().fake(),
-        profile_pic_url: format!(
-            "https://dummyimage.com/{}x{}.png",
-            rand::thread_rng().gen_range(300..600),
-            rand::thread_rng().gen_range(300..600),
-        ),
-        follower_count: rand::thread_rng().gen_range(100..6000),
-        follow_count: rand::thread_rng().gen_range(100..6000),
-    }
-}
-
-#[get("/user")]
-async fn get_user() -> impl Responder {
-    thread::sleep(Duration::from_millis(1000));
-
-    let user = generate_user();
-    HttpResponse::Ok().json(user)
-}
-
-#[get("/users")]
-async fn get_users() -> impl Responder {
-    thread::sleep(Duration::from_millis(1000));
-
-    let rand_num = rand::thread_rng().gen_range(2..10);
-    let mut users: Vec<User> = vec![];
-
-    for _ in (1..=rand_num).rev() {
-        users.push(generate_user());
-    }
-
-    HttpResponse::Ok().json(users)
-}
-
-#[post("/user")]
-async fn create_user() -> impl Responder {
-    thread::sleep(Duration::from_millis(1000));
-
-    let user = generate_user();
-    HttpResponse::Ok().json(user)
-}
-
-#[get("/like")]
-async fn get_like() -> impl Responder {
-    thread::sleep(Duration::from_millis(1000));
-
-    HttpResponse::Ok().json(Like {
-        like_count: rand::thread_rng().gen_range(100..200),
-        has_liked: Boolean(1).fake(),
-    })
-}
-
-#[post("/like")]
-async fn handle_like(req: web::Json<Like>) -> impl Responder {
-    thread::sleep(Duration::from_millis(1000));
-
-    let like_count = match req.has_liked {
-        true => req.like_count - 1, // ✅
-        false => req.like_count + 1,
-    };
-
-    HttpResponse::Ok().json(Like {
-        like_count,
-        has_liked: !req.has_liked,
-    })
-}
-
 #[post("/upload")]
 async fn upload(mut payload: Multipart) -> impl Responder {
     // iterate over multipart stream
@@ -218,27 +133,66 @@ async fn create_chat(req: HttpRequest) -> impl Responder {
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    dotenv().ok();
+
+    // MOCK_SEED 让生成的假数据每次启动都一样
+    let fake = web::Data::new(FakeData::from_env().map_err(std::io::Error::other)?);
+    match fake.seed() {
+        Some(seed) => log::info!("generating fake data with seed {}", seed),
+        None => log::info!("generating random fake data, set MOCK_SEED to make it reproducible"),
+    }
+
+    // 用户存在 JSON 文件里, 重启之后还在
+    let path = var("USERS_FILE").unwrap_or_else(|_| "users.json".to_string());
+    let user_store = web::Data::new(UserStore::open(&path)?);
+    users::seed(&user_store, &fake, SEED_USERS)
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    log::info!("loaded users from {}", user_store.path().display());
+
+    // 点赞也存在文件里, 按帖子记录哪些用户点了赞
+    let path = var("LIKES_FILE").unwrap_or_else(|_| "likes.json".to_string());
+    let like_store = web::Data::new(LikeStore::open(&path)?);
+    log::info!("loaded likes from {}", like_store.path().display());
+
+    // 每个接口的延迟和故障, 请求头 X-Mock-Delay 之类的可以临时覆盖
+    let mock_config = web::Data::new(MockConfig::from_env()?);
+    match var("MOCK_CONFIG") {
+        Ok(path) => log::info!("loaded mock latency and faults from {}", path),
+        Err(_) => log::info!("using the default mock latency, set MOCK_CONFIG to change it"),
+    }
 
     log::info!("starting HTTP server at http://localhost:10086");
 
-    HttpServer::new(|| {
+    HttpServer::new(move || {
         App::new()
+            // 先注册的在里面, 预检请求不会被延迟, 注入的错误响应也会带上 CORS 头
+            .wrap(from_fn(mock::inject))
             .wrap(
                 Cors::default()
                     .allow_any_origin()
-                    .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
+                    .allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
                     .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
                     .allowed_header(header::CONTENT_TYPE)
+                    .allowed_headers(vec![
+                        mock::DELAY_HEADER,
+                        mock::ERROR_HEADER,
+                        mock::TIMEOUT_HEADER,
+                    ])
+                    .expose_headers(vec![
+                        header::LINK,
+                        header::HeaderName::from_static("x-total-count"),
+                    ])
                     .supports_credentials()
                     .max_age(3600),
             )
             .wrap(middleware::Logger::default())
             .app_data(web::JsonConfig::default().limit(4096))
-            .service(get_user)
-            .service(get_users)
-            .service(create_user)
-            .service(get_like)
-            .service(handle_like)
+            .app_data(user_store.clone())
+            .app_data(like_store.clone())
+            .app_data(fake.clone())
+            .app_data(mock_config.clone())
+            .configure(users::config)
+            .configure(likes::config)
             .service(upload)
             .service(create_chat)
     })
@@ -0,0 +1,640 @@
+use crate::error::ApiError;
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header::HeaderMap, Method, StatusCode},
+    rt::time,
+    web, Error, HttpResponse, ResponseError,
+};
+use actix_web_lab::middleware::Next;
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// 请求头里可以覆盖配置: 延迟多少毫秒(`1500` 或者 `200-1500`)
+pub const DELAY_HEADER: &str = "x-mock-delay";
+/// 直接返回这个状态码
+pub const ERROR_HEADER: &str = "x-mock-error";
+/// 等这么多毫秒之后返回 504
+pub const TIMEOUT_HEADER: &str = "x-mock-timeout";
+
+/// 响应前等多久
+///
+/// 配置里写成 `{"fixed": 1000}` 或者 `{"uniform": {"min": 200, "max": 1500}}`, 单位是毫秒.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Delay {
+    Fixed(u64),
+    /// 在 `min` 到 `max` 之间均匀分布
+    Uniform {
+        min: u64,
+        max: u64,
+    },
+}
+
+/// 延迟和故障的设置, 没写的字段用上一级的
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Behavior {
+    pub delay: Option<Delay>,
+    /// 0 到 1, 有多少请求直接返回 `error_statuses` 里的一个状态码
+    pub error_rate: Option<f64>,
+    pub error_statuses: Option<Vec<u16>>,
+    /// 0 到 1, 有多少请求等 `timeout_ms` 之后返回 504
+    pub timeout_rate: Option<f64>,
+    pub timeout_ms: Option<u64>,
+}
+
+/// 一条路由的设置
+///
+/// `path` 里的 `{name}` 匹配一段路径, 结尾的 `*` 匹配剩下的所有部分,
+/// 比如 `/users/{user_id}` 和 `/static/*`. 不写 `method` 时匹配所有方法.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Route {
+    pub method: Option<String>,
+    pub path: String,
+    #[serde(flatten)]
+    pub behavior: Behavior,
+    // flatten 和 deny_unknown_fields 不能一起用, 没用上的键都收集到这里, 加载时报错
+    #[serde(flatten)]
+    unknown: BTreeMap<String, Value>,
+}
+
+/// mock 的延迟和故障注入配置, 从 `MOCK_CONFIG` 指定的 JSON 文件读, 格式见 `mock.example.json`
+///
+/// 按顺序找第一条匹配的路由, 它没写的字段用顶层的. 请求头又可以覆盖配置,
+/// 前端可以直接测某个请求的 loading 和出错的状态.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MockConfig {
+    #[serde(flatten)]
+    pub default: Behavior,
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, Value>,
+}
+
+impl Default for MockConfig {
+    /// 和以前一样每个请求等 1 秒, 上传和聊天不等
+    fn default() -> MockConfig {
+        let no_delay = |path: &str| Route {
+            method: None,
+            path: path.to_string(),
+            behavior: Behavior {
+                delay: Some(Delay::Fixed(0)),
+                ..Default::default()
+            },
+            unknown: BTreeMap::new(),
+        };
+
+        MockConfig {
+            default: Behavior {
+                delay: Some(Delay::Fixed(1000)),
+                ..Default::default()
+            },
+            routes: vec![no_delay("/upload"), no_delay("/create_chat")],
+            unknown: BTreeMap::new(),
+        }
+    }
+}
+
+// 一个请求最后要怎么处理
+#[derive(Debug)]
+struct Plan {
+    delay: Duration,
+    fault: Option<Fault>,
+}
+
+#[derive(Debug)]
+enum Fault {
+    Status(StatusCode),
+    Timeout(Duration),
+}
+
+impl Delay {
+    fn validate(&self) -> Result<(), String> {
+        match *self {
+            Delay::Uniform { min, max } if min > max => Err(format!(
+                "uniform delay min {} is larger than max {}",
+                min, max
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> Duration {
+        let ms = match *self {
+            Delay::Fixed(ms) => ms,
+            Delay::Uniform { min, max } => rng.gen_range(min..=max),
+        };
+        Duration::from_millis(ms)
+    }
+}
+
+impl Behavior {
+    // self 没写的字段用 fallback 的
+    fn or(&self, fallback: &Behavior) -> Behavior {
+        Behavior {
+            delay: self.delay.or(fallback.delay),
+            error_rate: self.error_rate.or(fallback.error_rate),
+            error_statuses: self
+                .error_statuses
+                .clone()
+                .or_else(|| fallback.error_statuses.clone()),
+            timeout_rate: self.timeout_rate.or(fallback.timeout_rate),
+            timeout_ms: self.timeout_ms.or(fallback.timeout_ms),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(delay) = &self.delay {
+            delay.validate()?;
+        }
+        for (name, rate) in [
+            ("error_rate", self.error_rate),
+            ("timeout_rate", self.timeout_rate),
+        ] {
+            if rate.is_some_and(|rate| !(0.0..=1.0).contains(&rate)) {
+                return Err(format!("{} must be between 0 and 1", name));
+            }
+        }
+        for &status in self.error_statuses.iter().flatten() {
+            error_status(status)?;
+        }
+        if self.error_statuses.as_ref().is_some_and(Vec::is_empty) {
+            return Err("error_statuses must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    fn plan<R: Rng>(&self, rng: &mut R) -> Plan {
+        let delay = self.delay.map_or(Duration::ZERO, |delay| delay.sample(rng));
+
+        let fault = if rng.gen_bool(self.timeout_rate.unwrap_or(0.0)) {
+            let ms = self.timeout_ms.unwrap_or(30_000);
+            Some(Fault::Timeout(Duration::from_millis(ms)))
+        } else if rng.gen_bool(self.error_rate.unwrap_or(0.0)) {
+            let statuses = self.error_statuses.as_deref().unwrap_or(&[500]);
+            let status = statuses[rng.gen_range(0..statuses.len())];
+            // 加载配置时已经检查过了
+            Some(Fault::Status(StatusCode::from_u16(status).unwrap()))
+        } else {
+            None
+        };
+
+        Plan { delay, fault }
+    }
+}
+
+impl Route {
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        if let Some(expected) = &self.method {
+            if !expected.eq_ignore_ascii_case(method.as_str()) {
+                return false;
+            }
+        }
+
+        let mut segments = path.trim_start_matches('/').split('/');
+        for pattern in self.path.trim_start_matches('/').split('/') {
+            if pattern == "*" {
+                return true;
+            }
+            match segments.next() {
+                Some(segment) if pattern.starts_with('{') && pattern.ends_with('}') => {
+                    if segment.is_empty() {
+                        return false;
+                    }
+                }
+                Some(segment) if segment == pattern => {}
+                _ => return false,
+            }
+        }
+        segments.next().is_none()
+    }
+}
+
+impl MockConfig {
+    /// 设置了 `MOCK_CONFIG` 时读这个文件, 否则用默认的配置
+    pub fn from_env() -> io::Result<MockConfig> {
+        match env::var("MOCK_CONFIG") {
+            Ok(path) => MockConfig::load(path),
+            Err(_) => Ok(MockConfig::default()),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<MockConfig> {
+        let path = path.as_ref();
+        let invalid = |message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), message),
+            )
+        };
+
+        let bytes = fs::read(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        let config: MockConfig =
+            serde_json::from_slice(&bytes).map_err(|e| invalid(e.to_string()))?;
+
+        // 拼错的键(比如 error_rat)不能悄悄忽略
+        if let Some(key) = config.unknown.keys().next() {
+            return Err(invalid(format!("unknown field `{}`", key)));
+        }
+        config.default.validate().map_err(&invalid)?;
+        for route in &config.routes {
+            if let Some(key) = route.unknown.keys().next() {
+                return Err(invalid(format!(
+                    "route `{}`: unknown field `{}`",
+                    route.path, key
+                )));
+            }
+            if !route.path.starts_with('/') {
+                return Err(invalid(format!("route `{}` must start with /", route.path)));
+            }
+            route
+                .behavior
+                .validate()
+                .map_err(|e| invalid(format!("route `{}`: {}", route.path, e)))?;
+        }
+
+        Ok(config)
+    }
+
+    fn behavior(&self, method: &Method, path: &str) -> Behavior {
+        match self.routes.iter().find(|route| route.matches(method, path)) {
+            Some(route) => route.behavior.or(&self.default),
+            None => self.default.clone(),
+        }
+    }
+}
+
+fn error_status(status: u16) -> Result<StatusCode, String> {
+    match StatusCode::from_u16(status) {
+        Ok(status) if status.is_client_error() || status.is_server_error() => Ok(status),
+        _ => Err(format!("{} is not an error status code", status)),
+    }
+}
+
+// 请求头里的设置覆盖配置里的, 同时带了错误和超时时超时优先
+fn overrides(headers: &HeaderMap) -> Result<Behavior, String> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .map(|value| {
+                value
+                    .to_str()
+                    .map(str::trim)
+                    .map_err(|_| format!("{} is not valid text", name))
+            })
+            .transpose()
+    };
+    let millis = |name: &str, value: &str| {
+        value
+            .parse::<u64>()
+            .map_err(|_| format!("{} must be milliseconds, got `{}`", name, value))
+    };
+
+    let mut behavior = Behavior::default();
+
+    if let Some(value) = header(DELAY_HEADER)? {
+        let delay = match value.split_once('-') {
+            Some((min, max)) => Delay::Uniform {
+                min: millis(DELAY_HEADER, min.trim())?,
+                max: millis(DELAY_HEADER, max.trim())?,
+            },
+            None => Delay::Fixed(millis(DELAY_HEADER, value)?),
+        };
+        delay.validate()?;
+        behavior.delay = Some(delay);
+    }
+
+    if let Some(value) = header(ERROR_HEADER)? {
+        let status = value
+            .parse()
+            .map_err(|_| format!("{} must be a status code, got `{}`", ERROR_HEADER, value))?;
+        error_status(status)?;
+        behavior.error_rate = Some(1.0);
+        behavior.error_statuses = Some(vec![status]);
+    }
+
+    if let Some(value) = header(TIMEOUT_HEADER)? {
+        behavior.timeout_rate = Some(1.0);
+        behavior.timeout_ms = Some(millis(TIMEOUT_HEADER, value)?);
+    }
+
+    Ok(behavior)
+}
+
+fn fault_response(status: StatusCode, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(json!({ "error": message }))
+}
+
+/// 按配置和请求头延迟响应, 或者注入错误和超时
+///
+/// 用 `actix_web_lab::middleware::from_fn(mock::inject)` 注册, 配置从 app data 里的
+/// `web::Data<MockConfig>` 读. 等待都是异步的, 不会占住 worker 线程.
+pub async fn inject(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let config = match req.app_data::<web::Data<MockConfig>>() {
+        Some(config) => config.clone(),
+        None => return next.call(req).await.map(|res| res.map_into_boxed_body()),
+    };
+
+    let behavior = match overrides(req.headers()) {
+        Ok(behavior) => behavior.or(&config.behavior(req.method(), req.path())),
+        Err(message) => {
+            let response = ApiError::BadRequest(message).error_response();
+            return Ok(req.into_response(response));
+        }
+    };
+    // ThreadRng 不能跨 await, 先把随机的部分都算好
+    let plan = behavior.plan(&mut rand::thread_rng());
+
+    time::sleep(plan.delay).await;
+
+    match plan.fault {
+        Some(Fault::Status(status)) => {
+            let message = format!("injected {} fault", status.as_u16());
+            Ok(req.into_response(fault_response(status, &message)))
+        }
+        Some(Fault::Timeout(timeout)) => {
+            time::sleep(timeout).await;
+            let response = fault_response(StatusCode::GATEWAY_TIMEOUT, "injected timeout");
+            Ok(req.into_response(response))
+        }
+        None => next.call(req).await.map(|res| res.map_into_boxed_body()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::path::PathBuf;
+
+    fn route(method: Option<&str>, path: &str) -> Route {
+        Route {
+            method: method.map(str::to_string),
+            path: path.to_string(),
+            behavior: Behavior::default(),
+            unknown: BTreeMap::new(),
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in pairs {
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        headers
+    }
+
+    // 每个测试用自己的文件, 并行跑的时候互不影响
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("mock_{}_{}.json", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn load_error(name: &str, contents: &str) -> String {
+        let path = config_file(name, contents);
+        let error = MockConfig::load(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        error.to_string()
+    }
+
+    #[test]
+    fn matches_params_and_trailing_wildcards() {
+        let user = route(None, "/users/{user_id}");
+        assert!(user.matches(&Method::GET, "/users/42"));
+        assert!(!user.matches(&Method::GET, "/users/"));
+        assert!(!user.matches(&Method::GET, "/users"));
+        assert!(!user.matches(&Method::GET, "/users/42/posts"));
+
+        let assets = route(None, "/static/*");
+        assert!(assets.matches(&Method::GET, "/static"));
+        assert!(assets.matches(&Method::GET, "/static/css/site.css"));
+        assert!(!assets.matches(&Method::GET, "/staticfoo"));
+    }
+
+    #[test]
+    fn matches_methods_ignoring_case() {
+        let like = route(Some("post"), "/like");
+
+        assert!(like.matches(&Method::POST, "/like"));
+        assert!(!like.matches(&Method::GET, "/like"));
+        assert!(route(None, "/like").matches(&Method::DELETE, "/like"));
+    }
+
+    #[test]
+    fn reads_fixed_and_ranged_delays_from_headers() {
+        let fixed = overrides(&headers(&[(DELAY_HEADER, "1500")])).unwrap();
+        assert_eq!(Some(Delay::Fixed(1500)), fixed.delay);
+
+        let range = overrides(&headers(&[(DELAY_HEADER, "200-1500")])).unwrap();
+        assert_eq!(
+            Some(Delay::Uniform {
+                min: 200,
+                max: 1500
+            }),
+            range.delay
+        );
+
+        let spaced = overrides(&headers(&[(DELAY_HEADER, " 200 - 1500 ")])).unwrap();
+        assert_eq!(range, spaced);
+
+        for value in ["1500-200", "fast", "-1", "200-"] {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                HeaderName::from_static(DELAY_HEADER),
+                HeaderValue::from_str(value).unwrap(),
+            );
+            assert!(overrides(&headers).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn reads_errors_and_timeouts_from_headers() {
+        let behavior =
+            overrides(&headers(&[(ERROR_HEADER, "404"), (TIMEOUT_HEADER, "50")])).unwrap();
+
+        assert_eq!(
+            Behavior {
+                delay: None,
+                error_rate: Some(1.0),
+                error_statuses: Some(vec![404]),
+                timeout_rate: Some(1.0),
+                timeout_ms: Some(50),
+            },
+            behavior
+        );
+        assert!(overrides(&headers(&[(ERROR_HEADER, "200")])).is_err());
+        assert!(overrides(&headers(&[(ERROR_HEADER, "oops")])).is_err());
+        assert_eq!(Behavior::default(), overrides(&HeaderMap::new()).unwrap());
+    }
+
+    #[test]
+    fn falls_back_field_by_field() {
+        let route = Behavior {
+            error_rate: Some(0.5),
+            ..Default::default()
+        };
+        let fallback = Behavior {
+            delay: Some(Delay::Fixed(1000)),
+            error_rate: Some(0.1),
+            error_statuses: Some(vec![503]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Behavior {
+                delay: Some(Delay::Fixed(1000)),
+                error_rate: Some(0.5),
+                error_statuses: Some(vec![503]),
+                timeout_rate: None,
+                timeout_ms: None,
+            },
+            route.or(&fallback)
+        );
+    }
+
+    #[test]
+    fn validates_rates_statuses_and_delays() {
+        let invalid = [
+            Behavior {
+                error_rate: Some(1.5),
+                ..Default::default()
+            },
+            Behavior {
+                timeout_rate: Some(-0.1),
+                ..Default::default()
+            },
+            Behavior {
+                error_statuses: Some(Vec::new()),
+                ..Default::default()
+            },
+            Behavior {
+                error_statuses: Some(vec![500, 302]),
+                ..Default::default()
+            },
+            Behavior {
+                delay: Some(Delay::Uniform { min: 10, max: 5 }),
+                ..Default::default()
+            },
+        ];
+
+        for behavior in invalid {
+            assert!(behavior.validate().is_err(), "{:?}", behavior);
+        }
+        assert!(Behavior::default().validate().is_ok());
+    }
+
+    #[test]
+    fn plans_timeouts_before_errors() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let behavior = Behavior {
+            delay: Some(Delay::Uniform {
+                min: 200,
+                max: 1500,
+            }),
+            error_rate: Some(1.0),
+            error_statuses: Some(vec![503]),
+            ..Default::default()
+        };
+
+        let plan = behavior.plan(&mut rng);
+        assert!((200..=1500).contains(&(plan.delay.as_millis() as u64)));
+        assert!(matches!(
+            plan.fault,
+            Some(Fault::Status(StatusCode::SERVICE_UNAVAILABLE))
+        ));
+
+        let timeout = Behavior {
+            timeout_rate: Some(1.0),
+            ..behavior
+        };
+        assert!(matches!(
+            timeout.plan(&mut rng).fault,
+            Some(Fault::Timeout(timeout)) if timeout == Duration::from_secs(30)
+        ));
+    }
+
+    #[test]
+    fn loads_the_example_config() {
+        let config = MockConfig::load("mock.example.json").unwrap();
+
+        assert_eq!(Some(Delay::Fixed(1000)), config.default.delay);
+        let users = config.behavior(&Method::GET, "/users");
+        assert_eq!(Some(vec![500, 503]), users.error_statuses);
+        assert_eq!(
+            Some(Delay::Fixed(1000)),
+            config.behavior(&Method::GET, "/users/42").delay
+        );
+        assert_eq!(
+            Some(Delay::Fixed(0)),
+            config.behavior(&Method::POST, "/upload").delay
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let top = load_error("unknown_top", r#"{ "error_rat": 0.1 }"#);
+        assert!(top.ends_with("unknown field `error_rat`"), "{}", top);
+
+        let route = load_error(
+            "unknown_route",
+            r#"{ "routes": [{ "path": "/users", "timeout": 5 }] }"#,
+        );
+        assert!(
+            route.ends_with("route `/users`: unknown field `timeout`"),
+            "{}",
+            route
+        );
+
+        let delay = load_error(
+            "unknown_delay",
+            r#"{ "delay": { "uniform": { "min": 1, "max": 2, "avg": 1 } } }"#,
+        );
+        assert!(delay.contains("unknown field `avg`"), "{}", delay);
+    }
+
+    #[test]
+    fn rejects_invalid_values_with_the_file_name() {
+        let error = load_error("invalid_rate", r#"{ "error_rate": 2 }"#);
+        assert!(error.contains("mock_"), "{}", error);
+        assert!(
+            error.ends_with("error_rate must be between 0 and 1"),
+            "{}",
+            error
+        );
+
+        let error = load_error("relative_path", r#"{ "routes": [{ "path": "users" }] }"#);
+        assert!(
+            error.ends_with("route `users` must start with /"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn reports_missing_files_with_their_path() {
+        let error = MockConfig::load("missing-mock.json").unwrap_err();
+
+        assert_eq!(io::ErrorKind::NotFound, error.kind());
+        assert!(error.to_string().starts_with("missing-mock.json: "));
+    }
+}
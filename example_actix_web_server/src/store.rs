@@ -0,0 +1,186 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// 存在一个 JSON 文件里的一组记录, 按 id 排序
+///
+/// 数据量很小(mock 用的), 所以每次修改后把整个文件重写一遍.
+/// 先写到临时文件再 rename, 写到一半进程挂了也不会留下半个文件.
+pub struct JsonStore<T> {
+    path: PathBuf,
+    records: RwLock<BTreeMap<String, T>>,
+}
+
+impl<T: Serialize + DeserializeOwned> JsonStore<T> {
+    /// 文件不存在时是一个空的 store, 第一次修改时才创建文件
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<JsonStore<T>> {
+        let path = path.into();
+
+        let records = match fs::read(&path) {
+            Ok(bytes) if bytes.is_empty() => BTreeMap::new(),
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(JsonStore {
+            path,
+            records: RwLock::new(records),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.read().unwrap().is_empty()
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&BTreeMap<String, T>) -> R) -> R {
+        f(&self.records.read().unwrap())
+    }
+
+    /// 修改记录, `f` 返回 `Ok` 并且写文件成功后才生效
+    ///
+    /// `f` 改的是一份拷贝, 它返回 `Err` 或者写文件失败(磁盘满了, 没有权限)时内存里的记录不变,
+    /// 不会出现内存里改了, 文件里没改, 重启之后数据对不上的情况.
+    /// 写文件是阻塞的, 在 handler 里要放到 `web::block` 里调用.
+    pub fn write<R, E: From<io::Error>>(
+        &self,
+        f: impl FnOnce(&mut BTreeMap<String, T>) -> Result<R, E>,
+    ) -> Result<R, E>
+    where
+        T: Clone,
+    {
+        let mut records = self.records.write().unwrap();
+        let mut updated = records.clone();
+        let result = f(&mut updated)?;
+        self.save(&updated)?;
+        *records = updated;
+        Ok(result)
+    }
+
+    fn save(&self, records: &BTreeMap<String, T>) -> io::Result<()> {
+        let bytes = serde_json::to_vec_pretty(records)?;
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&bytes)?;
+        // 先落盘再 rename, 否则断电之后可能换上一个还没写进去的空文件
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // 每个测试用自己的文件, 并行跑的时候互不影响
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("json_store_{}_{}.json", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn insert(store: &JsonStore<String>, id: &str, value: &str) -> io::Result<()> {
+        store.write(|records| {
+            records.insert(id.to_string(), value.to_string());
+            Ok::<_, io::Error>(())
+        })
+    }
+
+    #[test]
+    fn saves_records_and_reads_them_back() {
+        let path = temp_path("round_trip");
+        let store = JsonStore::<String>::open(&path).unwrap();
+        assert!(store.is_empty());
+        assert!(!path.exists());
+
+        insert(&store, "b", "second").unwrap();
+        insert(&store, "a", "first").unwrap();
+
+        let reopened = JsonStore::<String>::open(&path).unwrap();
+        let records = reopened.read(|records| records.clone());
+        assert_eq!(
+            vec![("a", "first"), ("b", "second")],
+            records
+                .iter()
+                .map(|(id, value)| (id.as_str(), value.as_str()))
+                .collect::<Vec<_>>()
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replaces_the_file_through_a_temporary_one() {
+        let path = temp_path("rename");
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        // 上次写到一半留下的临时文件不影响读, 下次写的时候被覆盖掉
+        fs::write(&tmp, "{\"half\": ").unwrap();
+
+        let store = JsonStore::<String>::open(&path).unwrap();
+        assert!(store.is_empty());
+        insert(&store, "a", "first").unwrap();
+
+        assert!(!Path::new(&tmp).exists());
+        let saved: BTreeMap<String, String> =
+            serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(Some("first"), saved.get("a").map(String::as_str));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn leaves_the_file_unchanged_when_f_returns_err() {
+        let path = temp_path("err");
+        let store = JsonStore::<String>::open(&path).unwrap();
+        insert(&store, "a", "first").unwrap();
+        let before = fs::read(&path).unwrap();
+
+        let result = store.write(|records| {
+            if records.contains_key("a") {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "a exists"));
+            }
+            records.insert("a".to_string(), "second".to_string());
+            Ok(())
+        });
+
+        assert_eq!(io::ErrorKind::AlreadyExists, result.unwrap_err().kind());
+        assert_eq!(before, fs::read(&path).unwrap());
+        assert_eq!(
+            Some("first".to_string()),
+            store.read(|records| records.get("a").cloned())
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keeps_memory_unchanged_when_saving_fails() {
+        // 目录不存在, 创建临时文件会失败
+        let path = temp_path("missing_dir").join("records.json");
+        let store = JsonStore::<String>::open(&path).unwrap();
+
+        assert!(insert(&store, "a", "first").is_err());
+
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_json() {
+        let path = temp_path("invalid");
+        fs::write(&path, "not json").unwrap();
+
+        let error = JsonStore::<String>::open(&path).err().unwrap();
+
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,908 @@
+use crate::error::{ApiError, FieldError};
+use crate::fake_data::{FakeData, SeedQuery};
+use crate::likes::{self, LikeStore};
+use crate::store::JsonStore;
+use actix_web::{delete, get, http::header, patch, post, put, web, HttpRequest, HttpResponse};
+use fake::{
+    faker::internet::en::{SafeEmail, Username},
+    faker::lorem::en::Paragraph,
+    faker::name::en::Name,
+    uuid::UUIDv4,
+    Fake,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+pub type UserStore = JsonStore<User>;
+
+const DEFAULT_PER_PAGE: usize = 20;
+const MAX_PER_PAGE: usize = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub user_id: String,
+    pub username: String,
+    pub full_name: String,
+    pub biography: String,
+    pub email: String,
+    pub profile_pic_url: String,
+    pub follower_count: i32,
+    pub follow_count: i32,
+}
+
+/// `POST /user` 和 `PUT /users/{user_id}` 的请求体
+///
+/// `user_id` 由 server 生成, PUT 时可以带上, 但必须和路径里的一样.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserInput {
+    user_id: Option<String>,
+    username: String,
+    full_name: String,
+    #[serde(default)]
+    biography: String,
+    email: String,
+    #[serde(default)]
+    profile_pic_url: String,
+    #[serde(default)]
+    follower_count: i32,
+    #[serde(default)]
+    follow_count: i32,
+}
+
+/// `PATCH /users/{user_id}` 的请求体, 只改带了的字段
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserPatch {
+    username: Option<String>,
+    full_name: Option<String>,
+    biography: Option<String>,
+    email: Option<String>,
+    profile_pic_url: Option<String>,
+    follower_count: Option<i32>,
+    follow_count: Option<i32>,
+}
+
+/// `GET /users` 的查询参数
+///
+/// `username` 和 `email` 按子串过滤(不区分大小写), `sort` 是 `follower_count` 或
+/// `follow_count`, 前面加 `-` 表示从大到小. 不排序时按 `user_id` 的顺序, 翻页时结果是稳定的.
+#[derive(Debug, Deserialize)]
+struct UsersQuery {
+    seed: Option<u64>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+    username: Option<String>,
+    email: Option<String>,
+    sort: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SortKey {
+    FollowerCount,
+    FollowCount,
+}
+
+// 校验过的查询参数
+struct Listing {
+    page: usize,
+    per_page: usize,
+    username: Option<String>,
+    email: Option<String>,
+    sort: Option<String>,
+}
+
+impl UsersQuery {
+    /// 带了 seed 时返回它, 生成的用户不能再分页, 过滤和排序
+    fn seed(&self) -> Result<Option<u64>, ApiError> {
+        let listing = self.page.is_some()
+            || self.per_page.is_some()
+            || self.username.is_some()
+            || self.email.is_some()
+            || self.sort.is_some();
+        if self.seed.is_some() && listing {
+            return Err(ApiError::BadRequest(
+                "seed cannot be combined with page, per_page, username, email or sort".to_string(),
+            ));
+        }
+        Ok(self.seed)
+    }
+
+    fn listing(&self) -> Result<Listing, ApiError> {
+        let mut fields = Vec::new();
+
+        let page = self.page.unwrap_or(1);
+        if page == 0 {
+            fields.push(FieldError {
+                field: "page",
+                message: "must be at least 1".to_string(),
+            });
+        }
+        let per_page = self.per_page.unwrap_or(DEFAULT_PER_PAGE);
+        if !(1..=MAX_PER_PAGE).contains(&per_page) {
+            fields.push(FieldError {
+                field: "per_page",
+                message: format!("must be between 1 and {}", MAX_PER_PAGE),
+            });
+        }
+        if let Some(sort) = &self.sort {
+            if sort_key(sort).is_none() {
+                fields.push(FieldError {
+                    field: "sort",
+                    message: "must be follower_count or follow_count, optionally prefixed with -"
+                        .to_string(),
+                });
+            }
+        }
+
+        if !fields.is_empty() {
+            return Err(ApiError::Invalid(fields));
+        }
+
+        // 空的过滤条件等于没有
+        let filter = |value: &Option<String>| {
+            value
+                .as_ref()
+                .filter(|value| !value.is_empty())
+                .map(|value| value.to_lowercase())
+        };
+
+        Ok(Listing {
+            page,
+            per_page,
+            username: filter(&self.username),
+            email: filter(&self.email),
+            sort: self.sort.clone(),
+        })
+    }
+}
+
+// "-follower_count" 表示按 follower_count 从大到小
+fn sort_key(sort: &str) -> Option<(SortKey, bool)> {
+    let (name, descending) = match sort.strip_prefix('-') {
+        Some(name) => (name, true),
+        None => (sort, false),
+    };
+
+    match name {
+        "follower_count" => Some((SortKey::FollowerCount, descending)),
+        "follow_count" => Some((SortKey::FollowCount, descending)),
+        _ => None,
+    }
+}
+
+impl Listing {
+    fn matches(&self, user: &User) -> bool {
+        let contains = |value: &str, filter: &Option<String>| {
+            filter
+                .as_ref()
+                .is_none_or(|filter| value.to_lowercase().contains(filter))
+        };
+
+        contains(&user.username, &self.username) && contains(&user.email, &self.email)
+    }
+
+    /// 过滤, 排序之后的一页, 以及过滤后的总数
+    fn page_of(&self, users: &BTreeMap<String, User>) -> (Vec<User>, usize) {
+        let mut matched: Vec<&User> = users.values().filter(|user| self.matches(user)).collect();
+
+        // sort_by_key 是稳定的, 值相同的还是按 user_id 的顺序
+        if let Some((key, descending)) = self.sort.as_deref().and_then(sort_key) {
+            matched.sort_by_key(|user| {
+                let value = match key {
+                    SortKey::FollowerCount => user.follower_count,
+                    SortKey::FollowCount => user.follow_count,
+                };
+                match descending {
+                    true => -(value as i64),
+                    false => value as i64,
+                }
+            });
+        }
+
+        let total = matched.len();
+        let page = matched
+            .into_iter()
+            .skip((self.page - 1).saturating_mul(self.per_page))
+            .take(self.per_page)
+            .cloned()
+            .collect();
+
+        (page, total)
+    }
+
+    // 除了页码之外保留原来的查询参数
+    fn url(&self, path: &str, page: usize) -> String {
+        let mut url = format!("{}?page={}&per_page={}", path, page, self.per_page);
+        for (name, value) in [
+            ("username", &self.username),
+            ("email", &self.email),
+            ("sort", &self.sort),
+        ] {
+            if let Some(value) = value {
+                url.push_str(&format!("&{}={}", name, percent_encode(value)));
+            }
+        }
+        url
+    }
+
+    /// RFC 8288 的 `Link` 头, 有上一页和下一页时才带 prev 和 next
+    fn link_header(&self, path: &str, total: usize) -> String {
+        let last = total.div_ceil(self.per_page).max(1);
+
+        let mut links = vec![(1, "first")];
+        if self.page > 1 {
+            links.push(((self.page - 1).min(last), "prev"));
+        }
+        if self.page < last {
+            links.push((self.page + 1, "next"));
+        }
+        links.push((last, "last"));
+
+        links
+            .into_iter()
+            .map(|(page, rel)| format!("<{}>; rel=\"{}\"", self.url(path, page), rel))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+// 查询参数里除了字母, 数字和 -._~ 之外的字节都要转义
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+impl UserInput {
+    fn into_user(self, user_id: String) -> User {
+        User {
+            user_id,
+            username: self.username,
+            full_name: self.full_name,
+            biography: self.biography,
+            email: self.email,
+            profile_pic_url: self.profile_pic_url,
+            follower_count: self.follower_count,
+            follow_count: self.follow_count,
+        }
+    }
+}
+
+impl UserPatch {
+    fn apply(self, user: &mut User) {
+        let UserPatch {
+            username,
+            full_name,
+            biography,
+            email,
+            profile_pic_url,
+            follower_count,
+            follow_count,
+        } = self;
+
+        if let Some(username) = username {
+            user.username = username;
+        }
+        if let Some(full_name) = full_name {
+            user.full_name = full_name;
+        }
+        if let Some(biography) = biography {
+            user.biography = biography;
+        }
+        if let Some(email) = email {
+            user.email = email;
+        }
+        if let Some(profile_pic_url) = profile_pic_url {
+            user.profile_pic_url = profile_pic_url;
+        }
+        if let Some(follower_count) = follower_count {
+            user.follower_count = follower_count;
+        }
+        if let Some(follow_count) = follow_count {
+            user.follow_count = follow_count;
+        }
+    }
+}
+
+impl User {
+    /// 检查所有字段, 把发现的问题都列出来
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut fields = Vec::new();
+        let mut check = |ok: bool, field: &'static str, message: &str| {
+            if !ok {
+                fields.push(FieldError {
+                    field,
+                    message: message.to_string(),
+                });
+            }
+        };
+
+        let username_length = self.username.chars().count();
+        check(
+            (3..=32).contains(&username_length),
+            "username",
+            "must be 3 to 32 characters long",
+        );
+        check(
+            self.username
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.'),
+            "username",
+            "may only contain letters, digits, `_` and `.`",
+        );
+        check(
+            !self.full_name.trim().is_empty() && self.full_name.chars().count() <= 100,
+            "full_name",
+            "must be 1 to 100 characters long",
+        );
+        check(
+            self.biography.chars().count() <= 2000,
+            "biography",
+            "must be at most 2000 characters long",
+        );
+        // 只做最基本的检查: 本地部分@域名, 域名里有点
+        let valid_email = self.email.split_once('@').is_some_and(|(local, domain)| {
+            !local.is_empty()
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && domain.contains('.')
+        });
+        check(valid_email, "email", "must be a valid email address");
+        check(
+            self.profile_pic_url.is_empty()
+                || self.profile_pic_url.starts_with("http://")
+                || self.profile_pic_url.starts_with("https://"),
+            "profile_pic_url",
+            "must be an http or https URL",
+        );
+        check(
+            self.follower_count >= 0,
+            "follower_count",
+            "must not be negative",
+        );
+        check(
+            self.follow_count >= 0,
+            "follow_count",
+            "must not be negative",
+        );
+
+        match fields.is_empty() {
+            true => Ok(()),
+            false => Err(ApiError::Invalid(fields)),
+        }
+    }
+}
+
+// 用户名不区分大小写地唯一
+fn check_unique(users: &BTreeMap<String, User>, user: &User) -> Result<(), ApiError> {
+    let taken = users.values().any(|other| {
+        other.user_id != user.user_id && other.username.eq_ignore_ascii_case(&user.username)
+    });
+
+    match taken {
+        true => Err(ApiError::Conflict(format!(
+            "username `{}` is already taken",
+            user.username
+        ))),
+        false => Ok(()),
+    }
+}
+
+pub fn not_found(user_id: &str) -> ApiError {
+    ApiError::NotFound(format!("user `{}` does not exist", user_id))
+}
+
+pub fn parse_json<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T, ApiError> {
+    serde_json::from_slice(body).map_err(|e| ApiError::BadRequest(e.to_string()))
+}
+
+// 所有随机的部分都来自 rng, 同一个 seed 生成的用户完全一样
+fn generate_user<R: Rng>(rng: &mut R) -> User {
+    User {
+        user_id: UUIDv4.fake_with_rng(rng),
+        username: Username().fake_with_rng(rng),
+        full_name: Name().fake_with_rng(rng),
+        biography: Paragraph(3..5).fake_with_rng(rng),
+        email: SafeEmail().fake_with_rng(rng),
+        profile_pic_url: format!(
+            "https://dummyimage.com/{}x{}.png",
+            rng.gen_range(300..600),
+            rng.gen_range(300..600),
+        ),
+        follower_count: rng.gen_range(100..6000),
+        follow_count: rng.gen_range(100..6000),
+    }
+}
+
+/// store 是空的时候生成 `count` 个假用户, 之后每次启动都用同一批
+///
+/// 设置了 `MOCK_SEED` 时, 新建的 store 里的用户每次都一样.
+pub fn seed(store: &UserStore, fake: &FakeData, count: usize) -> Result<(), ApiError> {
+    if !store.is_empty() {
+        return Ok(());
+    }
+
+    store.write(|users| {
+        while users.len() < count {
+            match generate_valid_user(users, fake) {
+                Some(user) => users.insert(user.user_id.clone(), user),
+                None => break,
+            };
+        }
+        Ok(())
+    })
+}
+
+// 生成一个通过校验, 用户名也没被占用的假用户
+// fake 生成的用户名可能重复, 也可能带着 `-` 之类的字符, 这时换一个再试.
+// 最多试 10 次, 不会因为生成的数据总是不合法而死循环
+fn generate_valid_user(users: &BTreeMap<String, User>, fake: &FakeData) -> Option<User> {
+    (0..10)
+        .map(|_| fake.with_rng(None, generate_user))
+        .find(|user| user.validate().is_ok() && check_unique(users, user).is_ok())
+}
+
+// 固定返回第一个用户, 前端每次拿到的都一样
+// 带了 ?seed= 时返回按这个 seed 生成的用户, 不保存
+#[get("/user")]
+async fn get_user(
+    store: web::Data<UserStore>,
+    fake: web::Data<FakeData>,
+    query: web::Query<SeedQuery>,
+) -> Result<HttpResponse, ApiError> {
+    if let Some(seed) = query.seed {
+        let user = fake.with_rng(Some(seed), generate_user);
+        return Ok(HttpResponse::Ok().json(user));
+    }
+
+    store
+        .read(|users| users.values().next().cloned())
+        .map(|user| HttpResponse::Ok().json(user))
+        .ok_or_else(|| ApiError::NotFound("there are no users yet".to_string()))
+}
+
+// 分页返回 store 里的用户, 总数在 X-Total-Count 里, 其他页的地址在 Link 里
+// 带了 ?seed= 时和以前一样返回 2 到 9 个生成的用户, 但同一个 seed 每次都一样, 不能再带分页和过滤的参数
+#[get("/users")]
+async fn get_users(
+    req: HttpRequest,
+    store: web::Data<UserStore>,
+    fake: web::Data<FakeData>,
+    query: web::Query<UsersQuery>,
+) -> Result<HttpResponse, ApiError> {
+    if let Some(seed) = query.seed()? {
+        let users: Vec<User> = fake.with_rng(Some(seed), |rng| {
+            let count = rng.gen_range(2..10);
+            (0..count).map(|_| generate_user(rng)).collect()
+        });
+        return Ok(HttpResponse::Ok().json(users));
+    }
+
+    let listing = query.listing()?;
+    let (users, total) = store.read(|users| listing.page_of(users));
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("X-Total-Count", total.to_string()))
+        .insert_header((header::LINK, listing.link_header(req.path(), total)))
+        .json(users))
+}
+
+// 不带请求体时和以前一样生成一个假用户, 但会保存下来
+#[post("/user")]
+async fn create_user(
+    store: web::Data<UserStore>,
+    fake: web::Data<FakeData>,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let input = match body.is_empty() {
+        true => None,
+        false => {
+            let input: UserInput = parse_json(&body)?;
+            if input.user_id.is_some() {
+                return Err(ApiError::BadRequest(
+                    "user_id is generated by the server".to_string(),
+                ));
+            }
+            let user = input.into_user(Uuid::new_v4().to_string());
+            user.validate()?;
+            Some(user)
+        }
+    };
+
+    let user = web::block(move || {
+        store.write(|users| {
+            let user = match input {
+                Some(user) => {
+                    check_unique(users, &user)?;
+                    user
+                }
+                // 生成的用户名不是客户端选的, 不合法或者重复时不能回 422/409, 换一个就是了
+                None => generate_valid_user(users, &fake).ok_or_else(|| {
+                    ApiError::Internal("failed to generate a unique user".to_string())
+                })?,
+            };
+            users.insert(user.user_id.clone(), user.clone());
+            Ok::<_, ApiError>(user)
+        })
+    })
+    .await??;
+
+    Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION, format!("/users/{}", user.user_id)))
+        .json(user))
+}
+
+#[get("/users/{user_id}")]
+async fn get_user_by_id(
+    store: web::Data<UserStore>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = path.into_inner();
+    store
+        .read(|users| users.get(&user_id).cloned())
+        .map(|user| HttpResponse::Ok().json(user))
+        .ok_or_else(|| not_found(&user_id))
+}
+
+// 整个替换, 没带的可选字段恢复成默认值
+#[put("/users/{user_id}")]
+async fn replace_user(
+    store: web::Data<UserStore>,
+    path: web::Path<String>,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = path.into_inner();
+    let input: UserInput = parse_json(&body)?;
+    if input.user_id.as_ref().is_some_and(|id| *id != user_id) {
+        return Err(ApiError::BadRequest(
+            "user_id in the body does not match the path".to_string(),
+        ));
+    }
+    let user = input.into_user(user_id);
+    user.validate()?;
+
+    let user = web::block(move || {
+        store.write(|users| {
+            if !users.contains_key(&user.user_id) {
+                return Err(not_found(&user.user_id));
+            }
+            check_unique(users, &user)?;
+            users.insert(user.user_id.clone(), user.clone());
+            Ok(user)
+        })
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+#[patch("/users/{user_id}")]
+async fn update_user(
+    store: web::Data<UserStore>,
+    path: web::Path<String>,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = path.into_inner();
+    let patch: UserPatch = parse_json(&body)?;
+
+    let user = web::block(move || {
+        store.write(|users| {
+            let mut user = users
+                .get(&user_id)
+                .cloned()
+                .ok_or_else(|| not_found(&user_id))?;
+            patch.apply(&mut user);
+            user.validate()?;
+            check_unique(users, &user)?;
+            users.insert(user_id, user.clone());
+            Ok::<_, ApiError>(user)
+        })
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+// 这个用户点过的赞也一起删掉, 否则点赞数里会一直算着不存在的用户
+#[delete("/users/{user_id}")]
+async fn delete_user(
+    store: web::Data<UserStore>,
+    like_store: web::Data<LikeStore>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = path.into_inner();
+    web::block(move || {
+        store.write(|users| {
+            if !users.contains_key(&user_id) {
+                return Err(not_found(&user_id));
+            }
+            // 拿着写锁, 点赞的请求要等用户删完才能检查用户是否存在
+            likes::forget_user(&like_store, &user_id)?;
+            users.remove(&user_id);
+            Ok(())
+        })
+    })
+    .await??;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_user)
+        .service(get_users)
+        .service(create_user)
+        .service(get_user_by_id)
+        .service(replace_user)
+        .service(update_user)
+        .service(delete_user);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(user_id: &str, username: &str) -> User {
+        User {
+            user_id: user_id.to_string(),
+            username: username.to_string(),
+            full_name: "Ada Lovelace".to_string(),
+            biography: String::new(),
+            email: "ada@example.com".to_string(),
+            profile_pic_url: String::new(),
+            follower_count: 0,
+            follow_count: 0,
+        }
+    }
+
+    // 没通过校验的字段, 按报告的顺序
+    fn invalid_fields(user: &User) -> Vec<&'static str> {
+        match user.validate() {
+            Ok(()) => Vec::new(),
+            Err(ApiError::Invalid(fields)) => fields.iter().map(|field| field.field).collect(),
+            Err(e) => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn accepts_a_valid_user() {
+        let user = User {
+            biography: "x".repeat(2000),
+            profile_pic_url: "https://dummyimage.com/300x300.png".to_string(),
+            ..user("1", "ada.l_2")
+        };
+
+        assert!(invalid_fields(&user).is_empty());
+    }
+
+    #[test]
+    fn reports_every_invalid_field() {
+        let user = User {
+            full_name: "  ".to_string(),
+            biography: "x".repeat(2001),
+            email: "ada".to_string(),
+            profile_pic_url: "ftp://example.com/ada.png".to_string(),
+            follower_count: -1,
+            follow_count: -1,
+            ..user("1", "a-")
+        };
+
+        assert_eq!(
+            vec![
+                "username",
+                "username",
+                "full_name",
+                "biography",
+                "email",
+                "profile_pic_url",
+                "follower_count",
+                "follow_count",
+            ],
+            invalid_fields(&user)
+        );
+    }
+
+    #[test]
+    fn checks_username_length_in_characters() {
+        for (username, valid) in [
+            ("abc", true),
+            (&*"a".repeat(32), true),
+            ("ab", false),
+            (&*"a".repeat(33), false),
+            ("ada lovelace", false),
+        ] {
+            assert_eq!(
+                valid,
+                invalid_fields(&user("1", username)).is_empty(),
+                "{}",
+                username
+            );
+        }
+    }
+
+    #[test]
+    fn checks_emails_loosely() {
+        for (email, valid) in [
+            ("ada@example.com", true),
+            ("a@b.co", true),
+            ("@example.com", false),
+            ("ada@example", false),
+            ("ada@.example.com", false),
+            ("ada@example.", false),
+            ("ada.example.com", false),
+        ] {
+            let user = User {
+                email: email.to_string(),
+                ..user("1", "ada")
+            };
+
+            assert_eq!(valid, invalid_fields(&user).is_empty(), "{}", email);
+        }
+    }
+
+    #[test]
+    fn generates_another_user_when_the_username_is_taken() {
+        let first = generate_valid_user(&BTreeMap::new(), &FakeData::new(Some(7))).unwrap();
+        let taken = user("taken", &first.username);
+        let users = BTreeMap::from([(taken.user_id.clone(), taken)]);
+
+        // 同一个 seed 第一个生成的还是它, 用户名重复了就换下一个
+        let user = generate_valid_user(&users, &FakeData::new(Some(7))).unwrap();
+
+        assert!(user.validate().is_ok());
+        assert_ne!(first.username, user.username);
+    }
+
+    fn query(query: &str) -> UsersQuery {
+        web::Query::<UsersQuery>::from_query(query)
+            .unwrap()
+            .into_inner()
+    }
+
+    fn listing(query_string: &str) -> Listing {
+        query(query_string).listing().unwrap()
+    }
+
+    // user_id 按字母顺序, 值是 follower_count
+    fn users(follower_counts: &[(&str, i32)]) -> BTreeMap<String, User> {
+        follower_counts
+            .iter()
+            .map(|&(id, follower_count)| {
+                let user = User {
+                    follower_count,
+                    ..user(id, &format!("user_{}", id))
+                };
+                (id.to_string(), user)
+            })
+            .collect()
+    }
+
+    fn ids(users: &[User]) -> Vec<&str> {
+        users.iter().map(|user| user.user_id.as_str()).collect()
+    }
+
+    #[test]
+    fn rejects_seed_combined_with_listing_params() {
+        assert_eq!(Some(7), query("seed=7").seed().unwrap());
+        assert_eq!(None, query("page=2").seed().unwrap());
+
+        for params in [
+            "page=1",
+            "per_page=5",
+            "username=ada",
+            "email=x",
+            "sort=follow_count",
+        ] {
+            let result = query(&format!("seed=7&{}", params)).seed();
+            assert!(matches!(result, Err(ApiError::BadRequest(_))), "{}", params);
+        }
+    }
+
+    #[test]
+    fn reports_every_invalid_listing_param() {
+        let fields = match query("page=0&per_page=101&sort=name").listing() {
+            Err(ApiError::Invalid(fields)) => fields,
+            _ => panic!("expected a validation error"),
+        };
+
+        assert_eq!(
+            vec!["page", "per_page", "sort"],
+            fields.iter().map(|field| field.field).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn sorts_ties_in_user_id_order() {
+        let users = users(&[("a", 5), ("b", 7), ("c", 5), ("d", 1)]);
+
+        let (page, total) = listing("sort=follower_count").page_of(&users);
+        assert_eq!(vec!["d", "a", "c", "b"], ids(&page));
+        assert_eq!(4, total);
+
+        let (page, _) = listing("sort=-follower_count").page_of(&users);
+        assert_eq!(vec!["b", "a", "c", "d"], ids(&page));
+    }
+
+    #[test]
+    fn filters_before_paging() {
+        let mut users = users(&[("a", 0), ("b", 0), ("c", 0)]);
+        users.get_mut("b").unwrap().email = "grace@Example.org".to_string();
+
+        let (page, total) = listing("email=EXAMPLE.COM&per_page=1&page=2").page_of(&users);
+
+        assert_eq!(vec!["c"], ids(&page));
+        assert_eq!(2, total);
+    }
+
+    #[test]
+    fn returns_an_empty_page_past_the_last_one() {
+        let users = users(&[("a", 0), ("b", 0), ("c", 0)]);
+        let listing = listing("page=5&per_page=2");
+
+        let (page, total) = listing.page_of(&users);
+
+        assert!(page.is_empty());
+        assert_eq!(3, total);
+        // prev 指向最后一页, 不是不存在的第 4 页, 也没有 next
+        assert_eq!(
+            "</users?page=1&per_page=2>; rel=\"first\", \
+             </users?page=2&per_page=2>; rel=\"prev\", \
+             </users?page=2&per_page=2>; rel=\"last\"",
+            listing.link_header("/users", total)
+        );
+    }
+
+    #[test]
+    fn links_to_the_neighbouring_pages() {
+        assert_eq!(
+            "</users?page=1&per_page=10>; rel=\"first\", \
+             </users?page=1&per_page=10>; rel=\"prev\", \
+             </users?page=3&per_page=10>; rel=\"next\", \
+             </users?page=3&per_page=10>; rel=\"last\"",
+            listing("page=2&per_page=10").link_header("/users", 21)
+        );
+    }
+
+    #[test]
+    fn has_one_page_when_there_are_no_users() {
+        assert_eq!(
+            "</users?page=1&per_page=20>; rel=\"first\", \
+             </users?page=1&per_page=20>; rel=\"last\"",
+            listing("").link_header("/users", 0)
+        );
+    }
+
+    #[test]
+    fn keeps_encoded_filters_in_links() {
+        let listing = listing("username=A%20b%26c&email=%C3%A9&sort=-follow_count");
+
+        assert_eq!(
+            "/users?page=3&per_page=20&username=a%20b%26c&email=%C3%A9&sort=-follow_count",
+            listing.url("/users", 3)
+        );
+        assert_eq!("a-z_0.9~", percent_encode("a-z_0.9~"));
+        assert_eq!("%2B%3D%3F%2F", percent_encode("+=?/"));
+    }
+
+    #[test]
+    fn usernames_are_unique_ignoring_case() {
+        let mut users = BTreeMap::new();
+        users.insert("1".to_string(), user("1", "Ada"));
+
+        // 改自己的用户名不算冲突
+        assert!(check_unique(&users, &user("1", "ada")).is_ok());
+        assert!(matches!(
+            check_unique(&users, &user("2", "ADA")),
+            Err(ApiError::Conflict(_))
+        ));
+        assert!(check_unique(&users, &user("2", "grace")).is_ok());
+    }
+}
@@ -0,0 +1,169 @@
+//! 对比四种 server 模式的压测
+//!
+//! cargo bench --bench load
+//!
+//! burst: 一批客户端并发地发短请求, 看吞吐和延迟
+//! idle: 先挂上一堆不发请求的空闲连接, 再看正常请求还能不能得到响应
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use web_server::shutdown::ShutdownSignal;
+use web_server::{event_loop, server, ThreadPool};
+
+const CLIENTS: usize = 64;
+const REQUESTS_PER_CLIENT: usize = 20;
+const IDLE_CONNECTIONS: usize = 500;
+const IDLE_CLIENTS: usize = 8;
+const IDLE_REQUESTS_PER_CLIENT: usize = 5;
+// 连接和读取的超时, server 没在 accept 时 backlog 满了之后连 connect 都会卡住
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Single,
+    Threads,
+    Pool,
+    Epoll,
+}
+
+impl Mode {
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Single => "single",
+            Mode::Threads => "threads",
+            Mode::Pool => "pool",
+            Mode::Epoll => "epoll",
+        }
+    }
+
+    // single 和 threads 模式没有关闭的办法, 压测结束后随进程一起退出
+    fn start(self, shutdown: &ShutdownSignal) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = shutdown.clone();
+
+        thread::spawn(move || match self {
+            Mode::Single => server::single_thread_mode(listener),
+            Mode::Threads => server::finite_number_of_multi_threads(listener),
+            Mode::Pool => {
+                let pool = ThreadPool::builder()
+                    .min_size(4)
+                    .max_size(16)
+                    .queue_capacity(64)
+                    .build()
+                    .unwrap();
+                server::thread_poll(listener, pool, &shutdown, Duration::from_secs(1));
+            }
+            Mode::Epoll => {
+                let pool = ThreadPool::new(4).unwrap();
+                event_loop::run(listener, pool, &shutdown, Duration::from_secs(1)).unwrap();
+            }
+        });
+
+        addr
+    }
+}
+
+fn get(addr: SocketAddr) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    // 读到 EOF 为止, 所以不能让 server 保持连接
+    stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+
+    if response.starts_with(b"HTTP/1.1 200 OK") {
+        Ok(())
+    } else {
+        Err(std::io::Error::other("unexpected response"))
+    }
+}
+
+struct Outcome {
+    latencies: Vec<Duration>,
+    failed: usize,
+    elapsed: Duration,
+}
+
+impl Outcome {
+    fn report(&mut self, mode: Mode, scenario: &str) {
+        self.latencies.sort();
+        let percentile = |p: f64| {
+            self.latencies
+                .get(
+                    ((self.latencies.len() as f64 * p) as usize)
+                        .min(self.latencies.len().max(1) - 1),
+                )
+                .copied()
+                .unwrap_or_default()
+        };
+
+        println!(
+            "{:<8} {:<6} {:>6} ok {:>4} failed {:>9.0} req/s  p50 {:>9.2?}  p99 {:>9.2?}",
+            mode.name(),
+            scenario,
+            self.latencies.len(),
+            self.failed,
+            self.latencies.len() as f64 / self.elapsed.as_secs_f64(),
+            percentile(0.5),
+            percentile(0.99),
+        );
+    }
+}
+
+// 每个客户端依次发 requests 个请求, 遇到失败就不再继续, 剩下的都算失败
+fn load(addr: SocketAddr, clients: usize, requests: usize) -> Outcome {
+    let start = Instant::now();
+
+    let handles: Vec<_> = (0..clients)
+        .map(|_| {
+            thread::spawn(move || {
+                let mut latencies = Vec::with_capacity(requests);
+                for done in 0..requests {
+                    let sent = Instant::now();
+                    if get(addr).is_err() {
+                        return (latencies, requests - done);
+                    }
+                    latencies.push(sent.elapsed());
+                }
+                (latencies, 0)
+            })
+        })
+        .collect();
+
+    let mut outcome = Outcome {
+        latencies: Vec::new(),
+        failed: 0,
+        elapsed: Duration::ZERO,
+    };
+    for handle in handles {
+        let (latencies, failed) = handle.join().unwrap();
+        outcome.latencies.extend(latencies);
+        outcome.failed += failed;
+    }
+    outcome.elapsed = start.elapsed();
+
+    outcome
+}
+
+fn main() {
+    // cargo bench 会把 --bench 之类的参数传进来, 这里用不到
+    for mode in [Mode::Single, Mode::Threads, Mode::Pool, Mode::Epoll] {
+        let shutdown = ShutdownSignal::new();
+        let addr = mode.start(&shutdown);
+
+        load(addr, CLIENTS, REQUESTS_PER_CLIENT).report(mode, "burst");
+
+        let idle: Vec<_> = (0..IDLE_CONNECTIONS)
+            .map_while(|_| TcpStream::connect_timeout(&addr, TIMEOUT).ok())
+            .collect();
+        // 等 server 把这些连接都 accept 进去
+        thread::sleep(Duration::from_millis(200));
+        load(addr, IDLE_CLIENTS, IDLE_REQUESTS_PER_CLIENT).report(mode, "idle");
+
+        shutdown.trigger();
+        drop(idle);
+    }
+}
@@ -0,0 +1,154 @@
+//! 对比工作窃取的 ThreadPool 和原来所有 worker 共用一个 Arc<Mutex<Receiver>> 的实现
+//!
+//! cargo bench --bench pool
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use web_server::ThreadPool;
+
+// 原来的设计: 所有 worker 抢同一把锁从通道里取任务
+struct MutexChannelPool {
+    workers: Vec<thread::JoinHandle<()>>,
+    sender: Option<mpsc::Sender<Box<dyn FnOnce() + Send>>>,
+}
+
+impl MutexChannelPool {
+    fn new(size: usize) -> MutexChannelPool {
+        let (sender, receiver) = mpsc::channel::<Box<dyn FnOnce() + Send>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+
+        MutexChannelPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    fn execute<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.sender.as_ref().unwrap().send(Box::new(f)).unwrap();
+    }
+}
+
+impl Drop for MutexChannelPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+const JOBS: usize = 10_000;
+
+// 每个任务只做一点点事情, 耗时主要花在分发上
+fn tiny_job(counter: &AtomicUsize) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+fn wait_for(counter: &AtomicUsize, expected: usize) {
+    while counter.load(Ordering::Relaxed) < expected {
+        thread::yield_now();
+    }
+}
+
+fn many_small_jobs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("many_small_jobs");
+    group.sample_size(20);
+
+    let threads = thread::available_parallelism().map_or(4, |n| n.get());
+    let mut sizes = vec![1, threads];
+    sizes.dedup();
+
+    for size in sizes {
+        let pool = ThreadPool::builder().size(size).build().unwrap();
+        pool.on_panic(|_, _| {});
+        group.bench_with_input(BenchmarkId::new("work_stealing", size), &size, |b, _| {
+            b.iter(|| {
+                let counter = Arc::new(AtomicUsize::new(0));
+                for _ in 0..JOBS {
+                    let counter = Arc::clone(&counter);
+                    pool.execute(move || tiny_job(&counter));
+                }
+                wait_for(&counter, JOBS);
+            })
+        });
+
+        let pool = MutexChannelPool::new(size);
+        group.bench_with_input(BenchmarkId::new("mutex_channel", size), &size, |b, _| {
+            b.iter(|| {
+                let counter = Arc::new(AtomicUsize::new(0));
+                for _ in 0..JOBS {
+                    let counter = Arc::clone(&counter);
+                    pool.execute(move || tiny_job(&counter));
+                }
+                wait_for(&counter, JOBS);
+            })
+        });
+    }
+
+    group.finish();
+}
+
+// 任务里再提交任务, 工作窃取的实现会放进本地队列, 不需要经过全局队列
+fn nested_jobs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("nested_jobs");
+    group.sample_size(20);
+
+    let threads = thread::available_parallelism().map_or(4, |n| n.get());
+    const PARENTS: usize = 100;
+    const CHILDREN: usize = 100;
+
+    let pool = Arc::new(ThreadPool::builder().size(threads).build().unwrap());
+    group.bench_function(BenchmarkId::new("work_stealing", threads), |b| {
+        b.iter(|| {
+            let counter = Arc::new(AtomicUsize::new(0));
+            for _ in 0..PARENTS {
+                let inner = Arc::clone(&pool);
+                let counter = Arc::clone(&counter);
+                pool.execute(move || {
+                    for _ in 0..CHILDREN {
+                        let counter = Arc::clone(&counter);
+                        inner.execute(move || tiny_job(&counter));
+                    }
+                });
+            }
+            wait_for(&counter, PARENTS * CHILDREN);
+        })
+    });
+
+    let pool = Arc::new(MutexChannelPool::new(threads));
+    group.bench_function(BenchmarkId::new("mutex_channel", threads), |b| {
+        b.iter(|| {
+            let counter = Arc::new(AtomicUsize::new(0));
+            for _ in 0..PARENTS {
+                let inner = Arc::clone(&pool);
+                let counter = Arc::clone(&counter);
+                pool.execute(move || {
+                    for _ in 0..CHILDREN {
+                        let counter = Arc::clone(&counter);
+                        inner.execute(move || tiny_job(&counter));
+                    }
+                });
+            }
+            wait_for(&counter, PARENTS * CHILDREN);
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, many_small_jobs, nested_jobs);
+criterion_main!(benches);
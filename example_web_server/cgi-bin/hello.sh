@@ -0,0 +1,14 @@
+#!/bin/sh
+# 最简单的 CGI 脚本: 先输出响应头和空行, 再输出 body
+echo "Content-Type: text/plain"
+echo
+
+echo "Hello from $SCRIPT_NAME"
+echo "method: $REQUEST_METHOD"
+echo "query: $QUERY_STRING"
+echo "client: $REMOTE_ADDR"
+
+# POST 的 body 从 stdin 读
+if [ "$CONTENT_LENGTH" -gt 0 ]; then
+    echo "body: $(head -c "$CONTENT_LENGTH")"
+fi
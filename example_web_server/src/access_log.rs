@@ -0,0 +1,538 @@
+use crate::http;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// 写日志的线程空闲这么久就把缓冲刷到文件里
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// 写日志的线程跟不上时最多攒这么多条, 再多就丢掉, 不让请求等日志
+const CHANNEL_CAPACITY: usize = 4096;
+
+static ACCESS_LOG: OnceLock<AccessLog> = OnceLock::new();
+
+/// 访问日志的格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Common Log Format, 没有 referer 和 user agent
+    Common,
+    /// Combined Log Format, Apache 和 nginx 默认的格式, 末尾追加了处理耗时(微秒)
+    #[default]
+    Combined,
+    /// 每行一个 JSON 对象
+    Json,
+}
+
+impl LogFormat {
+    /// 解析 `common`, `combined` 或 `json`
+    pub fn parse(name: &str) -> Option<LogFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "common" => Some(LogFormat::Common),
+            "combined" => Some(LogFormat::Combined),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// 一条访问记录
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub client: Option<IpAddr>,
+    pub time: SystemTime,
+    pub request_line: String,
+    pub status: u16,
+    pub bytes: u64,
+    pub duration: Duration,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl Entry {
+    /// 从原始请求里取出请求行, Referer 和 User-Agent
+    ///
+    /// `started` 是开始处理请求的时间, `bytes` 是响应 body 的字节数.
+    pub fn new(
+        client: Option<SocketAddr>,
+        request: &[u8],
+        status: u16,
+        bytes: u64,
+        started: Instant,
+    ) -> Entry {
+        // 只看请求头, body 可能有 1MB, 也不能把 body 里的内容当成请求头
+        let head = request
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map_or(request, |end| &request[..end + 4]);
+        let request = String::from_utf8_lossy(head);
+
+        Entry {
+            client: client.map(|addr| addr.ip()),
+            time: SystemTime::now(),
+            // 没读完请求就被拒绝的记成 -
+            request_line: match request.lines().next() {
+                Some(line) if !line.is_empty() => line.to_string(),
+                _ => "-".to_string(),
+            },
+            status,
+            bytes,
+            duration: started.elapsed(),
+            referer: http::header_value(&request, "Referer").map(str::to_string),
+            user_agent: http::header_value(&request, "User-Agent").map(str::to_string),
+        }
+    }
+
+    /// 按 `format` 格式化成一行, 不带换行符
+    pub fn format(&self, format: LogFormat) -> String {
+        let client = self
+            .client
+            .map_or_else(|| "-".to_string(), |ip| ip.to_string());
+
+        match format {
+            LogFormat::Common => format!(
+                "{} - - [{}] \"{}\" {} {}",
+                client,
+                clf_time(self.time),
+                escape_quoted(&self.request_line),
+                self.status,
+                self.clf_bytes(),
+            ),
+            LogFormat::Combined => format!(
+                "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\" {}",
+                client,
+                clf_time(self.time),
+                escape_quoted(&self.request_line),
+                self.status,
+                self.clf_bytes(),
+                escape_quoted(self.referer.as_deref().unwrap_or("-")),
+                escape_quoted(self.user_agent.as_deref().unwrap_or("-")),
+                self.duration.as_micros(),
+            ),
+            LogFormat::Json => format!(
+                "{{\"client\":{},\"time\":\"{}\",\"request\":\"{}\",\"status\":{},\"bytes\":{},\"duration_us\":{},\"referer\":{},\"user_agent\":{}}}",
+                self.client
+                    .map_or_else(|| "null".to_string(), |ip| format!("\"{}\"", ip)),
+                rfc3339_time(self.time),
+                escape_json(&self.request_line),
+                self.status,
+                self.bytes,
+                self.duration.as_micros(),
+                json_string(self.referer.as_deref()),
+                json_string(self.user_agent.as_deref()),
+            ),
+        }
+    }
+
+    // CLF 里没有 body 的响应记成 -
+    fn clf_bytes(&self) -> String {
+        match self.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        }
+    }
+}
+
+/// 写到文件的访问日志, 格式化和写文件都在单独的线程上, 不占用处理请求的时间
+///
+/// 文件超过 `max_size` 或者打开超过 `rotate_every` 后会轮转:
+/// `access.log` 改名成 `access.log.1`, 原来的 `.1` 变成 `.2`, 以此类推, 最多保留 `keep` 个旧文件.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use web_server::access_log::{self, AccessLog, LogFormat};
+///
+/// let log = AccessLog::builder("access.log")
+///     .format(LogFormat::Json)
+///     .max_size(10 * 1024 * 1024)
+///     .rotate_every(Duration::from_secs(24 * 60 * 60))
+///     .build()
+///     .unwrap();
+/// access_log::install(log);
+/// ```
+pub struct AccessLog {
+    sender: Mutex<Option<SyncSender<Entry>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    dropped: AtomicU64,
+}
+
+impl AccessLog {
+    pub fn builder(path: impl Into<PathBuf>) -> AccessLogBuilder {
+        AccessLogBuilder {
+            path: path.into(),
+            format: LogFormat::default(),
+            max_size: None,
+            rotate_every: None,
+            keep: 5,
+        }
+    }
+
+    /// 把记录交给写日志的线程, 不会阻塞
+    ///
+    /// 写日志的线程跟不上时这条记录会被丢掉, 计入 `dropped`.
+    pub fn log(&self, entry: Entry) {
+        let sender = self.sender.lock().unwrap();
+        let Some(sender) = sender.as_ref() else {
+            return;
+        };
+
+        match sender.try_send(entry) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// 因为写日志的线程跟不上而丢掉的记录数
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// 等已经提交的记录都写进文件后返回, 之后的记录都会被忽略
+    pub fn close(&self) {
+        // 先丢掉 sender, 写日志的线程收完剩下的记录就会退出
+        self.sender.lock().unwrap().take();
+
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+pub struct AccessLogBuilder {
+    path: PathBuf,
+    format: LogFormat,
+    max_size: Option<u64>,
+    rotate_every: Option<Duration>,
+    keep: usize,
+}
+
+impl AccessLogBuilder {
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// 文件超过 `bytes` 字节后轮转
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// 文件打开超过 `interval` 后轮转
+    pub fn rotate_every(mut self, interval: Duration) -> Self {
+        self.rotate_every = Some(interval);
+        self
+    }
+
+    /// 最多保留几个轮转出来的旧文件, 默认 5 个, 0 表示轮转时直接丢掉旧内容
+    pub fn keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// 打开(或者追加到)日志文件, 启动写日志的线程
+    pub fn build(self) -> io::Result<AccessLog> {
+        let format = self.format;
+        let mut file = RotatingFile::open(self)?;
+        let (sender, receiver) = mpsc::sync_channel::<Entry>(CHANNEL_CAPACITY);
+
+        let writer = thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || loop {
+                match receiver.recv_timeout(FLUSH_INTERVAL) {
+                    Ok(entry) => {
+                        if let Err(e) = file.write_line(&entry.format(format)) {
+                            eprintln!("Failed to write access log: {}", e);
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        if let Err(e) = file.flush() {
+                            eprintln!("Failed to flush access log: {}", e);
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        let _ = file.flush();
+                        break;
+                    }
+                }
+            })?;
+
+        Ok(AccessLog {
+            sender: Mutex::new(Some(sender)),
+            writer: Mutex::new(Some(writer)),
+            dropped: AtomicU64::new(0),
+        })
+    }
+}
+
+/// 设置全局的访问日志, 各个 server 模式处理完请求后都会写到这里
+///
+/// 已经设置过时返回 `Err`, 把传进来的日志还回去.
+pub fn install(log: AccessLog) -> Result<(), AccessLog> {
+    ACCESS_LOG.set(log)
+}
+
+/// 记录一个请求, 没有设置访问日志时什么也不做
+pub fn record(
+    client: Option<SocketAddr>,
+    request: &[u8],
+    status: u16,
+    bytes: u64,
+    started: Instant,
+) {
+    if let Some(log) = ACCESS_LOG.get() {
+        log.log(Entry::new(client, request, status, bytes, started));
+    }
+}
+
+/// 把全局访问日志里还没写的记录都写进文件, 退出前调用
+pub fn close() {
+    if let Some(log) = ACCESS_LOG.get() {
+        log.close();
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    opened: Instant,
+    max_size: Option<u64>,
+    rotate_every: Option<Duration>,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(builder: AccessLogBuilder) -> io::Result<RotatingFile> {
+        let file = open_append(&builder.path)?;
+        let size = file.metadata()?.len();
+
+        Ok(RotatingFile {
+            path: builder.path,
+            file: BufWriter::new(file),
+            size,
+            opened: Instant::now(),
+            max_size: builder.max_size,
+            rotate_every: builder.rotate_every,
+            keep: builder.keep,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let too_big = self
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + line.len() as u64 + 1 > max);
+        let too_old = self
+            .rotate_every
+            .is_some_and(|interval| self.opened.elapsed() >= interval);
+        if too_big || too_old {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.keep > 0 {
+            // 从最老的开始往后挪, 超出 keep 的那个会被覆盖掉
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+
+        self.file = BufWriter::new(open_append(&self.path)?);
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+// 10/Oct/2000:13:55:36 +0000, 统一用 UTC
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day, hour, minute, second) = utc(time);
+
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+// 2000-10-10T13:55:36Z
+fn rfc3339_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = utc(time);
+
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hour, minute, second
+    )
+}
+
+// 把时间戳拆成 UTC 的年月日时分秒, 日期的换算来自 Howard Hinnant 的 civil_from_days
+fn utc(time: SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (days, rest) = ((secs / 86400) as i64, secs % 86400);
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day, rest / 3600, rest % 3600 / 60, rest % 60)
+}
+
+// 双引号里的字段, 把引号和反斜杠转义掉, 免得一行日志被拆错
+fn escape_quoted(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn json_string(value: Option<&str>) -> String {
+    value.map_or_else(
+        || "null".to_string(),
+        |value| format!("\"{}\"", escape_json(value)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> Entry {
+        Entry {
+            client: Some("127.0.0.1".parse().unwrap()),
+            // 2000-10-10T13:55:36Z
+            time: UNIX_EPOCH + Duration::from_secs(971186136),
+            request_line: "GET /index.html HTTP/1.1".to_string(),
+            status: 200,
+            bytes: 2326,
+            duration: Duration::from_micros(1500),
+            referer: None,
+            user_agent: Some("curl/8.0 \"test\"".to_string()),
+        }
+    }
+
+    #[test]
+    fn reads_headers_only_from_the_request_head() {
+        let entry = Entry::new(
+            None,
+            b"POST /form HTTP/1.1\r\nReferer: /\r\n\r\nUser-Agent: in the body\r\n",
+            200,
+            0,
+            Instant::now(),
+        );
+
+        assert_eq!("POST /form HTTP/1.1", entry.request_line);
+        assert_eq!(Some("/"), entry.referer.as_deref());
+        assert_eq!(None, entry.user_agent);
+    }
+
+    #[test]
+    fn formats_combined_and_json_lines() {
+        let entry = entry();
+
+        assert_eq!(
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /index.html HTTP/1.1\" 200 2326 \"-\" \"curl/8.0 \\\"test\\\"\" 1500",
+            entry.format(LogFormat::Combined)
+        );
+        assert_eq!(
+            "{\"client\":\"127.0.0.1\",\"time\":\"2000-10-10T13:55:36Z\",\"request\":\"GET /index.html HTTP/1.1\",\"status\":200,\"bytes\":2326,\"duration_us\":1500,\"referer\":null,\"user_agent\":\"curl/8.0 \\\"test\\\"\"}",
+            entry.format(LogFormat::Json)
+        );
+    }
+
+    #[test]
+    fn rotates_when_file_grows_past_max_size() {
+        let dir =
+            std::env::temp_dir().join(format!("web_server_access_log_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        let log = AccessLog::builder(&path)
+            .format(LogFormat::Common)
+            .max_size(100)
+            .keep(2)
+            .build()
+            .unwrap();
+        for _ in 0..5 {
+            log.log(entry());
+        }
+        log.close();
+
+        // 每行 80 多字节, 每写一行就轮转一次, 只留下最后一行和两个旧文件
+        let lines = |path: PathBuf| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(1, lines(path.clone()));
+        assert_eq!(1, lines(dir.join("access.log.1")));
+        assert_eq!(1, lines(dir.join("access.log.2")));
+        assert!(!dir.join("access.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+use crate::dispatch::Dispatcher;
+use crate::observer::{NoopObserver, Observer};
+use crate::queue::{JobQueue, RejectionPolicy};
+use crate::{PoolCreationError, ThreadPool};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Configures and creates a `ThreadPool`.
+///
+/// ```
+/// use std::time::Duration;
+/// use web_server::{RejectionPolicy, ThreadPool};
+///
+/// let pool = ThreadPool::builder()
+///     .min_size(2)
+///     .max_size(8)
+///     .keep_alive(Duration::from_secs(30))
+///     .queue_capacity(100)
+///     .rejection_policy(RejectionPolicy::Reject)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct ThreadPoolBuilder {
+    min_size: usize,
+    max_size: Option<usize>,
+    keep_alive: Duration,
+    queue_capacity: Option<usize>,
+    rejection_policy: RejectionPolicy,
+    task_queues: Vec<(String, u32)>,
+    observer: Arc<dyn Observer>,
+}
+
+impl ThreadPoolBuilder {
+    pub fn new() -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            min_size: 4,
+            max_size: None,
+            keep_alive: Duration::from_secs(60),
+            queue_capacity: None,
+            rejection_policy: RejectionPolicy::default(),
+            task_queues: Vec::new(),
+            observer: Arc::new(NoopObserver),
+        }
+    }
+
+    /// 固定大小的线程池, 相当于把 `min_size` 和 `max_size` 设成同一个值, 默认是 4
+    pub fn size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.min_size = size;
+        self.max_size = Some(size);
+        self
+    }
+
+    /// 常驻的 worker 数, 空闲时也不会退出
+    pub fn min_size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.min_size = size;
+        self
+    }
+
+    /// 队列积压时最多扩容到的 worker 数, 不设置则等于 `min_size`
+    pub fn max_size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.max_size = Some(size);
+        self
+    }
+
+    /// 超出 `min_size` 的 worker 空闲多久后退出, 默认 60 秒
+    pub fn keep_alive(mut self, keep_alive: Duration) -> ThreadPoolBuilder {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// 队列中最多能排队的任务数, 不设置则不限制
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// 队列满了之后的处理策略, 默认是阻塞提交方
+    pub fn rejection_policy(mut self, policy: RejectionPolicy) -> ThreadPoolBuilder {
+        self.rejection_policy = policy;
+        self
+    }
+
+    /// 增加一个具名队列, 用 `ThreadPool::try_execute_in` 往里提交任务
+    ///
+    /// 具名队列和 `Priority::Normal` 的任务(权重为 1)按权重轮流执行, 同名的队列会覆盖之前的权重.
+    pub fn task_queue(mut self, name: impl Into<String>, weight: u32) -> ThreadPoolBuilder {
+        let name = name.into();
+        self.task_queues.retain(|(queue, _)| *queue != name);
+        self.task_queues.push((name, weight));
+        self
+    }
+
+    /// 接收线程池事件的观察者, 默认什么都不做, 调试时可以用 `LogObserver` 打印出来
+    pub fn observer<O>(mut self, observer: O) -> ThreadPoolBuilder
+    where
+        O: Observer + 'static,
+    {
+        self.observer = Arc::new(observer);
+        self
+    }
+
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        let max_size = self.max_size.unwrap_or(self.min_size);
+
+        if max_size == 0 {
+            return Err(PoolCreationError {
+                error_msg: "`size` should more than 0".to_string(),
+                code: -1,
+            });
+        }
+
+        if self.min_size > max_size {
+            return Err(PoolCreationError {
+                error_msg: "`min_size` should not be more than `max_size`".to_string(),
+                code: -3,
+            });
+        }
+
+        if self.queue_capacity == Some(0) {
+            return Err(PoolCreationError {
+                error_msg: "`queue_capacity` should more than 0".to_string(),
+                code: -2,
+            });
+        }
+
+        if let Some((name, _)) = self.task_queues.iter().find(|(_, weight)| *weight == 0) {
+            return Err(PoolCreationError {
+                error_msg: format!("weight of task queue `{}` should more than 0", name),
+                code: -4,
+            });
+        }
+
+        let queue = JobQueue::new(self.queue_capacity, self.rejection_policy);
+
+        Ok(ThreadPool::start(
+            self.min_size,
+            max_size,
+            self.keep_alive,
+            queue,
+            Dispatcher::new(&self.task_queues),
+            self.observer,
+        ))
+    }
+}
+
+// Observer 是 trait 对象, 没法 derive Debug
+impl fmt::Debug for ThreadPoolBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThreadPoolBuilder")
+            .field("min_size", &self.min_size)
+            .field("max_size", &self.max_size)
+            .field("keep_alive", &self.keep_alive)
+            .field("queue_capacity", &self.queue_capacity)
+            .field("rejection_policy", &self.rejection_policy)
+            .field("task_queues", &self.task_queues)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for ThreadPoolBuilder {
+    fn default() -> Self {
+        ThreadPoolBuilder::new()
+    }
+}
@@ -0,0 +1,356 @@
+use crate::http::{Request, Response};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// 把以 `prefix` 开头的请求交给 `dir` 下的 CGI 脚本处理
+///
+/// `/cgi-bin/hello.sh/extra?name=x` 会运行 `dir/hello.sh`, `PATH_INFO` 是 `/extra`,
+/// `QUERY_STRING` 是 `name=x`. 请求头通过 `HTTP_*` 环境变量传进去, body 从 stdin 传进去,
+/// 脚本在 stdout 上输出响应头, 空行, 再输出 body.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use web_server::cgi::Cgi;
+/// use web_server::site::{self, Site};
+///
+/// let cgi = Cgi::new("/cgi-bin", "cgi-bin").timeout(Duration::from_secs(10));
+/// site::install(Site::default().cgi(cgi));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Cgi {
+    prefix: String,
+    dir: PathBuf,
+    timeout: Duration,
+}
+
+impl Cgi {
+    pub fn new(prefix: &str, dir: impl Into<PathBuf>) -> Cgi {
+        Cgi {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            dir: dir.into(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// 脚本最多运行多久, 超时后被杀掉并回 504, 默认 30 秒
+    pub fn timeout(mut self, timeout: Duration) -> Cgi {
+        self.timeout = timeout;
+        self
+    }
+
+    /// 不是 `prefix` 下的路径返回 `None`
+    pub fn handle(&self, request: &Request, client: Option<SocketAddr>) -> Option<Response> {
+        let rest = strip_prefix(request.path(), &self.prefix)?;
+
+        // 路径的第一段是脚本名, 剩下的是 PATH_INFO
+        let rest = rest.trim_start_matches('/');
+        let (name, path_info) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, ""),
+        };
+        if !safe_segments(name) {
+            return Some(status("HTTP/1.1 404 NOT FOUND"));
+        }
+        // 脚本在自己的目录下运行, 相对路径要先转成绝对路径
+        let script = match fs::canonicalize(self.dir.join(name)) {
+            Ok(script) if script.is_file() => script,
+            _ => return Some(status("HTTP/1.1 404 NOT FOUND")),
+        };
+
+        let script_name = format!("{}/{}", self.prefix, name);
+        let env = environment(request, client, &script_name, path_info, &script);
+
+        Some(match self.run(&script, env, &request.body) {
+            Ok(Some(output)) => parse_output(&output).unwrap_or_else(|e| {
+                eprintln!("Invalid output from CGI script {}: {}", script.display(), e);
+                status("HTTP/1.1 502 BAD GATEWAY")
+            }),
+            Ok(None) => {
+                eprintln!("CGI script {} timed out", script.display());
+                status("HTTP/1.1 504 GATEWAY TIMEOUT")
+            }
+            Err(e) => {
+                eprintln!("Failed to run CGI script {}: {}", script.display(), e);
+                status("HTTP/1.1 502 BAD GATEWAY")
+            }
+        })
+    }
+
+    // 超时返回 Ok(None)
+    fn run(
+        &self,
+        script: &Path,
+        env: Vec<(String, String)>,
+        body: &[u8],
+    ) -> io::Result<Option<Vec<u8>>> {
+        let mut child = Command::new(script)
+            .current_dir(script.parent().unwrap_or(&self.dir))
+            // 只传 CGI 规定的变量, 不把 server 自己的环境变量泄露给脚本
+            .env_clear()
+            .envs(env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+
+        // 写 stdin 和读 stdout 要同时进行, 否则 body 和输出都比管道缓冲区大时会互相等死
+        let mut stdin = child.stdin.take().unwrap();
+        let body = body.to_vec();
+        thread::spawn(move || {
+            // 脚本不读 stdin 就退出时会写失败, 不用管
+            let _ = stdin.write_all(&body);
+        });
+
+        let mut stdout = child.stdout.take().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = sender.send(stdout.read_to_end(&mut output).map(|_| output));
+        });
+
+        match receiver.recv_timeout(self.timeout) {
+            Ok(output) => {
+                child.wait()?;
+                output.map(Some)
+            }
+            Err(_) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                Ok(None)
+            }
+        }
+    }
+}
+
+// /cgi-bin 能匹配 /cgi-bin/x 但不能匹配 /cgi-binx
+pub(crate) fn strip_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(prefix)?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+/// 每一段都得是普通的名字, `..`, `.` 和空段都不行, 拼到目录后面时不会跑出这个目录
+pub(crate) fn safe_segments(path: &str) -> bool {
+    path.split('/')
+        .all(|segment| !segment.is_empty() && segment != "." && segment != "..")
+}
+
+/// CGI/1.1 规定的环境变量, FastCGI 的参数也用这一套
+pub(crate) fn environment(
+    request: &Request,
+    client: Option<SocketAddr>,
+    script_name: &str,
+    path_info: &str,
+    script_filename: &Path,
+) -> Vec<(String, String)> {
+    let query = request
+        .target
+        .split_once('?')
+        .map_or("", |(_, query)| query);
+    // Host 可能带着端口
+    let host = request.header("Host").unwrap_or("localhost");
+    let (server_name, server_port) = match host.rsplit_once(':') {
+        Some((name, port)) if !port.ends_with(']') => (name, port),
+        _ => (host, "80"),
+    };
+
+    let mut env = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE", "web_server".to_string()),
+        ("SERVER_PROTOCOL", request.version.clone()),
+        ("SERVER_NAME", server_name.to_string()),
+        ("SERVER_PORT", server_port.to_string()),
+        ("REQUEST_METHOD", request.method.clone()),
+        ("REQUEST_URI", request.target.clone()),
+        ("QUERY_STRING", query.to_string()),
+        ("SCRIPT_NAME", script_name.to_string()),
+        ("SCRIPT_FILENAME", script_filename.display().to_string()),
+        ("PATH_INFO", path_info.to_string()),
+        ("CONTENT_LENGTH", request.body.len().to_string()),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value))
+    .collect::<Vec<_>>();
+
+    if let Some(content_type) = request.header("Content-Type") {
+        env.push(("CONTENT_TYPE".to_string(), content_type.to_string()));
+    }
+    if let Some(client) = client {
+        env.push(("REMOTE_ADDR".to_string(), client.ip().to_string()));
+        env.push(("REMOTE_PORT".to_string(), client.port().to_string()));
+    }
+    // 脚本里调用其他命令时需要 PATH
+    if let Ok(path) = std::env::var("PATH") {
+        env.push(("PATH".to_string(), path));
+    }
+
+    for (name, value) in &request.headers {
+        // 这两个已经单独传了; Proxy 头可能被用来设置 HTTP_PROXY(httpoxy 漏洞)
+        if ["Content-Type", "Content-Length", "Proxy"]
+            .iter()
+            .any(|skipped| skipped.eq_ignore_ascii_case(name))
+        {
+            continue;
+        }
+        let name = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        env.push((name, value.clone()));
+    }
+
+    env
+}
+
+/// 解析 CGI 脚本的输出: 响应头, 空行, body
+///
+/// `Status` 头决定状态码, 没有时有 `Location` 的是 302, 否则是 200.
+pub(crate) fn parse_output(output: &[u8]) -> Result<Response, &'static str> {
+    // 脚本一般只输出 \n, 也兼容 \r\n
+    let (head, body) = [&b"\r\n\r\n"[..], b"\n\n"]
+        .iter()
+        .filter_map(|separator| {
+            output
+                .windows(separator.len())
+                .position(|window| window == *separator)
+                .map(|i| (i, separator.len()))
+        })
+        .min()
+        .map(|(i, len)| (&output[..i], &output[i + len..]))
+        .ok_or("missing blank line after the headers")?;
+    let head = std::str::from_utf8(head).map_err(|_| "headers are not valid UTF-8")?;
+
+    let mut status_line = None;
+    let mut headers = Vec::new();
+    for line in head.lines() {
+        let (name, value) = line.split_once(':').ok_or("header without a colon")?;
+        let value = value.trim();
+
+        if name.eq_ignore_ascii_case("Status") {
+            // Status: 404 Not Found
+            let code = value.split_whitespace().next().unwrap_or_default();
+            if code.len() != 3 || code.parse::<u16>().is_err() {
+                return Err("invalid Status header");
+            }
+            status_line = Some(format!("HTTP/1.1 {}", value.to_ascii_uppercase()));
+        } else if !name.eq_ignore_ascii_case("Content-Length") {
+            // Content-Length 由 Response::write_to 补上
+            headers.push((name.trim().to_string(), value.to_string()));
+        }
+    }
+    if headers.is_empty() && status_line.is_none() {
+        return Err("no headers");
+    }
+
+    let has_location = headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Location"));
+    let status_line = status_line.unwrap_or_else(|| match has_location {
+        true => "HTTP/1.1 302 FOUND".to_string(),
+        false => "HTTP/1.1 200 OK".to_string(),
+    });
+
+    let mut response = Response::new(&status_line, body.to_vec());
+    response.headers = headers;
+    Ok(response)
+}
+
+pub(crate) fn status(status_line: &str) -> Response {
+    let mut response = Response::new(status_line, Vec::new());
+    response.set_header("Content-Type", "text/html");
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{self, Limits};
+    use std::os::unix::fs::PermissionsExt;
+
+    fn request(raw: &[u8]) -> Request {
+        http::parse_request(raw, &Limits::default())
+            .unwrap()
+            .unwrap()
+    }
+
+    fn script(dir: &Path, name: &str, source: &str) {
+        let path = dir.join(name);
+        fs::write(&path, source).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn runs_scripts_with_cgi_environment_and_body() {
+        let dir = std::env::temp_dir().join(format!("web_server_cgi_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        script(
+            &dir,
+            "echo.sh",
+            "#!/bin/sh\n\
+             echo 'Content-Type: text/plain'\n\
+             echo \"X-Method: $REQUEST_METHOD\"\n\
+             echo\n\
+             echo \"$SCRIPT_NAME $PATH_INFO $QUERY_STRING $HTTP_USER_AGENT $REMOTE_ADDR\"\n\
+             cat\n",
+        );
+        script(
+            &dir,
+            "missing.sh",
+            "#!/bin/sh\nprintf 'Status: 404 Not Found\\r\\n\\r\\nnope'\n",
+        );
+        script(&dir, "slow.sh", "#!/bin/sh\nsleep 5\n");
+        let cgi = Cgi::new("/cgi-bin", &dir).timeout(Duration::from_millis(200));
+        let client = Some("127.0.0.1:5000".parse().unwrap());
+
+        let response = cgi
+            .handle(
+                &request(
+                    b"POST /cgi-bin/echo.sh/extra?a=1 HTTP/1.1\r\nUser-Agent: test\r\n\
+                      Content-Length: 5\r\n\r\nhello",
+                ),
+                client,
+            )
+            .unwrap();
+        assert_eq!(200, response.status_code());
+        assert_eq!(Some("text/plain"), response.header("Content-Type"));
+        assert_eq!(Some("POST"), response.header("X-Method"));
+        assert_eq!(
+            "/cgi-bin/echo.sh /extra a=1 test 127.0.0.1\nhello",
+            String::from_utf8_lossy(&response.body)
+        );
+
+        let response = cgi
+            .handle(
+                &request(b"GET /cgi-bin/missing.sh HTTP/1.1\r\n\r\n"),
+                client,
+            )
+            .unwrap();
+        assert_eq!("HTTP/1.1 404 NOT FOUND", response.status_line);
+        assert_eq!(b"nope", &response.body[..]);
+
+        let status = |raw: &[u8]| cgi.handle(&request(raw), client).map(|r| r.status_code());
+        assert_eq!(Some(504), status(b"GET /cgi-bin/slow.sh HTTP/1.1\r\n\r\n"));
+        assert_eq!(
+            Some(404),
+            status(b"GET /cgi-bin/nothing.sh HTTP/1.1\r\n\r\n")
+        );
+        assert_eq!(
+            Some(404),
+            status(b"GET /cgi-bin/../cgi.sh HTTP/1.1\r\n\r\n")
+        );
+        assert_eq!(None, status(b"GET /cgi-binx/echo.sh HTTP/1.1\r\n\r\n"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parses_location_only_output_as_redirect() {
+        let response = parse_output(b"Location: /elsewhere\n\n").unwrap();
+
+        assert_eq!("HTTP/1.1 302 FOUND", response.status_line);
+        assert_eq!(Some("/elsewhere"), response.header("Location"));
+        assert!(parse_output(b"no blank line").is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::http::Response;
+use flate2::write::GzEncoder;
+use std::io::{self, Write};
+
+/// 小于这个大小的 body 压缩后收益不大, 还要多花 CPU, 直接原样返回
+pub const MIN_COMPRESS_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+}
+
+/// 根据 Accept-Encoding 选出一个编码, 形如 `gzip;q=0.8, br`
+///
+/// q 值高的优先, q 值相同时 br 优先于 gzip, q=0 表示客户端明确拒绝.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut gzip_q = None;
+    let mut br_q = None;
+    let mut wildcard_q = None;
+
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        match coding.as_str() {
+            "gzip" | "x-gzip" => gzip_q = Some(q),
+            "br" => br_q = Some(q),
+            "*" => wildcard_q = Some(q),
+            _ => {}
+        }
+    }
+
+    // 没有单独列出来的编码, 按 * 的 q 值算
+    let gzip_q = gzip_q.or(wildcard_q).unwrap_or(0.0);
+    let br_q = br_q.or(wildcard_q).unwrap_or(0.0);
+
+    if br_q > 0.0 && br_q >= gzip_q {
+        Some(Encoding::Brotli)
+    } else if gzip_q > 0.0 {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+/// 文本类的内容才值得压缩, PNG/JPG 这类本身就是压缩过的格式, 再压一遍只会更大
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json" | "application/javascript" | "application/xml" | "image/svg+xml"
+        )
+}
+
+pub fn compress(body: &[u8], encoding: Encoding) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Brotli => {
+            let mut output = Vec::new();
+            {
+                // 参数依次是 buffer 大小, 压缩等级(0-11)和窗口大小
+                // 等级调低一点, 11 级对每个请求来说太慢了
+                let mut encoder = brotli::CompressorWriter::new(&mut output, 4096, 5, 22);
+                encoder.write_all(body)?;
+            } // CompressorWriter 在 drop 时才会把剩余的数据写完
+            Ok(output)
+        }
+    }
+}
+
+/// 按客户端的 Accept-Encoding 对响应做内容协商
+///
+/// 只要内容类型可压缩就会带上 `Vary: Accept-Encoding`, 这样缓存才不会把压缩过的响应发给不支持的客户端.
+pub fn negotiate_response(mut response: Response, accept_encoding: Option<&str>) -> Response {
+    let compressible = response.header("Content-Type").is_some_and(is_compressible);
+
+    if !compressible || response.header("Content-Encoding").is_some() {
+        return response;
+    }
+
+    response.set_header("Vary", "Accept-Encoding");
+
+    if response.body.len() < MIN_COMPRESS_SIZE {
+        return response;
+    }
+
+    if let Some(encoding) = accept_encoding.and_then(negotiate) {
+        // 压缩失败就退回不压缩, 不至于让整个请求失败
+        if let Ok(body) = compress(&response.body, encoding) {
+            response.body = body;
+            response.set_header("Content-Encoding", encoding.as_str());
+        }
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn large_html() -> Response {
+        let mut response =
+            Response::new("HTTP/1.1 200 OK", "<p>hello</p>".repeat(200).into_bytes());
+        response.set_header("Content-Type", "text/html");
+        response
+    }
+
+    #[test]
+    fn negotiates_by_quality() {
+        assert_eq!(Some(Encoding::Brotli), negotiate("gzip, deflate, br"));
+        assert_eq!(Some(Encoding::Gzip), negotiate("gzip;q=1.0, br;q=0.5"));
+        assert_eq!(Some(Encoding::Gzip), negotiate("br;q=0, *"));
+        assert_eq!(None, negotiate("identity"));
+        assert_eq!(None, negotiate("gzip;q=0"));
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let response = negotiate_response(large_html(), Some("gzip"));
+
+        assert_eq!(Some("gzip"), response.header("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.header("Vary"));
+
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&response.body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!("<p>hello</p>".repeat(200), decoded);
+    }
+
+    #[test]
+    fn skips_small_and_binary_bodies() {
+        let mut small = Response::new("HTTP/1.1 200 OK", b"{}".to_vec());
+        small.set_header("Content-Type", "application/json");
+        let small = negotiate_response(small, Some("br"));
+        assert_eq!(None, small.header("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), small.header("Vary"));
+
+        let mut png = Response::new("HTTP/1.1 200 OK", vec![0; 4096]);
+        png.set_header("Content-Type", "image/png");
+        let png = negotiate_response(png, Some("br"));
+        assert_eq!(None, png.header("Content-Encoding"));
+        assert_eq!(None, png.header("Vary"));
+    }
+}
@@ -0,0 +1,830 @@
+use crate::cgi::Cgi;
+use crate::fastcgi::{Address, FastCgi};
+use crate::http::Limits;
+use crate::proxy::Proxy;
+use crate::site::Site;
+use crate::websocket;
+use crate::{PoolCreationError, ThreadPool};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// server 的配置, 从 TOML 文件读取, 没写的项用默认值
+///
+/// 监听地址和路由的默认值和原来写死在 main.rs 里的一样, 线程池不一样:
+/// 原来是固定 4 个线程, 队列不限长度, 现在默认 4 到 16 个线程, 队列最多排 64 个连接,
+/// 见 `Workers`. 文件里的相对路径按配置文件所在的目录解析.
+///
+/// ```toml
+/// [[listener]]
+/// addr = "127.0.0.1:7878"
+/// redirect_to_https = true
+///
+/// [[listener]]
+/// addr = "127.0.0.1:7879"
+/// cert = "cert.pem"
+/// key = "key.pem"
+///
+/// [workers]
+/// min = 4
+/// max = 16
+///
+/// [timeouts]
+/// shutdown = 30
+///
+/// [limits]
+/// max_body_size = 1048576
+/// request_timeout = 30
+///
+/// [[route]]
+/// path = "/"
+/// file = "index.html"
+///
+/// [[static]]
+/// prefix = "/assets"
+/// dir = "public"
+///
+/// [[proxy]]
+/// prefix = "/api"
+/// upstreams = ["127.0.0.1:10086"]
+///
+/// [[cgi]]
+/// prefix = "/cgi-bin"
+/// dir = "cgi-bin"
+///
+/// [[fastcgi]]
+/// prefix = "/php"
+/// socket = "/run/php/php-fpm.sock"
+/// root = "/var/www"
+///
+/// [[websocket]]
+/// path = "/ws"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// 写了 `[[listener]]` 就会替换掉默认的 127.0.0.1:7878
+    pub listener: Vec<Listener>,
+    pub workers: Workers,
+    pub timeouts: Timeouts,
+    pub limits: RequestLimits,
+    /// 404 响应的 body
+    pub not_found: Option<PathBuf>,
+    /// 写了 `[[route]]` 就会替换掉默认的路由
+    pub route: Vec<Route>,
+    #[serde(rename = "static")]
+    pub statics: Vec<StaticDir>,
+    pub proxy: Vec<ProxyRoute>,
+    pub cgi: Vec<CgiDir>,
+    pub fastcgi: Vec<FastCgiApp>,
+    /// 写了 `[[websocket]]` 就会替换掉默认的 `/ws`
+    pub websocket: Vec<WebSocketPath>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Listener {
+    /// IP:端口
+    pub addr: String,
+    /// 同时给出证书和私钥时监听 HTTPS
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// 把请求都重定向到第一个 HTTPS listener
+    #[serde(default)]
+    pub redirect_to_https: bool,
+}
+
+impl Listener {
+    pub fn new(addr: &str) -> Listener {
+        Listener {
+            addr: addr.to_string(),
+            cert: None,
+            key: None,
+            redirect_to_https: false,
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.cert.is_some() && self.key.is_some()
+    }
+
+    /// 校验过的配置里 addr 一定能解析
+    pub fn socket_addr(&self) -> SocketAddr {
+        self.addr.parse().unwrap()
+    }
+}
+
+/// 线程池的大小, 见 `ThreadPoolBuilder`
+///
+/// 默认 4 到 16 个线程, 队列最多排 64 个, 排满了 accept 会等到队列有空位.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Workers {
+    pub min: usize,
+    pub max: usize,
+    pub queue_capacity: usize,
+}
+
+impl Default for Workers {
+    fn default() -> Self {
+        Workers {
+            min: 4,
+            max: 16,
+            queue_capacity: 64,
+        }
+    }
+}
+
+/// 各种超时, 单位都是秒
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// 收到关闭信号后等进行中的请求多久
+    pub shutdown: u64,
+    /// 连接上游和等待上游响应的超时
+    pub proxy: u64,
+    /// 上游健康检查的间隔
+    pub health_check: u64,
+    /// CGI 脚本和 FastCGI 应用最多运行多久
+    pub cgi: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            shutdown: 30,
+            proxy: 30,
+            health_check: 5,
+            cgi: 30,
+        }
+    }
+}
+
+/// 请求的大小限制和每个连接的超时, 见 `http::Limits`, 超时的单位是秒
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestLimits {
+    pub max_uri_length: usize,
+    pub max_header_size: usize,
+    pub max_headers: usize,
+    pub max_body_size: u64,
+    pub read_timeout: u64,
+    pub write_timeout: u64,
+    pub request_timeout: u64,
+    pub max_keep_alive_requests: usize,
+    pub keep_alive_timeout: u64,
+    pub max_websockets: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        let limits = Limits::default();
+
+        RequestLimits {
+            max_uri_length: limits.max_uri_length,
+            max_header_size: limits.max_header_size,
+            max_headers: limits.max_headers,
+            max_body_size: limits.max_body_size,
+            read_timeout: limits.read_timeout.as_secs(),
+            write_timeout: limits.write_timeout.as_secs(),
+            request_timeout: limits.request_timeout.as_secs(),
+            max_keep_alive_requests: limits.max_keep_alive_requests,
+            keep_alive_timeout: limits.keep_alive_timeout.as_secs(),
+            max_websockets: limits.max_websockets,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    pub path: String,
+    pub file: PathBuf,
+    /// 响应前先等多少毫秒, 用来模拟耗时的请求
+    #[serde(default)]
+    pub delay_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticDir {
+    pub prefix: String,
+    pub dir: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyRoute {
+    pub prefix: String,
+    pub upstreams: Vec<String>,
+}
+
+/// `prefix` 下的请求交给 `dir` 里的 CGI 脚本处理
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CgiDir {
+    pub prefix: String,
+    pub dir: PathBuf,
+}
+
+/// `path` 上的 WebSocket 连接把收到的消息原样发回去
+///
+/// 别的处理函数要在代码里用 `Site::websocket` 注册.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebSocketPath {
+    pub path: String,
+}
+
+/// `prefix` 下的请求交给 FastCGI 应用处理, `addr` 和 `socket` 二选一
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FastCgiApp {
+    pub prefix: String,
+    /// 应用监听的 IP:端口
+    pub addr: Option<String>,
+    /// 应用监听的 Unix socket
+    pub socket: Option<PathBuf>,
+    /// 应用那边的文档根目录, 原样传过去, 不做相对路径处理
+    pub root: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listener: vec![Listener::new("127.0.0.1:7878")],
+            workers: Workers::default(),
+            timeouts: Timeouts::default(),
+            limits: RequestLimits::default(),
+            not_found: Some(PathBuf::from("404.html")),
+            route: vec![
+                Route {
+                    path: "/".to_string(),
+                    file: PathBuf::from("index.html"),
+                    delay_ms: 0,
+                },
+                // 模拟单线程如果有一个耗时的, 其他的就慢了
+                Route {
+                    path: "/api/posts".to_string(),
+                    file: PathBuf::from("data.json"),
+                    delay_ms: 10_000,
+                },
+            ],
+            statics: Vec::new(),
+            proxy: Vec::new(),
+            cgi: Vec::new(),
+            fastcgi: Vec::new(),
+            websocket: vec![WebSocketPath {
+                path: "/ws".to_string(),
+            }],
+        }
+    }
+}
+
+/// 配置文件读不了, 格式不对或者没通过校验
+#[derive(Debug)]
+pub struct ConfigError {
+    pub path: PathBuf,
+    /// 每一条都指出了是哪一项出了什么问题
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid config {}:", self.path.display())?;
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConfigError {}
+
+impl Config {
+    /// 读取并校验配置文件
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let error = |problems| ConfigError {
+            path: path.to_path_buf(),
+            problems,
+        };
+
+        let text = fs::read_to_string(path).map_err(|e| error(vec![e.to_string()]))?;
+        // toml 的错误信息里带着行号和出错的那一行
+        let mut config = Config::parse(&text).map_err(|e| error(vec![e.to_string()]))?;
+
+        if let Some(dir) = path.parent() {
+            config.resolve_paths(dir);
+        }
+        config.validate().map_err(error)?;
+
+        Ok(config)
+    }
+
+    /// 只解析, 不校验, 相对路径也不做处理
+    pub fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// 把相对路径换成相对于 `dir` 的路径
+    pub fn resolve_paths(&mut self, dir: &Path) {
+        let resolve = |path: &mut PathBuf| {
+            if path.is_relative() {
+                *path = dir.join(&*path);
+            }
+        };
+
+        let paths = self
+            .listener
+            .iter_mut()
+            .flat_map(|listener| [&mut listener.cert, &mut listener.key])
+            .chain([&mut self.not_found])
+            .chain(self.fastcgi.iter_mut().map(|app| &mut app.socket));
+        for path in paths.flatten() {
+            resolve(path);
+        }
+        for route in &mut self.route {
+            resolve(&mut route.file);
+        }
+        for root in &mut self.statics {
+            resolve(&mut root.dir);
+        }
+        for cgi in &mut self.cgi {
+            resolve(&mut cgi.dir);
+        }
+    }
+
+    /// 检查所有配置项, 返回发现的全部问题, 而不是遇到第一个就停下
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: String| {
+            if !ok {
+                problems.push(problem);
+            }
+        };
+
+        check(
+            !self.listener.is_empty(),
+            "at least one [[listener]] is required".to_string(),
+        );
+        let mut addrs: HashMap<SocketAddr, usize> = HashMap::new();
+        for (i, listener) in self.listener.iter().enumerate() {
+            match listener.addr.parse::<SocketAddr>() {
+                Ok(addr) => {
+                    let first = *addrs.entry(addr).or_insert(i);
+                    if first != i {
+                        check(
+                            false,
+                            format!(
+                                "listener[{}].addr: {} is already used by listener[{}]",
+                                i, addr, first
+                            ),
+                        );
+                    }
+                }
+                Err(_) => check(
+                    false,
+                    format!(
+                        "listener[{}].addr: `{}` is not a valid address, expected IP:PORT like 127.0.0.1:7878",
+                        i, listener.addr
+                    ),
+                ),
+            }
+
+            check(
+                listener.cert.is_some() == listener.key.is_some(),
+                format!("listener[{}]: cert and key must be set together", i),
+            );
+            for (name, path) in [("cert", &listener.cert), ("key", &listener.key)] {
+                if let Some(path) = path {
+                    check(
+                        path.is_file(),
+                        format!(
+                            "listener[{}].{}: {} does not exist",
+                            i,
+                            name,
+                            path.display()
+                        ),
+                    );
+                }
+            }
+            if listener.redirect_to_https {
+                check(
+                    !listener.is_tls(),
+                    format!(
+                        "listener[{}].redirect_to_https: an HTTPS listener cannot redirect to itself",
+                        i
+                    ),
+                );
+                check(
+                    self.listener.iter().any(Listener::is_tls),
+                    format!(
+                        "listener[{}].redirect_to_https: there is no HTTPS listener to redirect to",
+                        i
+                    ),
+                );
+            }
+        }
+
+        let Workers {
+            min,
+            max,
+            queue_capacity,
+        } = self.workers;
+        check(min > 0, "workers.min must be at least 1".to_string());
+        check(
+            max >= min,
+            format!(
+                "workers.max ({}) must not be less than workers.min ({})",
+                max, min
+            ),
+        );
+        check(
+            queue_capacity > 0,
+            "workers.queue_capacity must be at least 1".to_string(),
+        );
+
+        check(
+            self.timeouts.proxy > 0,
+            "timeouts.proxy must be at least 1 second".to_string(),
+        );
+        check(
+            self.timeouts.health_check > 0,
+            "timeouts.health_check must be at least 1 second".to_string(),
+        );
+        check(
+            self.timeouts.cgi > 0,
+            "timeouts.cgi must be at least 1 second".to_string(),
+        );
+
+        let limits = &self.limits;
+        // 至少要放得下一个请求行
+        check(
+            limits.max_header_size >= 64,
+            format!(
+                "limits.max_header_size: {} bytes is too small, it must be at least 64",
+                limits.max_header_size
+            ),
+        );
+        check(
+            limits.max_uri_length > 0 && limits.max_uri_length < limits.max_header_size,
+            format!(
+                "limits.max_uri_length ({}) must be between 1 and limits.max_header_size ({})",
+                limits.max_uri_length, limits.max_header_size
+            ),
+        );
+        for (name, timeout) in [
+            ("read_timeout", limits.read_timeout),
+            ("write_timeout", limits.write_timeout),
+            ("request_timeout", limits.request_timeout),
+            ("keep_alive_timeout", limits.keep_alive_timeout),
+        ] {
+            check(
+                timeout > 0,
+                format!("limits.{} must be at least 1 second", name),
+            );
+        }
+
+        check(
+            limits.max_keep_alive_requests > 0,
+            "limits.max_keep_alive_requests must be at least 1".to_string(),
+        );
+        check(
+            limits.max_websockets < max,
+            format!(
+                "limits.max_websockets ({}) must be less than workers.max ({})",
+                limits.max_websockets, max
+            ),
+        );
+
+        if let Some(path) = &self.not_found {
+            check(
+                path.is_file(),
+                format!("not_found: {} does not exist", path.display()),
+            );
+        }
+
+        for (i, route) in self.route.iter().enumerate() {
+            check(
+                route.path.starts_with('/'),
+                format!("route[{}].path: `{}` must start with /", i, route.path),
+            );
+            if let Some(first) = self.route[..i].iter().position(|r| r.path == route.path) {
+                check(
+                    false,
+                    format!(
+                        "route[{}].path: {} is already mapped by route[{}]",
+                        i, route.path, first
+                    ),
+                );
+            }
+            check(
+                route.file.is_file(),
+                format!("route[{}].file: {} does not exist", i, route.file.display()),
+            );
+        }
+
+        for (i, root) in self.statics.iter().enumerate() {
+            check(
+                root.prefix.starts_with('/'),
+                format!("static[{}].prefix: `{}` must start with /", i, root.prefix),
+            );
+            check(
+                root.dir.is_dir(),
+                format!(
+                    "static[{}].dir: {} is not a directory",
+                    i,
+                    root.dir.display()
+                ),
+            );
+        }
+
+        for (i, route) in self.proxy.iter().enumerate() {
+            check(
+                route.prefix.starts_with('/'),
+                format!("proxy[{}].prefix: `{}` must start with /", i, route.prefix),
+            );
+            check(
+                !route.upstreams.is_empty(),
+                format!("proxy[{}].upstreams: at least one upstream is required", i),
+            );
+            for (j, upstream) in route.upstreams.iter().enumerate() {
+                let valid = upstream
+                    .rsplit_once(':')
+                    .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+                check(
+                    valid,
+                    format!(
+                        "proxy[{}].upstreams[{}]: `{}` is not a valid address, expected HOST:PORT",
+                        i, j, upstream
+                    ),
+                );
+            }
+        }
+
+        for (i, cgi) in self.cgi.iter().enumerate() {
+            check(
+                cgi.prefix.starts_with('/'),
+                format!("cgi[{}].prefix: `{}` must start with /", i, cgi.prefix),
+            );
+            check(
+                cgi.dir.is_dir(),
+                format!("cgi[{}].dir: {} is not a directory", i, cgi.dir.display()),
+            );
+        }
+
+        for (i, app) in self.fastcgi.iter().enumerate() {
+            check(
+                app.prefix.starts_with('/'),
+                format!("fastcgi[{}].prefix: `{}` must start with /", i, app.prefix),
+            );
+            check(
+                app.addr.is_some() != app.socket.is_some(),
+                format!("fastcgi[{}]: exactly one of addr and socket must be set", i),
+            );
+            if let Some(addr) = &app.addr {
+                check(
+                    addr.parse::<SocketAddr>().is_ok(),
+                    format!(
+                        "fastcgi[{}].addr: `{}` is not a valid address, expected IP:PORT like 127.0.0.1:9000",
+                        i, addr
+                    ),
+                );
+            }
+        }
+
+        for (i, socket) in self.websocket.iter().enumerate() {
+            check(
+                socket.path.starts_with('/'),
+                format!("websocket[{}].path: `{}` must start with /", i, socket.path),
+            );
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// 按 `[workers]` 创建线程池
+    pub fn pool(&self) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::builder()
+            .min_size(self.workers.min)
+            .max_size(self.workers.max)
+            .queue_capacity(self.workers.queue_capacity)
+            .build()
+    }
+
+    /// 路由, 静态目录, CGI 和请求大小限制
+    pub fn site(&self) -> Site {
+        let mut site = Site::new().limits(self.request_limits());
+
+        for route in &self.route {
+            site = site.delayed_route(
+                &route.path,
+                &route.file,
+                Duration::from_millis(route.delay_ms),
+            );
+        }
+        for root in &self.statics {
+            site = site.static_dir(&root.prefix, &root.dir);
+        }
+        if let Some(path) = &self.not_found {
+            site = site.not_found(path);
+        }
+
+        let timeout = Duration::from_secs(self.timeouts.cgi);
+        for cgi in &self.cgi {
+            site = site.cgi(Cgi::new(&cgi.prefix, &cgi.dir).timeout(timeout));
+        }
+        for app in &self.fastcgi {
+            // 校验过的配置里两个一定有一个
+            let address = match (&app.addr, &app.socket) {
+                (Some(addr), _) => Address::Tcp(addr.clone()),
+                (None, socket) => Address::Unix(socket.clone().unwrap()),
+            };
+            site = site.fastcgi(FastCgi::new(&app.prefix, address, &app.root).timeout(timeout));
+        }
+        for socket in &self.websocket {
+            site = site.websocket(&socket.path, websocket::echo);
+        }
+
+        site
+    }
+
+    /// 没有配置 `[[proxy]]` 时返回 `None`
+    pub fn proxy(&self) -> Option<Proxy> {
+        if self.proxy.is_empty() {
+            return None;
+        }
+
+        let proxy = self
+            .proxy
+            .iter()
+            .fold(Proxy::new(), |proxy, route| {
+                let upstreams: Vec<&str> = route.upstreams.iter().map(String::as_str).collect();
+                proxy.route(&route.prefix, &upstreams)
+            })
+            .timeout(Duration::from_secs(self.timeouts.proxy));
+
+        Some(proxy)
+    }
+
+    pub fn request_limits(&self) -> Limits {
+        let limits = &self.limits;
+
+        Limits {
+            max_uri_length: limits.max_uri_length,
+            max_header_size: limits.max_header_size,
+            max_headers: limits.max_headers,
+            max_body_size: limits.max_body_size,
+            read_timeout: Duration::from_secs(limits.read_timeout),
+            write_timeout: Duration::from_secs(limits.write_timeout),
+            request_timeout: Duration::from_secs(limits.request_timeout),
+            max_keep_alive_requests: limits.max_keep_alive_requests,
+            keep_alive_timeout: Duration::from_secs(limits.keep_alive_timeout),
+            max_websockets: limits.max_websockets,
+        }
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeouts.shutdown)
+    }
+
+    pub fn health_check_interval(&self) -> Duration {
+        Duration::from_secs(self.timeouts.health_check)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_config_and_keeps_defaults_for_missing_sections() {
+        let config = Config::parse(
+            r#"
+            [[listener]]
+            addr = "127.0.0.1:8080"
+
+            [[listener]]
+            addr = "[::1]:8443"
+            cert = "cert.pem"
+            key = "key.pem"
+
+            [workers]
+            max = 32
+
+            [limits]
+            max_header_size = 4096
+            request_timeout = 5
+
+            [[proxy]]
+            prefix = "/api"
+            upstreams = ["127.0.0.1:10086", "localhost:10087"]
+
+            [[fastcgi]]
+            prefix = "/php"
+            addr = "127.0.0.1:9000"
+            root = "/var/www"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(2, config.listener.len());
+        assert!(config.listener[1].is_tls());
+        assert_eq!("[::1]:8443".parse(), Ok(config.listener[1].socket_addr()));
+        assert_eq!(4, config.workers.min);
+        assert_eq!(32, config.workers.max);
+        assert_eq!(4096, config.request_limits().max_header_size);
+        assert_eq!(
+            Duration::from_secs(5),
+            config.request_limits().request_timeout
+        );
+        assert_eq!(Limits::default().max_body_size, config.limits.max_body_size);
+        assert_eq!(Config::default().route, config.route);
+        assert_eq!(2, config.proxy[0].upstreams.len());
+        assert_eq!(Some("127.0.0.1:9000"), config.fastcgi[0].addr.as_deref());
+        assert_eq!(30, config.timeouts.cgi);
+        assert_eq!(Config::default().websocket, config.websocket);
+    }
+
+    #[test]
+    fn reports_every_problem_with_its_location() {
+        let config = Config::parse(
+            r#"
+            [[listener]]
+            addr = "localhost"
+            redirect_to_https = true
+
+            [[listener]]
+            addr = "127.0.0.1:7878"
+            cert = "missing.pem"
+
+            [workers]
+            min = 8
+            max = 2
+
+            [limits]
+            max_header_size = 10
+            read_timeout = 0
+
+            [[route]]
+            path = "index"
+            file = "missing.html"
+
+            [[proxy]]
+            prefix = "/api"
+            upstreams = ["127.0.0.1"]
+
+            [[cgi]]
+            prefix = "/cgi-bin"
+            dir = "missing"
+
+            [[fastcgi]]
+            prefix = "/php"
+            root = "/var/www"
+
+            [[websocket]]
+            path = "ws"
+            "#,
+        )
+        .unwrap();
+
+        let problems = config.validate().unwrap_err();
+
+        assert_eq!(
+            vec![
+                "listener[0].addr: `localhost` is not a valid address, expected IP:PORT like 127.0.0.1:7878",
+                "listener[0].redirect_to_https: there is no HTTPS listener to redirect to",
+                "listener[1]: cert and key must be set together",
+                "listener[1].cert: missing.pem does not exist",
+                "workers.max (2) must not be less than workers.min (8)",
+                "limits.max_header_size: 10 bytes is too small, it must be at least 64",
+                "limits.max_uri_length (2048) must be between 1 and limits.max_header_size (10)",
+                "limits.read_timeout must be at least 1 second",
+                "limits.max_websockets (8) must be less than workers.max (2)",
+                "route[0].path: `index` must start with /",
+                "route[0].file: missing.html does not exist",
+                "proxy[0].upstreams[0]: `127.0.0.1` is not a valid address, expected HOST:PORT",
+                "cgi[0].dir: missing is not a directory",
+                "fastcgi[0]: exactly one of addr and socket must be set",
+                "websocket[0].path: `ws` must start with /",
+            ],
+            problems
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let error = Config::parse("[workers]\nmin = 1\nthreads = 4\n").unwrap_err();
+
+        assert!(error.to_string().contains("unknown field `threads`"));
+    }
+}
@@ -0,0 +1,452 @@
+use crate::{lock, ExecuteError, Job, ThreadPool};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// 任务的优先级, `execute` 提交的任务是 `Normal`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    /// 比如健康检查, 只要有空闲的 worker 就先执行
+    High,
+}
+
+// 一条车道就是一个先进先出的队列, 三个优先级各占一条, 每个具名队列也各占一条
+struct Lane {
+    priority: Priority,
+    weight: i64,
+    // 平滑加权轮询(和 nginx 的 upstream 一样)用的当前权重
+    current: i64,
+    // 槽里的任务可能已经被它自己的令牌直接取走了, 这样的空槽留在原地, 轮到时再扔掉
+    jobs: VecDeque<Slot>,
+}
+
+// 一个任务的位置, 车道和令牌各拿一份, 令牌要取自己的任务时不用在车道里找
+type Slot = Arc<Mutex<Option<Job>>>;
+
+impl Lane {
+    fn new(priority: Priority, weight: u32) -> Lane {
+        Lane {
+            priority,
+            weight: i64::from(weight),
+            current: 0,
+            jobs: VecDeque::new(),
+        }
+    }
+
+    // 扔掉队头已经被取走的空槽, 之后车道不空就说明里面还有任务
+    fn trim(&mut self) {
+        while self.jobs.front().is_some_and(|slot| lock(slot).is_none()) {
+            self.jobs.pop_front();
+        }
+    }
+}
+
+struct DispatchState {
+    lanes: Vec<Lane>,
+}
+
+impl DispatchState {
+    // 先看优先级, 同一优先级的车道之间按权重轮流
+    fn pick(&mut self) -> Option<usize> {
+        for lane in &mut self.lanes {
+            lane.trim();
+        }
+
+        let priority = self
+            .lanes
+            .iter()
+            .filter(|lane| !lane.jobs.is_empty())
+            .map(|lane| lane.priority)
+            .max()?;
+
+        let mut total = 0;
+        let mut best: Option<(usize, i64)> = None;
+        for (index, lane) in self.lanes.iter_mut().enumerate() {
+            if lane.priority != priority || lane.jobs.is_empty() {
+                continue;
+            }
+
+            lane.current += lane.weight;
+            total += lane.weight;
+            if best.is_none_or(|(_, current)| lane.current > current) {
+                best = Some((index, lane.current));
+            }
+        }
+
+        let (best, _) = best?;
+        self.lanes[best].current -= total;
+
+        Some(best)
+    }
+}
+
+// 在 Message::NewJob 之上的一层调度
+// 真正的任务先按车道排好, 再往底层队列里放一个令牌, 令牌和任务一一对应,
+// worker 取到令牌时才决定执行哪个任务, 所以后提交的高优先级任务可以插到前面
+//
+// 这一层有一把全局的锁, 只在真的用到优先级或者具名队列之后才启用,
+// 在那之前 `execute` 的任务直接进底层的工作窃取队列
+pub(crate) struct Dispatcher {
+    state: Mutex<DispatchState>,
+    engaged: AtomicBool,
+    // 具名队列的名字, 建好之后不再变, 放在锁外面, 按名字找车道时不用拿全局的锁
+    names: Vec<String>,
+}
+
+impl Dispatcher {
+    /// `queues` 是具名队列的名字和权重, 它们和 `Priority::Normal` 的任务一起按权重轮流执行
+    pub(crate) fn new(queues: &[(String, u32)]) -> Dispatcher {
+        let mut lanes = vec![
+            Lane::new(Priority::High, 1),
+            Lane::new(Priority::Normal, 1),
+            Lane::new(Priority::Low, 1),
+        ];
+        lanes.extend(
+            queues
+                .iter()
+                .map(|(_, weight)| Lane::new(Priority::Normal, *weight)),
+        );
+
+        Dispatcher {
+            state: Mutex::new(DispatchState { lanes }),
+            engaged: AtomicBool::new(!queues.is_empty()),
+            names: queues.iter().map(|(name, _)| name.clone()).collect(),
+        }
+    }
+
+    /// 有没有任务经过调度器, 没有的话 `Priority::Normal` 的任务可以绕过它
+    ///
+    /// 一旦启用就不再关掉, 否则绕过去的普通任务会排到高优先级任务前面.
+    /// 启用之前已经直接进了底层队列的任务还是按原来的顺序执行.
+    pub(crate) fn engaged(&self) -> bool {
+        self.engaged.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn lane(&self, priority: Priority) -> usize {
+        match priority {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+
+    // 具名队列的车道排在三个优先级后面
+    pub(crate) fn named_lane(&self, name: &str) -> Option<usize> {
+        self.names
+            .iter()
+            .position(|existing| existing == name)
+            .map(|index| index + 3)
+    }
+
+    /// 把任务放进车道, 返回要交给底层队列的令牌
+    pub(crate) fn enqueue(self: &Arc<Self>, lane: usize, job: Job) -> Token {
+        self.engaged.store(true, Ordering::Relaxed);
+
+        let slot = Arc::new(Mutex::new(Some(job)));
+        lock(&self.state).lanes[lane]
+            .jobs
+            .push_back(Arc::clone(&slot));
+
+        Token {
+            dispatcher: Arc::clone(self),
+            slot,
+            ran: false,
+        }
+    }
+
+    fn next(&self) -> Option<Job> {
+        let mut state = lock(&self.state);
+        let lane = state.pick()?;
+
+        // pick 之后车道的队头一定是还没被取走的任务
+        let slot = state.lanes[lane].jobs.pop_front()?;
+        let job = lock(&slot).take();
+        job
+    }
+
+    // 令牌被 DropOldest 挤掉时, 去掉最低优先级车道里最老的任务, 保证令牌和任务数量一致
+    //
+    // 令牌和任务不是一一绑定的, 被挤掉的令牌自己的任务可能已经被别的令牌执行了,
+    // 还在的话也不一定是最该丢的那个.
+    fn discard_oldest(&self) -> Option<Job> {
+        let mut state = lock(&self.state);
+
+        let mut lanes: Vec<&mut Lane> = state.lanes.iter_mut().collect();
+        lanes.sort_by_key(|lane| lane.priority);
+
+        lanes.into_iter().find_map(|lane| {
+            while let Some(slot) = lane.jobs.pop_front() {
+                if let Some(job) = lock(&slot).take() {
+                    return Some(job);
+                }
+            }
+            None
+        })
+    }
+}
+
+thread_local! {
+    // 正在提交的令牌(它的槽的地址), 拒绝策略是 CallerRuns 时令牌会在提交的过程中直接执行
+    static SUBMITTING: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// 底层队列里实际排队的东西, 执行时从调度器里取出当前最该执行的任务
+pub(crate) struct Token {
+    dispatcher: Arc<Dispatcher>,
+    slot: Slot,
+    ran: bool,
+}
+
+impl Token {
+    fn id(&self) -> usize {
+        Arc::as_ptr(&self.slot) as usize
+    }
+
+    // 直接取出自己的任务, 它还在车道里的话留下一个空槽
+    fn take(&self) -> Option<Job> {
+        lock(&self.slot).take()
+    }
+
+    /// 把令牌包成 Job 交给 `push`
+    pub(crate) fn submit<R>(self, push: impl FnOnce(Job) -> R) -> R {
+        let previous = SUBMITTING.with(|submitting| submitting.replace(Some(self.id())));
+        let result = push(Box::new(move || self.run()));
+        SUBMITTING.with(|submitting| submitting.set(previous));
+
+        result
+    }
+
+    fn run(mut self) {
+        self.ran = true;
+
+        // 在提交方线程上执行时, 执行的应该是刚提交的这个任务, 而不是车道里最早的那个
+        let job = if SUBMITTING.with(Cell::get) == Some(self.id()) {
+            self.take()
+        } else {
+            None
+        };
+
+        if let Some(job) = job.or_else(|| self.dispatcher.next()) {
+            job();
+        }
+    }
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        // 令牌没被执行就被丢掉了, 也要去掉一个任务
+        if !self.ran {
+            // 在提交自己的过程中被丢掉, 说明被拒绝的就是它, 去掉它自己的任务;
+            // 否则是排队时被 DropOldest 挤掉的, 按策略去掉最老的
+            let rejected = SUBMITTING.with(Cell::get) == Some(self.id());
+            let job = if rejected { self.take() } else { None };
+            // 任务在锁外面释放, 它的 drop 可能还要做别的事
+            drop(job.or_else(|| self.dispatcher.discard_oldest()));
+        }
+    }
+}
+
+impl ThreadPool {
+    /// Submit a job with the given priority.
+    ///
+    /// Whenever a worker becomes free it runs the oldest job of the highest
+    /// priority that has any waiting, so `High` jobs such as health checks
+    /// are not stuck behind a backlog of slow `Normal` ones.
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let _ = self.try_execute_with_priority(priority, f);
+    }
+
+    /// Submit a job with the given priority, returning an error if it was rejected.
+    pub fn try_execute_with_priority<F>(&self, priority: Priority, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let lane = self.shared.dispatcher.lane(priority);
+
+        self.shared.submit_to(lane, Box::new(f))
+    }
+
+    /// Submit a job into a named queue configured with `ThreadPoolBuilder::task_queue`.
+    ///
+    /// Named queues share the `Normal` priority level with plain `execute`
+    /// jobs and take turns with them in proportion to their weights.
+    ///
+    /// ```
+    /// use web_server::ThreadPool;
+    ///
+    /// let pool = ThreadPool::builder().size(2).task_queue("posts", 1).build().unwrap();
+    ///
+    /// assert!(pool.try_execute_in("posts", || {}).is_ok());
+    /// assert!(pool.try_execute_in("missing", || {}).is_err());
+    /// ```
+    pub fn try_execute_in<F>(&self, queue: &str, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let lane = self
+            .shared
+            .dispatcher
+            .named_lane(queue)
+            .ok_or_else(|| ExecuteError::UnknownQueue(queue.to_string()))?;
+
+        self.shared.submit_to(lane, Box::new(f))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dispatcher(queues: &[(&str, u32)]) -> Arc<Dispatcher> {
+        let queues: Vec<_> = queues
+            .iter()
+            .map(|(name, weight)| (name.to_string(), *weight))
+            .collect();
+
+        Arc::new(Dispatcher::new(&queues))
+    }
+
+    fn enqueue(dispatcher: &Arc<Dispatcher>, lane: usize, order: &Arc<Mutex<Vec<char>>>, c: char) {
+        let order = Arc::clone(order);
+        let mut token = dispatcher.enqueue(lane, Box::new(move || lock(&order).push(c)));
+        // 测试里直接从调度器取任务, 不让令牌去掉对应的任务
+        token.ran = true;
+    }
+
+    #[test]
+    fn higher_priority_runs_first_and_weights_share_normal_level() {
+        let dispatcher = dispatcher(&[("api", 2)]);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let api = dispatcher.named_lane("api").unwrap();
+
+        for _ in 0..3 {
+            enqueue(&dispatcher, dispatcher.lane(Priority::Normal), &order, 'n');
+            enqueue(&dispatcher, api, &order, 'a');
+        }
+        enqueue(&dispatcher, dispatcher.lane(Priority::Low), &order, 'l');
+        enqueue(&dispatcher, dispatcher.lane(Priority::High), &order, 'h');
+
+        while let Some(job) = dispatcher.next() {
+            job();
+        }
+
+        // api 的权重是默认车道的两倍
+        let order: String = lock(&order).iter().collect();
+        assert_eq!("hanaannl", order);
+    }
+
+    #[test]
+    fn rejected_tokens_discard_their_own_job() {
+        let dispatcher = dispatcher(&[]);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        enqueue(&dispatcher, dispatcher.lane(Priority::Normal), &order, 'a');
+        let captured = Arc::clone(&order);
+        let token = dispatcher.enqueue(
+            dispatcher.lane(Priority::Normal),
+            Box::new(move || lock(&captured).push('b')),
+        );
+        // 像 Reject 那样在提交的过程中就把令牌丢掉
+        token.submit(drop);
+
+        while let Some(job) = dispatcher.next() {
+            job();
+        }
+
+        assert_eq!(vec!['a'], *lock(&order));
+    }
+
+    #[test]
+    fn evicted_tokens_discard_the_oldest_job_of_the_lowest_lane() {
+        let dispatcher = dispatcher(&[]);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        enqueue(&dispatcher, dispatcher.lane(Priority::High), &order, 'h');
+        enqueue(&dispatcher, dispatcher.lane(Priority::Low), &order, 'l');
+        enqueue(&dispatcher, dispatcher.lane(Priority::Low), &order, 'm');
+        let captured = Arc::clone(&order);
+        let token = dispatcher.enqueue(
+            dispatcher.lane(Priority::Normal),
+            Box::new(move || lock(&captured).push('n')),
+        );
+        // 排队时被 DropOldest 挤掉
+        drop(token);
+
+        while let Some(job) = dispatcher.next() {
+            job();
+        }
+
+        assert_eq!("hnm", lock(&order).iter().collect::<String>());
+    }
+
+    #[test]
+    fn jobs_taken_by_their_token_leave_an_empty_slot() {
+        let dispatcher = dispatcher(&[]);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let normal = dispatcher.lane(Priority::Normal);
+
+        let captured = Arc::clone(&order);
+        let mut token = dispatcher.enqueue(normal, Box::new(move || lock(&captured).push('a')));
+        enqueue(&dispatcher, normal, &order, 'b');
+
+        // 像 CallerRuns 那样直接取自己的任务, 车道里只剩一个空槽
+        token.ran = true;
+        token.take().unwrap()();
+        while let Some(job) = dispatcher.next() {
+            job();
+        }
+
+        assert_eq!(vec!['a', 'b'], *lock(&order));
+    }
+
+    #[test]
+    fn normal_jobs_bypass_the_dispatcher_until_it_is_used() {
+        let pool = ThreadPool::new(1).unwrap();
+
+        pool.execute(|| {});
+        assert!(!pool.shared.dispatcher.engaged());
+
+        pool.execute_with_priority(Priority::High, || {});
+        assert!(pool.shared.dispatcher.engaged());
+
+        let named = ThreadPool::builder()
+            .size(1)
+            .task_queue("posts", 1)
+            .build()
+            .unwrap();
+        assert!(named.shared.dispatcher.engaged());
+    }
+
+    #[test]
+    fn high_priority_jobs_skip_the_backlog() {
+        let pool = ThreadPool::new(1).unwrap();
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let (started, wait_started) = std::sync::mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = blocked.recv();
+        });
+        wait_started.recv().unwrap();
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for (priority, c) in [
+            (Priority::Low, 'l'),
+            (Priority::Normal, 'n'),
+            (Priority::High, 'h'),
+        ] {
+            let order = Arc::clone(&order);
+            pool.execute_with_priority(priority, move || lock(&order).push(c));
+        }
+        release.send(()).unwrap();
+        pool.shutdown(std::time::Duration::from_secs(1));
+
+        assert_eq!(vec!['h', 'n', 'l'], *lock(&order));
+    }
+}
@@ -0,0 +1,409 @@
+use crate::http::{self, Limits, RequestError, Response};
+use crate::server;
+use crate::shutdown::ShutdownSignal;
+use crate::site;
+use crate::{ShutdownReport, ThreadPool};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+
+enum State {
+    Reading(Vec<u8>),
+    // 请求已经交给线程池, 等响应回来
+    Handling,
+    Writing { response: Vec<u8>, written: usize },
+}
+
+struct Connection {
+    stream: TcpStream,
+    state: State,
+    accepted: Instant,
+    // 上一次读到或者写出数据的时间
+    last_active: Instant,
+}
+
+// 线程池处理完请求后, 把响应连同连接的 token 发回事件循环
+type Completed = (Token, Vec<u8>);
+
+impl Connection {
+    fn new(stream: TcpStream) -> Connection {
+        let now = Instant::now();
+
+        Connection {
+            stream,
+            state: State::Reading(Vec::new()),
+            accepted: now,
+            last_active: now,
+        }
+    }
+
+    // 返回 true 表示连接可以关掉了
+    fn on_readable(
+        &mut self,
+        token: Token,
+        registry: &Registry,
+        pool: &ThreadPool,
+        completed: &mpsc::Sender<Completed>,
+        waker: &Arc<Waker>,
+    ) -> bool {
+        let State::Reading(buffer) = &mut self.state else {
+            return false;
+        };
+
+        let site = site::current();
+        let limits = site.request_limits();
+        // 再多就肯定超过限制了, 不用接着读
+        let cap = limits.max_header_size as u64 + limits.max_body_size;
+
+        // mio 是边沿触发的, 要一直读到 WouldBlock, 否则不会再收到可读事件
+        let mut chunk = [0; 1024];
+        let mut closed = false;
+        while (buffer.len() as u64) <= cap {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                }
+                Ok(n) => {
+                    buffer.extend_from_slice(&chunk[..n]);
+                    self.last_active = Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return true,
+            }
+        }
+
+        let request = match http::parse_request(buffer, limits) {
+            Ok(Some(request)) => request,
+            Ok(None) if !closed => return false,
+            Ok(None) if buffer.is_empty() => return true,
+            Ok(None) => {
+                let error = RequestError::Malformed("connection closed mid-request");
+                return self.reject(token, registry, error);
+            }
+            // 超过限制的请求不用交给线程池, 直接回错误响应
+            Err(error) => return self.reject(token, registry, error),
+        };
+
+        self.state = State::Handling;
+        let client = self.stream.peer_addr().ok();
+        let started = Instant::now();
+
+        let completed = completed.clone();
+        let waker = Arc::clone(waker);
+        // 处理请求可能很慢(比如 /api/posts), 不能放在事件循环线程上做
+        pool.execute(move || {
+            let result =
+                panic::catch_unwind(AssertUnwindSafe(|| server::respond_to(&request, client)));
+            let (mut response, panicked) = match result {
+                Ok(response) => (response, None),
+                Err(payload) => (
+                    Response::new("HTTP/1.1 500 INTERNAL SERVER ERROR", Vec::new()),
+                    Some(payload),
+                ),
+            };
+            // 写完响应就关连接, 不支持 keep-alive, 要明确告诉 HTTP/1.1 的客户端
+            response.set_header("Connection", "close");
+
+            let mut bytes = Vec::new();
+            // 写进 Vec 不会失败
+            response.write_to(&mut bytes).unwrap();
+            if completed.send((token, bytes)).is_ok() {
+                let _ = waker.wake();
+            }
+            // 响应是事件循环线程写回去的, 这里记的耗时不包括写的时间
+            server::log_response(client, request.raw(), &response, started);
+
+            // 连接已经有了响应, 再把 panic 交给线程池的 panic hook
+            if let Some(payload) = panicked {
+                panic::resume_unwind(payload);
+            }
+        });
+
+        false
+    }
+
+    fn reject(&mut self, token: Token, registry: &Registry, error: RequestError) -> bool {
+        let Some(response) = error.response() else {
+            return true;
+        };
+
+        server::log_response(self.stream.peer_addr().ok(), b"", &response, self.accepted);
+        let mut bytes = Vec::new();
+        response.write_to(&mut bytes).unwrap();
+        self.start_writing(token, registry, bytes)
+    }
+
+    // 返回 true 表示响应一次就写完了(或者写失败了), 连接可以关掉了
+    fn start_writing(&mut self, token: Token, registry: &Registry, response: Vec<u8>) -> bool {
+        self.state = State::Writing {
+            response,
+            written: 0,
+        };
+        self.last_active = Instant::now();
+
+        // 大部分响应一次就能写完, 写不完再等可写事件
+        if self.on_writable() {
+            return true;
+        }
+        // 只影响这一个连接, 关掉它就行, 不能让整个事件循环退出
+        if let Err(e) = registry.reregister(&mut self.stream, token, Interest::WRITABLE) {
+            eprintln!("Failed to wait for connection to become writable: {}", e);
+            return true;
+        }
+        false
+    }
+
+    // 返回 true 表示响应写完了或者写失败了, 连接可以关掉了
+    fn on_writable(&mut self) -> bool {
+        let State::Writing { response, written } = &mut self.state else {
+            return false;
+        };
+
+        while *written < response.len() {
+            match self.stream.write(&response[*written..]) {
+                Ok(0) => return true,
+                Ok(n) => {
+                    *written += n;
+                    self.last_active = Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return false,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return true,
+            }
+        }
+
+        true
+    }
+
+    // 一直不发完请求(slowloris)或者一直不收响应的连接
+    fn expired(&self, limits: &Limits) -> bool {
+        match self.state {
+            State::Reading(_) => {
+                self.accepted.elapsed() > limits.request_timeout
+                    || self.last_active.elapsed() > limits.read_timeout
+            }
+            State::Handling => false,
+            State::Writing { .. } => self.last_active.elapsed() > limits.write_timeout,
+        }
+    }
+}
+
+/// 基于 epoll(通过 mio)的事件循环, 一个线程管理所有连接的读写, 请求交给 `pool` 处理
+///
+/// 没发完请求的连接不占用 worker, 所以少量线程就能挂住成千上万个连接.
+/// 收到关闭信号后停止 accept, 丢掉还没读完请求的连接, 等已经在处理的请求写回响应后返回.
+/// 单个连接上的错误只会关掉那个连接, 只有创建 epoll 或者 poll 本身出错时才返回错误.
+pub fn run(
+    listener: net::TcpListener,
+    pool: ThreadPool,
+    shutdown: &ShutdownSignal,
+    timeout: Duration,
+) -> io::Result<ShutdownReport> {
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(1024);
+
+    listener.set_nonblocking(true)?;
+    let mut listener = Some(TcpListener::from_std(listener));
+    if let Some(listener) = &mut listener {
+        poll.registry()
+            .register(listener, LISTENER, Interest::READABLE)?;
+    }
+
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+    let (completed, responses) = mpsc::channel::<Completed>();
+
+    let mut connections: HashMap<Token, Connection> = HashMap::new();
+    let mut next_token = WAKER.0 + 1;
+
+    loop {
+        if shutdown.is_triggered() {
+            if let Some(mut listener) = listener.take() {
+                // 关掉 listener, 新连接会直接被拒绝
+                if let Err(e) = poll.registry().deregister(&mut listener) {
+                    eprintln!("Failed to stop listening: {}", e);
+                }
+                connections.retain(|_, connection| !matches!(connection.state, State::Reading(_)));
+            }
+
+            if connections.is_empty() {
+                break;
+            }
+        }
+
+        // 带超时是为了定期检查关闭标记
+        match poll.poll(&mut events, Some(Duration::from_millis(100))) {
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            result => result?,
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => {
+                    let Some(listener) = &listener else {
+                        continue;
+                    };
+
+                    loop {
+                        match listener.accept() {
+                            Ok((mut stream, _)) => {
+                                let token = Token(next_token);
+                                next_token += 1;
+
+                                // 注册失败时 stream 在这里被丢掉, 连接就关了
+                                match poll.registry().register(
+                                    &mut stream,
+                                    token,
+                                    Interest::READABLE,
+                                ) {
+                                    Ok(()) => {
+                                        connections.insert(token, Connection::new(stream));
+                                    }
+                                    Err(e) => eprintln!("Failed to register connection: {}", e),
+                                }
+                            }
+                            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                            Err(e) => {
+                                eprintln!("Failed to accept connection: {}", e);
+                                break;
+                            }
+                        }
+                    }
+                }
+                WAKER => {
+                    for (token, response) in responses.try_iter() {
+                        let Some(connection) = connections.get_mut(&token) else {
+                            continue;
+                        };
+
+                        if connection.start_writing(token, poll.registry(), response) {
+                            close(&poll, &mut connections, token);
+                        }
+                    }
+                }
+                token => {
+                    let Some(connection) = connections.get_mut(&token) else {
+                        continue;
+                    };
+
+                    let mut done = false;
+                    if event.is_readable() {
+                        done |= connection.on_readable(
+                            token,
+                            poll.registry(),
+                            &pool,
+                            &completed,
+                            &waker,
+                        );
+                    }
+                    if event.is_writable() {
+                        done |= connection.on_writable();
+                    }
+
+                    if done {
+                        close(&poll, &mut connections, token);
+                    }
+                }
+            }
+        }
+
+        // 请求发得太慢或者响应一直收不走的连接, 读的时候回 408, 写的时候直接断开
+        let site = site::current();
+        let expired: Vec<Token> = connections
+            .iter()
+            .filter(|(_, connection)| connection.expired(site.request_limits()))
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired {
+            if let Some(connection) = connections.get_mut(&token) {
+                if let State::Reading(_) = connection.state {
+                    let mut bytes = Vec::new();
+                    let response = RequestError::Timeout.response().unwrap();
+                    response.write_to(&mut bytes).unwrap();
+                    // 非阻塞地写一次, 写不进去就算了
+                    let _ = connection.stream.write(&bytes);
+                }
+            }
+            close(&poll, &mut connections, token);
+        }
+    }
+
+    Ok(pool.shutdown(timeout))
+}
+
+// 注销失败也没关系, 连接被丢掉时 fd 关闭, epoll 会自己把它移除
+fn close(poll: &Poll, connections: &mut HashMap<Token, Connection>, token: Token) {
+    if let Some(mut connection) = connections.remove(&token) {
+        if let Err(e) = poll.registry().deregister(&mut connection.stream) {
+            eprintln!("Failed to deregister connection: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+    use std::thread;
+
+    fn get(addr: net::SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_requests_while_other_connections_idle() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = ShutdownSignal::new();
+
+        let signal = shutdown.clone();
+        let server = thread::spawn(move || {
+            let pool = ThreadPool::new(1).unwrap();
+            run(listener, pool, &signal, Duration::from_secs(1)).unwrap()
+        });
+
+        // 这些连接一直不发请求, 阻塞模式下它们会占住唯一的 worker
+        let idle: Vec<_> = (0..50).map(|_| TcpStream::connect(addr).unwrap()).collect();
+
+        for _ in 0..3 {
+            assert!(get(addr).starts_with("HTTP/1.1 200 OK\r\n"));
+        }
+
+        shutdown.trigger();
+        let report = server.join().unwrap();
+        assert!(report.timed_out.is_empty());
+        drop(idle);
+    }
+
+    #[test]
+    fn closing_a_connection_that_fails_to_deregister_only_drops_it() {
+        let poll = Poll::new().unwrap();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream.set_nonblocking(true).unwrap();
+
+        // 没有注册过, 注销会失败
+        let mut connections = HashMap::new();
+        connections.insert(
+            Token(2),
+            Connection::new(mio::net::TcpStream::from_std(stream)),
+        );
+        close(&poll, &mut connections, Token(2));
+
+        assert!(connections.is_empty());
+    }
+}
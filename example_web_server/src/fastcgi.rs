@@ -0,0 +1,382 @@
+use crate::cgi;
+use crate::http::{Request, Response};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+// 记录类型, 见 FastCGI 规范第 8 节
+const VERSION: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+// 每个连接只发一个请求, 所以 id 固定是 1
+const REQUEST_ID: u16 = 1;
+const MAX_CONTENT: usize = 65535;
+// 脚本的输出最多读这么多, 防止把内存撑爆
+const MAX_OUTPUT: usize = 64 * 1024 * 1024;
+
+/// FastCGI 应用监听的地址
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+/// 把以 `prefix` 开头的请求用 FastCGI 协议转给本地的应用(比如 php-fpm)
+///
+/// 每个请求新建一个连接, 不复用. 应用根据 `SCRIPT_FILENAME` 找脚本,
+/// 它是 `root` 加上请求路径.
+///
+/// ```no_run
+/// use std::path::PathBuf;
+/// use web_server::fastcgi::{Address, FastCgi};
+/// use web_server::site::{self, Site};
+///
+/// let php = FastCgi::new("/php", Address::Unix(PathBuf::from("/run/php/php-fpm.sock")), "/var/www");
+/// site::install(Site::default().fastcgi(php));
+/// ```
+#[derive(Debug, Clone)]
+pub struct FastCgi {
+    prefix: String,
+    address: Address,
+    root: PathBuf,
+    timeout: Duration,
+}
+
+trait Stream: Read + Write {}
+
+impl<S: Read + Write> Stream for S {}
+
+impl FastCgi {
+    pub fn new(prefix: &str, address: Address, root: impl Into<PathBuf>) -> FastCgi {
+        FastCgi {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            address,
+            root: root.into(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// 连接, 读和写应用的超时, 默认 30 秒
+    pub fn timeout(mut self, timeout: Duration) -> FastCgi {
+        self.timeout = timeout;
+        self
+    }
+
+    /// 不是 `prefix` 下的路径返回 `None`
+    pub fn handle(&self, request: &Request, client: Option<SocketAddr>) -> Option<Response> {
+        let path = request.path();
+        cgi::strip_prefix(path, &self.prefix)?;
+
+        // 应用会直接执行 SCRIPT_FILENAME 指向的文件, 不能让它指到 root 外面去
+        if !cgi::safe_segments(path.trim_start_matches('/')) {
+            return Some(cgi::status("HTTP/1.1 404 NOT FOUND"));
+        }
+
+        // 整个路径都是脚本名, 由应用自己决定怎么处理
+        let script = self.root.join(path.trim_start_matches('/'));
+        let params = cgi::environment(request, client, path, "", &script);
+
+        Some(match self.run(&params, &request.body) {
+            Ok(output) => cgi::parse_output(&output).unwrap_or_else(|e| {
+                eprintln!("Invalid output from FastCGI {:?}: {}", self.address, e);
+                cgi::status("HTTP/1.1 502 BAD GATEWAY")
+            }),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                eprintln!("FastCGI {:?} timed out", self.address);
+                cgi::status("HTTP/1.1 504 GATEWAY TIMEOUT")
+            }
+            Err(e) => {
+                eprintln!("Failed to talk to FastCGI {:?}: {}", self.address, e);
+                cgi::status("HTTP/1.1 502 BAD GATEWAY")
+            }
+        })
+    }
+
+    fn connect(&self) -> io::Result<Box<dyn Stream>> {
+        match &self.address {
+            Address::Tcp(addr) => {
+                let addr = addr
+                    .parse()
+                    .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "invalid address"))?;
+                let stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+                stream.set_read_timeout(Some(self.timeout))?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                Ok(Box::new(stream))
+            }
+            Address::Unix(path) => {
+                // UnixStream 没有 connect_timeout, 应用的监听队列满了时 connect 会一直卡住,
+                // 所以放到另一个线程里连, 超时就不等了, 那个线程之后连上了也会直接把连接关掉
+                let (sender, receiver) = mpsc::channel();
+                let path = path.clone();
+                thread::spawn(move || {
+                    let _ = sender.send(UnixStream::connect(path));
+                });
+                let stream = match receiver.recv_timeout(self.timeout) {
+                    Ok(stream) => stream?,
+                    Err(_) => return Err(io::Error::new(ErrorKind::TimedOut, "connect timed out")),
+                };
+                stream.set_read_timeout(Some(self.timeout))?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+
+    // 返回应用写到 FCGI_STDOUT 的全部内容
+    fn run(&self, params: &[(String, String)], body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = self.connect()?;
+
+        // 请求都攒在一起一次写出去
+        let mut out = Vec::new();
+        // role 和 flags(0: 响应完就关连接), 后面 5 个保留字节
+        let mut begin = RESPONDER.to_be_bytes().to_vec();
+        begin.extend_from_slice(&[0; 6]);
+        write_record(&mut out, BEGIN_REQUEST, &begin);
+        write_stream(&mut out, PARAMS, &encode_params(params));
+        write_stream(&mut out, STDIN, body);
+        stream.write_all(&out)?;
+        stream.flush()?;
+
+        let mut output = Vec::new();
+        loop {
+            let (kind, content) = read_record(&mut stream)?;
+            match kind {
+                STDOUT if output.len() + content.len() > MAX_OUTPUT => {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "output too large"));
+                }
+                STDOUT => output.extend_from_slice(&content),
+                STDERR if !content.is_empty() => {
+                    eprint!("{}", String::from_utf8_lossy(&content));
+                }
+                // 前 4 个字节是应用的退出码, 第 5 个是协议状态, 0 表示正常完成
+                END_REQUEST => {
+                    return match content.get(4) {
+                        Some(0) => Ok(output),
+                        _ => Err(io::Error::other("request rejected by the application")),
+                    };
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn write_record(out: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    out.extend_from_slice(&[VERSION, kind]);
+    out.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    out.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    // 不加 padding
+    out.extend_from_slice(&[0, 0]);
+    out.extend_from_slice(content);
+}
+
+// 流式记录按最大长度切开, 最后跟一个空记录表示结束
+fn write_stream(out: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    for chunk in content.chunks(MAX_CONTENT) {
+        write_record(out, kind, chunk);
+    }
+    write_record(out, kind, &[]);
+}
+
+fn read_record<R: Read + ?Sized>(stream: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 8];
+    stream.read_exact(&mut header)?;
+    if header[0] != VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "unsupported FastCGI version",
+        ));
+    }
+
+    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let padding = header[6] as usize;
+    let mut content = vec![0; length + padding];
+    stream.read_exact(&mut content)?;
+    content.truncate(length);
+
+    Ok((header[1], content))
+}
+
+// 名字和值的长度小于 128 时用 1 个字节, 否则用 4 个字节并把最高位置 1
+fn encode_params(params: &[(String, String)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (name, value) in params {
+        for length in [name.len(), value.len()] {
+            match length {
+                0..=127 => out.push(length as u8),
+                _ => out.extend_from_slice(&(length as u32 | 0x8000_0000).to_be_bytes()),
+            }
+        }
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{self, Limits};
+    use std::os::unix::net::UnixListener;
+    use std::time::Instant;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "web_server_fcgi_{}_{}.sock",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn request(raw: &[u8]) -> Request {
+        http::parse_request(raw, &Limits::default())
+            .unwrap()
+            .unwrap()
+    }
+
+    fn decode_params(mut data: &[u8]) -> Vec<(String, String)> {
+        fn length(data: &mut &[u8]) -> usize {
+            if data[0] < 128 {
+                let length = data[0] as usize;
+                *data = &data[1..];
+                length
+            } else {
+                let length = u32::from_be_bytes([data[0] & 0x7f, data[1], data[2], data[3]]);
+                *data = &data[4..];
+                length as usize
+            }
+        }
+
+        let mut params = Vec::new();
+        while !data.is_empty() {
+            let name_length = length(&mut data);
+            let value_length = length(&mut data);
+            let name = String::from_utf8(data[..name_length].to_vec()).unwrap();
+            let value = String::from_utf8(data[name_length..][..value_length].to_vec()).unwrap();
+            data = &data[name_length + value_length..];
+            params.push((name, value));
+        }
+        params
+    }
+
+    #[test]
+    fn encodes_long_params_with_four_byte_lengths() {
+        let params = vec![
+            ("SHORT".to_string(), "x".to_string()),
+            ("LONG".to_string(), "y".repeat(300)),
+        ];
+
+        let encoded = encode_params(&params);
+
+        assert_eq!(&[5, 1], &encoded[..2]);
+        assert_eq!(decode_params(&encoded), params);
+    }
+
+    #[test]
+    fn talks_to_application_over_unix_socket() {
+        let path = socket_path("app");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // 一个最简单的应用: 把方法, 脚本路径和 body 写回去
+        let application = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (mut params, mut body) = (Vec::new(), Vec::new());
+            loop {
+                let (kind, content) = read_record(&mut stream).unwrap();
+                match kind {
+                    PARAMS => params.extend_from_slice(&content),
+                    STDIN if content.is_empty() => break,
+                    STDIN => body.extend_from_slice(&content),
+                    _ => {}
+                }
+            }
+            let params = decode_params(&params);
+            let param = |name: &str| {
+                params
+                    .iter()
+                    .find(|(key, _)| key == name)
+                    .map_or("", |(_, value)| value.as_str())
+                    .to_string()
+            };
+
+            let output = format!(
+                "Status: 201 Created\r\nContent-Type: text/plain\r\n\r\n{} {} {}",
+                param("REQUEST_METHOD"),
+                param("SCRIPT_FILENAME"),
+                String::from_utf8_lossy(&body)
+            );
+            let mut out = Vec::new();
+            write_stream(&mut out, STDOUT, output.as_bytes());
+            write_record(&mut out, END_REQUEST, &[0; 8]);
+            stream.write_all(&out).unwrap();
+        });
+
+        let fastcgi = FastCgi::new("/app", Address::Unix(path.clone()), "/srv");
+        let request = request(b"POST /app/index.php HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody");
+
+        let response = fastcgi.handle(&request, None).unwrap();
+        application.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!("HTTP/1.1 201 CREATED", response.status_line);
+        assert_eq!(
+            "POST /srv/app/index.php body",
+            String::from_utf8_lossy(&response.body)
+        );
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_root() {
+        // 应用根本不存在, 走到连接这一步就会是 502
+        let fastcgi = FastCgi::new("/app", Address::Unix(socket_path("missing")), "/srv");
+
+        for path in [
+            "/app/../../etc/passwd",
+            "/app/./index.php",
+            "/app//index.php",
+        ] {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", path);
+            let response = fastcgi.handle(&request(raw.as_bytes()), None).unwrap();
+
+            assert_eq!("HTTP/1.1 404 NOT FOUND", response.status_line, "{}", path);
+        }
+    }
+
+    #[test]
+    fn times_out_connecting_to_a_full_unix_socket() {
+        let path = socket_path("full");
+        let _listener = UnixListener::bind(&path).unwrap();
+
+        // 不 accept, 用非阻塞的 connect 把监听队列塞满
+        let mut pending = Vec::new();
+        loop {
+            match mio::net::UnixStream::connect(&path) {
+                Ok(stream) => pending.push(stream),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        let fastcgi = FastCgi::new("/app", Address::Unix(path.clone()), "/srv")
+            .timeout(Duration::from_millis(200));
+        let start = Instant::now();
+        let response = fastcgi
+            .handle(&request(b"GET /app/index.php HTTP/1.1\r\n\r\n"), None)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!("HTTP/1.1 504 GATEWAY TIMEOUT", response.status_line);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
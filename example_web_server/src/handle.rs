@@ -0,0 +1,59 @@
+use std::fmt;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::time::Duration;
+
+/// 通过 `JobHandle` 取结果时可能出现的错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobError {
+    /// 任务执行时 panic 了, 里面是 panic 信息
+    Panicked(String),
+    /// 任务没有产出结果就被丢弃了, 比如线程池在它执行前就没了, 或者结果已经被取走过
+    Canceled,
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Panicked(msg) => write!(f, "job panicked: {}", msg),
+            JobError::Canceled => write!(f, "job was canceled"),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
+
+/// `ThreadPool::spawn` 返回的句柄, 用来拿到任务的返回值
+///
+/// 结果只能取一次, 通过 `try_join` 或 `join_timeout` 取到之后再调用会得到 `JobError::Canceled`.
+pub struct JobHandle<T> {
+    receiver: Receiver<Result<T, JobError>>,
+}
+
+impl<T> JobHandle<T> {
+    pub(crate) fn new(receiver: Receiver<Result<T, JobError>>) -> JobHandle<T> {
+        JobHandle { receiver }
+    }
+
+    /// 阻塞直到任务完成
+    pub fn join(self) -> Result<T, JobError> {
+        self.receiver.recv().unwrap_or(Err(JobError::Canceled))
+    }
+
+    /// 不阻塞, 任务还没完成时返回 None
+    pub fn try_join(&mut self) -> Option<Result<T, JobError>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(JobError::Canceled)),
+        }
+    }
+
+    /// 最多等待 `timeout`, 超时还没完成时返回 None, 之后可以继续等
+    pub fn join_timeout(&mut self, timeout: Duration) -> Option<Result<T, JobError>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => Some(result),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => Some(Err(JobError::Canceled)),
+        }
+    }
+}
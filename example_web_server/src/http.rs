@@ -0,0 +1,526 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+/// 请求的各项上限和每个连接的超时
+///
+/// 超时要配合 socket 上的读写超时(见 `server::set_timeouts`)才有效:
+/// 阻塞的 read 每次最多等 `read_timeout`, 回来之后才有机会检查 `request_timeout`,
+/// 所以一个慢慢发字节的客户端(slowloris)最晚在 `request_timeout + read_timeout` 后被断开.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// 请求行里的路径最长多少字节, 超过了回 414
+    pub max_uri_length: usize,
+    /// 请求行加上所有请求头最多多少字节, 超过了回 431
+    pub max_header_size: usize,
+    /// 最多多少个请求头, 超过了回 431
+    pub max_headers: usize,
+    /// body 最多多少字节, 超过了回 413
+    pub max_body_size: u64,
+    /// 两次读到数据之间最多等多久
+    pub read_timeout: Duration,
+    /// 一次写最多等多久, 客户端一直不收数据时不会永远占着 worker
+    pub write_timeout: Duration,
+    /// 整个请求(包括 body)要在这么长时间内发完, 超过了回 408
+    pub request_timeout: Duration,
+    /// 一个连接上最多处理多少个请求, 1 表示每个响应后都关掉连接
+    pub max_keep_alive_requests: usize,
+    /// 保持的连接在两个请求之间最多等多久, 到时间后直接关掉, 不回 408
+    ///
+    /// 线程池模式下空闲的连接也占着一个 worker, 所以比 `read_timeout` 短得多.
+    pub keep_alive_timeout: Duration,
+    /// 最多同时打开多少个 WebSocket 连接, 满了之后的升级请求回 503
+    ///
+    /// 每个 WebSocket 连接一直占着一个 worker, 要比线程池的 worker 数少, 给普通请求留出 worker.
+    pub max_websockets: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_uri_length: 2048,
+            max_header_size: 8 * 1024,
+            max_headers: 100,
+            max_body_size: 1024 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+            max_keep_alive_requests: 100,
+            keep_alive_timeout: Duration::from_secs(2),
+            max_websockets: 8,
+        }
+    }
+}
+
+/// 读取或者解析请求失败的原因
+#[derive(Debug)]
+pub enum RequestError {
+    /// 连接在发来任何数据之前就关掉了
+    Closed,
+    /// 连接被重置之类的错误, 没法再回响应了
+    Io(io::Error),
+    /// 没在 `request_timeout` 或 `read_timeout` 内发完请求
+    Timeout,
+    Malformed(&'static str),
+    UriTooLong,
+    HeadersTooLarge,
+    BodyTooLarge,
+    /// 比如 chunked 编码的请求体
+    Unsupported(&'static str),
+}
+
+impl RequestError {
+    /// 要回给客户端的错误响应, 连接已经断了时返回 `None`
+    pub fn response(&self) -> Option<Response> {
+        let status_line = match self {
+            RequestError::Closed | RequestError::Io(_) => return None,
+            RequestError::Timeout => "HTTP/1.1 408 REQUEST TIMEOUT",
+            RequestError::Malformed(_) => "HTTP/1.1 400 BAD REQUEST",
+            RequestError::UriTooLong => "HTTP/1.1 414 URI TOO LONG",
+            RequestError::HeadersTooLarge => "HTTP/1.1 431 REQUEST HEADER FIELDS TOO LARGE",
+            RequestError::BodyTooLarge => "HTTP/1.1 413 PAYLOAD TOO LARGE",
+            RequestError::Unsupported(_) => "HTTP/1.1 501 NOT IMPLEMENTED",
+        };
+
+        let mut response = Response::new(status_line, Vec::new());
+        // 请求可能还没读完, 连接上剩下的数据没法再当成下一个请求
+        response.set_header("Connection", "close");
+        Some(response)
+    }
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => RequestError::Timeout,
+            _ => RequestError::Io(e),
+        }
+    }
+}
+
+/// 解析好的请求
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // 原始的请求头和 body, server::respond 和访问日志用
+    raw: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// 去掉查询参数的路径
+    pub fn path(&self) -> &str {
+        self.target.split(['?', '#']).next().unwrap_or_default()
+    }
+
+    /// 原始的请求, 包括请求头和 body
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// 客户端是否希望响应后保持连接
+    ///
+    /// HTTP/1.1 默认保持, 除非带了 `Connection: close`; HTTP/1.0 要带 `Connection: keep-alive`.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("Connection").unwrap_or_default();
+        let has = |option: &str| {
+            connection
+                .split(',')
+                .any(|value| value.trim().eq_ignore_ascii_case(option))
+        };
+
+        match self.version.as_str() {
+            "HTTP/1.0" => has("keep-alive"),
+            _ => !has("close"),
+        }
+    }
+
+    /// `Content-Length` 的值, 没有这个头时是 0
+    pub(crate) fn content_length(&self) -> Result<u64, RequestError> {
+        match self.header("Content-Length") {
+            Some(length) => length
+                .parse()
+                .map_err(|_| RequestError::Malformed("invalid Content-Length")),
+            None => Ok(0),
+        }
+    }
+}
+
+/// 解析 `buffer` 开头的请求头, 还没读到空行时返回 `Ok(None)`
+///
+/// 没读完也会检查已经读到的部分, 超长的路径和请求头不用等读完就能拒绝.
+/// 成功时返回不带 body 的请求和请求头的长度(包括空行).
+pub fn parse_head(
+    buffer: &[u8],
+    limits: &Limits,
+) -> Result<Option<(Request, usize)>, RequestError> {
+    let end = buffer
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|end| end + 4);
+    let head = &buffer[..end.unwrap_or(buffer.len())];
+
+    // 路径在请求行的第二段, 请求行还没读完时按已经读到的长度算
+    let request_line = head.split(|&byte| byte == b'\n').next().unwrap_or_default();
+    let target = request_line
+        .split(|&byte| byte == b' ')
+        .nth(1)
+        .unwrap_or_default();
+    if target.len() > limits.max_uri_length {
+        return Err(RequestError::UriTooLong);
+    }
+    if head.len() > limits.max_header_size {
+        return Err(RequestError::HeadersTooLarge);
+    }
+    // 减去请求行和结尾的空行
+    let lines = head.windows(2).filter(|window| window == b"\r\n").count();
+    if lines.saturating_sub(if end.is_some() { 2 } else { 1 }) > limits.max_headers {
+        return Err(RequestError::HeadersTooLarge);
+    }
+
+    let Some(end) = end else {
+        return Ok(None);
+    };
+
+    let head = std::str::from_utf8(&buffer[..end])
+        .map_err(|_| RequestError::Malformed("request head is not valid UTF-8"))?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target), Some(version), None) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        return Err(RequestError::Malformed("invalid request line"));
+    };
+    if method.is_empty() || !target.starts_with('/') && target != "*" {
+        return Err(RequestError::Malformed("invalid request line"));
+    }
+    if !version.starts_with("HTTP/1.") {
+        return Err(RequestError::Malformed("unsupported HTTP version"));
+    }
+
+    let mut headers = Vec::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(RequestError::Malformed("header without a colon"))?;
+        // 请求头的名字里不能有空白, 否则前后的代理可能会理解成不同的请求
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(RequestError::Malformed("invalid header name"));
+        }
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+
+    let request = Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        version: version.to_string(),
+        headers,
+        body: Vec::new(),
+        raw: buffer[..end].to_vec(),
+    };
+
+    Ok(Some((request, end)))
+}
+
+/// 解析 `buffer` 开头的完整请求, 请求头或者 body 还没读完时返回 `Ok(None)`
+///
+/// body 只支持 `Content-Length`, 超过 `max_body_size` 的在读 body 之前就会被拒绝.
+pub fn parse_request(buffer: &[u8], limits: &Limits) -> Result<Option<Request>, RequestError> {
+    let Some((mut request, head_len)) = parse_head(buffer, limits)? else {
+        return Ok(None);
+    };
+
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(RequestError::Unsupported(
+            "Transfer-Encoding is not supported",
+        ));
+    }
+    let length = request.content_length()?;
+    if length > limits.max_body_size {
+        return Err(RequestError::BodyTooLarge);
+    }
+
+    let end = head_len + length as usize;
+    if buffer.len() < end {
+        return Ok(None);
+    }
+
+    request.body = buffer[head_len..end].to_vec();
+    request.raw = buffer[..end].to_vec();
+    Ok(Some(request))
+}
+
+/// 从阻塞的 `stream` 读一个完整的请求
+///
+/// 整个请求要在 `limits.request_timeout` 内读完, 单次 read 的超时由 socket 自己控制.
+pub fn read_request<R: Read>(stream: &mut R, limits: &Limits) -> Result<Request, RequestError> {
+    read_next_request(stream, &mut Vec::new(), limits)
+}
+
+/// 和 `read_request` 一样, 但读多了的数据留在 `buffer` 里, 给同一个连接上的下一个请求用
+///
+/// 客户端可以不等响应就连着发几个请求(pipelining), 它们可能被一次读进来.
+pub fn read_next_request<R: Read>(
+    stream: &mut R,
+    buffer: &mut Vec<u8>,
+    limits: &Limits,
+) -> Result<Request, RequestError> {
+    let started = Instant::now();
+    let mut chunk = [0; 1024];
+
+    loop {
+        if let Some(request) = parse_request(buffer, limits)? {
+            buffer.drain(..request.raw.len());
+            return Ok(request);
+        }
+        if started.elapsed() > limits.request_timeout {
+            return Err(RequestError::Timeout);
+        }
+
+        match stream.read(&mut chunk) {
+            Ok(0) if buffer.is_empty() => return Err(RequestError::Closed),
+            Ok(0) => return Err(RequestError::Malformed("connection closed mid-request")),
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// 从原始请求文本中取出某个请求头的值, 请求头名大小写不敏感
+pub fn header_value<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request
+        .split("\r\n")
+        .skip(1) // 第一行是请求行
+        .take_while(|line| !line.is_empty()) // 空行之后是 body
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// 一个待写回客户端的 HTTP 响应
+///
+/// `Content-Length` 由 `write_to` 根据 body 自动补上, 不需要手动设置.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status_line: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status_line: &str, body: Vec<u8>) -> Response {
+        Response {
+            status_line: status_line.to_string(),
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    /// 状态行里的状态码, 比如 "HTTP/1.1 404 NOT FOUND" 里的 404
+    pub fn status_code(&self) -> u16 {
+        self.status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .unwrap_or_default()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// 设置响应头, 同名的会被覆盖
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        match self
+            .headers
+            .iter_mut()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        {
+            Some((_, old)) => *old = value.to_string(),
+            None => self.headers.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        let mut head = format!(
+            "{}\r\nContent-Length: {}\r\n",
+            self.status_line,
+            self.body.len()
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+
+        // 和之前的 stream.write 不同, write_all 会一直写到所有字节都写完
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_header_case_insensitively() {
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\naccept-encoding: gzip, br\r\n\r\n";
+
+        assert_eq!(Some("gzip, br"), header_value(request, "Accept-Encoding"));
+        assert_eq!(None, header_value(request, "User-Agent"));
+    }
+
+    fn small_limits() -> Limits {
+        Limits {
+            max_uri_length: 16,
+            max_header_size: 128,
+            max_headers: 2,
+            max_body_size: 4,
+            request_timeout: Duration::from_millis(50),
+            ..Limits::default()
+        }
+    }
+
+    #[test]
+    fn parses_request_and_waits_for_the_rest() {
+        let limits = small_limits();
+
+        assert!(parse_request(b"GET / HTTP/1.1\r\nHost: a\r\n", &limits)
+            .unwrap()
+            .is_none());
+        assert!(
+            parse_request(b"POST /p HTTP/1.1\r\nContent-Length: 3\r\n\r\nab", &limits)
+                .unwrap()
+                .is_none()
+        );
+
+        let request = parse_request(
+            b"POST /p?x=1 HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc",
+            &limits,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!("POST", request.method);
+        assert_eq!("/p", request.path());
+        assert_eq!(Some("3"), request.header("content-length"));
+        assert_eq!(b"abc", &request.body[..]);
+    }
+
+    #[test]
+    fn rejects_requests_over_the_limits() {
+        let limits = small_limits();
+        let status = |request: &[u8]| {
+            parse_request(request, &limits)
+                .unwrap_err()
+                .response()
+                .unwrap()
+                .status_code()
+        };
+
+        // 请求还没读完就能判断出来
+        assert_eq!(414, status(b"GET /aaaaaaaaaaaaaaaaaaaa"));
+        assert_eq!(431, status(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n"));
+        assert_eq!(431, status(&[b'x'; 129]));
+        assert_eq!(413, status(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n"));
+        assert_eq!(
+            501,
+            status(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n")
+        );
+        assert_eq!(400, status(b"GET / HTTP/1.1 extra\r\n\r\n"));
+        assert_eq!(400, status(b"GET / HTTP/1.1\r\nBad Header: 1\r\n\r\n"));
+        assert_eq!(400, status(b"GET / SPDY/3\r\n\r\n"));
+    }
+
+    // 每次只给一个字节, 模拟 slowloris
+    struct Trickle(&'static [u8]);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            std::thread::sleep(Duration::from_millis(10));
+            let Some((&first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn cuts_off_slow_and_broken_clients() {
+        let limits = small_limits();
+
+        let mut slow = Trickle(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(matches!(
+            read_request(&mut slow, &limits),
+            Err(RequestError::Timeout)
+        ));
+
+        let mut reset = io::Cursor::new(Vec::new()).chain(ResetReader);
+        let error = read_request(&mut reset, &limits).unwrap_err();
+        assert!(matches!(error, RequestError::Io(_)));
+        assert!(error.response().is_none());
+    }
+
+    struct ResetReader;
+
+    impl Read for ResetReader {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(ErrorKind::ConnectionReset.into())
+        }
+    }
+
+    #[test]
+    fn keeps_pipelined_requests_for_the_next_read() {
+        let limits = Limits::default();
+        let mut stream = io::Cursor::new(
+            b"POST /a HTTP/1.1\r\nContent-Length: 2\r\n\r\nhiGET /b HTTP/1.0\r\n\r\n".to_vec(),
+        );
+        let mut buffer = Vec::new();
+
+        let first = read_next_request(&mut stream, &mut buffer, &limits).unwrap();
+        assert_eq!(("/a", &b"hi"[..]), (first.path(), &first.body[..]));
+        assert!(first.keep_alive());
+
+        let second = read_next_request(&mut stream, &mut buffer, &limits).unwrap();
+        assert_eq!("/b", second.path());
+        assert!(!second.keep_alive());
+        assert!(buffer.is_empty());
+        assert!(matches!(
+            read_next_request(&mut stream, &mut buffer, &limits),
+            Err(RequestError::Closed)
+        ));
+    }
+
+    #[test]
+    fn writes_content_length_and_headers() {
+        let mut response = Response::new("HTTP/1.1 200 OK", b"hi".to_vec());
+        response.set_header("Content-Type", "text/plain");
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nContent-Type: text/plain\r\n\r\nhi",
+            String::from_utf8(out).unwrap()
+        );
+    }
+}
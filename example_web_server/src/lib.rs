@@ -1,5 +1,46 @@
-use std::sync::{mpsc, Arc, Mutex};
+pub mod access_log;
+pub mod builder;
+pub mod cgi;
+pub mod compression;
+pub mod config;
+mod dispatch;
+pub mod event_loop;
+pub mod fastcgi;
+pub mod handle;
+pub mod http;
+mod metrics;
+pub mod observer;
+pub mod proxy;
+mod queue;
+pub mod scope;
+pub mod server;
+pub mod shutdown;
+pub mod site;
+mod timer;
+pub mod tls;
+pub mod websocket;
+
+use std::any::Any;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
+
+pub use builder::ThreadPoolBuilder;
+pub use dispatch::Priority;
+pub use handle::{JobError, JobHandle};
+pub use metrics::{HistogramSnapshot, PoolStats};
+pub use observer::{Observer, PoolEvent, StopReason};
+pub use queue::{ExecuteError, RejectionPolicy};
+pub use scope::Scope;
+pub use timer::ScheduledHandle;
+
+use dispatch::Dispatcher;
+use metrics::Metrics;
+use queue::{JobQueue, Pushed};
+use timer::Timer;
 
 #[derive(Debug)]
 pub struct PoolCreationError {
@@ -8,8 +49,7 @@ pub struct PoolCreationError {
 }
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    shared: Arc<Shared>,
 }
 
 pub enum Message {
@@ -20,100 +60,473 @@ pub enum Message {
 // Job 将是一个有着 execute 接收到的闭包类型的 trait 对象的类型别名
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+// 任务 panic 时的回调, 参数是 worker id 和 panic 信息
+type PanicHandler = Arc<dyn Fn(usize, &str) + Send + Sync + 'static>;
+
 pub struct Worker {
     id: usize,
-    thread: Option<thread::JoinHandle<()>>,
+    // worker 线程挂掉后会被重新拉起, 新线程的 JoinHandle 要放回同一个位置, 所以需要共享
+    thread: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+}
+
+// 线程池和所有 worker 线程共享的状态, worker 线程自己退休或者被重新拉起时都要用到
+struct Shared {
+    queue: JobQueue,
+    dispatcher: Arc<Dispatcher>,
+    panic_handler: RwLock<PanicHandler>,
+    workers: Mutex<Vec<Worker>>,
+    min_size: usize,
+    max_size: usize,
+    // 多于 min_size 的 worker 空闲超过这个时间就会退出
+    keep_alive: Duration,
+    next_id: AtomicUsize,
+    shutting_down: AtomicBool,
+    timer: Timer,
+    observer: Arc<dyn Observer>,
+    metrics: Metrics,
+}
+
+/// `ThreadPool::shutdown` 的结果
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+    /// 在期限内处理完手头任务并退出的 worker id
+    pub finished: Vec<usize>,
+    /// 超过期限还没退出的 worker id, 这些线程不会再被 join
+    pub timed_out: Vec<usize>,
 }
 
 impl ThreadPool {
     /// Create a new ThreadPool.
     ///
     /// The size is the number of workers in the pool.
+    /// The job queue is unbounded; use `ThreadPool::builder` to limit it.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// The `new` function will panic if the size is zero.
+    /// The `new` function will return an error if the size is zero.
     pub fn new(size: usize) -> Result<ThreadPool, PoolCreationError> {
-        let mut workers = Vec::with_capacity(size);
+        ThreadPool::builder().size(size).build()
+    }
 
-        let (sender, receiver) = mpsc::channel();
+    /// Create a builder for configuring the pool size, queue capacity and rejection policy.
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
 
-        let receiver = Arc::new(Mutex::new(receiver));
+    fn start(
+        min_size: usize,
+        max_size: usize,
+        keep_alive: Duration,
+        queue: JobQueue,
+        dispatcher: Dispatcher,
+        observer: Arc<dyn Observer>,
+    ) -> ThreadPool {
+        // 队列要在多个 worker 线程之间共享, 而且取任务会修改队列
+        // 为了在多个线程间共享所有权, 这里用 Arc, 队列内部的工作窃取保证同一个任务只会被一个线程取到
+        let shared = Arc::new(Shared {
+            queue,
+            dispatcher: Arc::new(dispatcher),
+            panic_handler: RwLock::new(Arc::new(|id, msg: &str| {
+                eprintln!("Worker {} panicked while executing a job: {}", id, msg);
+            })),
+            workers: Mutex::new(Vec::with_capacity(max_size)),
+            min_size,
+            max_size,
+            keep_alive,
+            next_id: AtomicUsize::new(min_size),
+            shutting_down: AtomicBool::new(false),
+            timer: Timer::default(),
+            observer,
+            metrics: Metrics::new(),
+        });
 
-        for i in 0..size {
-            // 这里要将 receiver 传递到多个 Worker 实例中, 但这样是不行的
-            // 因为 Rust 所提供的通道实现是多生产者, 单消费者
-            // 另外从通道队列中取出任务涉及到修改 receiver, 所以这些线程需要一个能安全的共享和修改 receiver 的方式, 否则可能导致竞争状态
-            // 为了在多个线程间共享所有权并允许线程修改其值, 需要使用 Arc<Mutex<T>>
-            workers.push(Worker::new(i, Arc::clone(&receiver)))
+        {
+            let mut workers = lock(&shared.workers);
+            for i in 0..min_size {
+                workers.push(Worker::new(i, Arc::clone(&shared)));
+            }
         }
 
-        match size > 0 {
-            true => Ok(ThreadPool { workers, sender }),
-            false => Err(PoolCreationError {
-                error_msg: "`size` should more than 0".to_string(),
-                code: -1,
-            }),
-        }
+        ThreadPool { shared }
     }
 
+    /// Submit a job to the pool with `Priority::Normal`.
+    ///
+    /// If the queue is full the configured `RejectionPolicy` applies;
+    /// a rejected job is silently dropped and counted in `rejected_count`.
+    /// Use `try_execute` to find out whether the job was accepted.
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
+        let _ = self.try_execute(f);
+    }
+
+    /// Submit a job to the pool, returning an error if it was rejected.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.submit(Box::new(f))
+    }
+
+    /// Number of jobs waiting in the queue.
+    pub fn queue_depth(&self) -> usize {
+        self.shared.queue.len()
+    }
+
+    /// Number of jobs rejected or dropped because the queue was full.
+    pub fn rejected_count(&self) -> usize {
+        self.shared.queue.rejected()
+    }
+
+    /// Number of live workers, between the configured minimum and maximum size.
+    pub fn current_size(&self) -> usize {
+        lock(&self.shared.workers).len()
+    }
+
+    /// Take a snapshot of the pool's counters and latency histograms.
+    pub fn stats(&self) -> PoolStats {
+        let metrics = &self.shared.metrics;
+
+        PoolStats {
+            queued: self.queue_depth(),
+            workers: self.current_size(),
+            active_workers: metrics.active.load(Ordering::SeqCst),
+            completed: metrics.completed.load(Ordering::SeqCst),
+            panicked: metrics.panicked.load(Ordering::SeqCst),
+            rejected: self.rejected_count(),
+            queue_wait: metrics.queue_wait.snapshot(),
+            execution: metrics.execution.snapshot(),
+        }
+    }
+
+    /// Run `f` on the pool and return a handle to its result.
+    ///
+    /// A panic inside `f` is returned from the handle as `JobError::Panicked`
+    /// instead of going to the `on_panic` hook.
+    ///
+    /// ```
+    /// let pool = web_server::ThreadPool::new(4).unwrap();
+    ///
+    /// let handles: Vec<_> = (1..=10u64).map(|n| pool.spawn(move || n * n)).collect();
+    /// let sum: u64 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    ///
+    /// assert_eq!(385, sum);
+    /// ```
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+
+        self.execute(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f))
+                .map_err(|payload| JobError::Panicked(panic_message(&*payload)));
+
+            // 调用方可能已经把 handle 丢掉了, 这时结果没人要, 发送失败也无所谓
+            let _ = sender.send(result);
+        });
+
+        JobHandle::new(receiver)
+    }
 
-        self.sender.send(Message::NewJob(job)).unwrap();
+    /// Set the hook that is called when a job panics.
+    ///
+    /// The panic is caught, so the worker keeps running and picks up the next job.
+    /// By default the panic is printed to stderr.
+    pub fn on_panic<F>(&self, handler: F)
+    where
+        F: Fn(usize, &str) + Send + Sync + 'static,
+    {
+        *self
+            .shared
+            .panic_handler
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Arc::new(handler);
+    }
+
+    /// Gracefully shut down the pool, waiting at most `timeout` for the workers.
+    ///
+    /// Jobs that are already queued still run before the workers exit.
+    /// Workers that miss the deadline are reported by id in `ShutdownReport::timed_out`.
+    pub fn shutdown(self, timeout: Duration) -> ShutdownReport {
+        let workers = self.terminate_workers();
+
+        let deadline = Instant::now() + timeout;
+        let mut report = ShutdownReport::default();
+
+        for worker in &workers {
+            let thread = match lock(&worker.thread).take() {
+                Some(thread) => thread,
+                None => continue,
+            };
+
+            // JoinHandle 没有带超时的 join, 只能轮询 is_finished 直到截止时间
+            while !thread.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            if thread.is_finished() {
+                let _ = thread.join();
+                report.finished.push(worker.id);
+            } else {
+                // 丢掉 JoinHandle 只是让线程脱离, 并不会杀掉它
+                report.timed_out.push(worker.id);
+            }
+        }
+
+        report
+    }
+
+    // 把 worker 从共享状态里取出来交给调用方 join, 之后不会再扩容, 也不会有 worker 退休
+    // Terminate 排在已有任务后面, 所以 worker 会先把队列里的任务做完再退出
+    fn terminate_workers(&self) -> Vec<Worker> {
+        // 还没到期的定时任务不再执行
+        self.shared.timer.shutdown();
+
+        let workers = {
+            let mut workers = lock(&self.shared.workers);
+            self.shared.shutting_down.store(true, Ordering::SeqCst);
+            mem::take(&mut *workers)
+        };
+
+        self.shared.observer.on_event(PoolEvent::ShutdownStarted {
+            workers: workers.len(),
+        });
+
+        for _ in &workers {
+            self.shared.queue.push_terminate();
+        }
+
+        workers
+    }
+}
+
+impl Shared {
+    fn submit(self: &Arc<Self>, job: Job) -> Result<(), ExecuteError> {
+        // 没用过优先级和具名队列时不经过调度器, 省掉它的那把全局锁
+        if self.dispatcher.engaged() {
+            return self.submit_to(self.dispatcher.lane(Priority::Normal), job);
+        }
+
+        self.grow_if_needed();
+        self.pushed(self.queue.push(job))
+    }
+
+    fn submit_to(self: &Arc<Self>, lane: usize, job: Job) -> Result<(), ExecuteError> {
+        // 先看要不要扩容, 这样 Block 策略下也能先多开几个 worker 消化队列, 而不是直接阻塞
+        self.grow_if_needed();
+
+        // 底层队列里放的是令牌, 容量限制和拒绝策略都作用在令牌上
+        let token = self.dispatcher.enqueue(lane, job);
+        let result = token.submit(|job| self.queue.push(job));
+
+        self.pushed(result)
+    }
+
+    fn pushed(&self, result: Result<Pushed, ExecuteError>) -> Result<(), ExecuteError> {
+        if let Ok(Pushed::DroppedOldest) | Err(_) = result {
+            self.observer.on_event(PoolEvent::JobRejected);
+        }
+
+        result.map(|_| ())
+    }
+
+    // 队列里等着的任务比空闲的 worker 多, 而且还没到上限时, 多开一个 worker
+    fn grow_if_needed(self: &Arc<Self>) {
+        if self.max_size == self.min_size || self.queue.len() < self.queue.idle() {
+            return;
+        }
+
+        let mut workers = lock(&self.workers);
+
+        if workers.len() < self.max_size && !self.shutting_down.load(Ordering::SeqCst) {
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+
+            workers.push(Worker::new(id, Arc::clone(self)));
+        }
+    }
+
+    // 空闲太久的 worker 在数量多于 min_size 时把自己从池里移除, 返回是否可以退出
+    fn retire(&self, id: usize) -> bool {
+        let mut workers = lock(&self.workers);
+
+        if workers.len() <= self.min_size || self.shutting_down.load(Ordering::SeqCst) {
+            return false;
+        }
+
+        // 自己的 JoinHandle 随之被丢掉, 线程变成脱离状态, 退出后资源自动回收
+        workers.retain(|worker| worker.id != id);
+
+        true
     }
 }
 
 impl Worker {
-    pub fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
+    fn new(id: usize, shared: Arc<Shared>) -> Worker {
+        let thread = Arc::new(Mutex::new(None));
 
-            match message {
-                Message::NewJob(job) => {
-                    println!("Worker {} got a job; executing.", id);
+        Worker::spawn(id, shared, Arc::clone(&thread));
 
-                    job();
-                }
-                Message::Terminate => {
-                    println!("Worker {} was told to terminate.", id);
+        Worker { id, thread }
+    }
+
+    fn spawn(id: usize, shared: Arc<Shared>, slot: Arc<Mutex<Option<thread::JoinHandle<()>>>>) {
+        // 先拿住 slot 的锁再创建线程, 避免新线程挂掉后拉起的替代线程的 JoinHandle 被这里覆盖掉
+        let mut guard = lock(&slot);
+
+        let sentinel = Sentinel {
+            id,
+            shared: Arc::clone(&shared),
+            slot: Arc::clone(&slot),
+        };
+
+        *guard = Some(thread::spawn(move || {
+            // sentinel 跟随线程的生命周期, 线程因为 panic 退出时会在 drop 中重新拉起一个 worker
+            let _sentinel = sentinel;
+
+            shared.queue.attach(id);
+            shared
+                .observer
+                .on_event(PoolEvent::WorkerStarted { worker_id: id });
 
-                    break;
+            let reason = Worker::run(id, &shared);
+            shared.observer.on_event(PoolEvent::WorkerStopped {
+                worker_id: id,
+                reason,
+            });
+        }));
+    }
+
+    fn run(id: usize, shared: &Shared) -> StopReason {
+        let elastic = shared.max_size > shared.min_size;
+
+        loop {
+            let queued = if elastic {
+                match shared.queue.pop_timeout(shared.keep_alive) {
+                    Some(queued) => queued,
+                    // 空闲太久了
+                    None if shared.retire(id) => return StopReason::Retired,
+                    None => continue,
                 }
+            } else {
+                shared.queue.pop()
+            };
+
+            match queued.message {
+                Message::NewJob(job) => Worker::execute(id, shared, job, queued.queued_at),
+                Message::Terminate => return StopReason::Terminated,
             }
+        }
+    }
+
+    fn execute(id: usize, shared: &Shared, job: Job, queued_at: Instant) {
+        let metrics = &shared.metrics;
+        let started = Instant::now();
+        let queue_wait = started - queued_at;
+
+        metrics.queue_wait.record(queue_wait);
+        metrics.active.fetch_add(1, Ordering::SeqCst);
+        shared.observer.on_event(PoolEvent::JobStarted {
+            worker_id: id,
+            queue_wait,
         });
-        Worker {
-            id,
-            thread: Some(thread),
+
+        // 在任务边界捕获 panic, 一个坏请求不会再带走整个 worker 线程
+        let result = panic::catch_unwind(AssertUnwindSafe(job));
+
+        let elapsed = started.elapsed();
+        metrics.execution.record(elapsed);
+        metrics.active.fetch_sub(1, Ordering::SeqCst);
+        metrics.completed.fetch_add(1, Ordering::SeqCst);
+
+        if let Err(payload) = result {
+            let message = panic_message(&*payload);
+            metrics.panicked.fetch_add(1, Ordering::SeqCst);
+            shared.observer.on_event(PoolEvent::JobPanicked {
+                worker_id: id,
+                message: &message,
+            });
+
+            let handler = Arc::clone(
+                &shared
+                    .panic_handler
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner),
+            );
+            handler(id, &message);
         }
+
+        shared.observer.on_event(PoolEvent::JobFinished {
+            worker_id: id,
+            elapsed,
+        });
     }
 }
 
-impl Drop for ThreadPool {
-    // 当线程池被丢弃时, 应该 join 所有线程以确保他们完成其操作
+// 当 worker 线程意外退出(比如 panic hook 自己又 panic 了)时负责补上一个新的线程, 保证线程池大小不变
+struct Sentinel {
+    id: usize,
+    shared: Arc<Shared>,
+    slot: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+}
+
+impl Drop for Sentinel {
     fn drop(&mut self) {
-        println!("Sending terminate message to all workers.");
+        // 不管是正常退出还是 panic, 本地队列里没做完的任务都要还回去
+        self.shared.queue.detach(self.id);
+
+        if thread::panicking() {
+            self.shared.observer.on_event(PoolEvent::WorkerStopped {
+                worker_id: self.id,
+                reason: StopReason::Died,
+            });
 
-        for _ in &self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+            Worker::spawn(self.id, Arc::clone(&self.shared), Arc::clone(&self.slot));
         }
+    }
+}
+
+// 某个线程持有锁时 panic 会导致 Mutex 中毒, 之后所有 worker 的 lock().unwrap() 都会 panic
+// 池里被锁保护的数据并不会因此处于不一致的状态, 所以直接取出里面的值继续用
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
 
-        println!("Shutting down all workers.");
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    // panic!("...") 的 payload 是 &str, panic!("{}", x) 的是 String
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+impl Drop for ThreadPool {
+    // 当线程池被丢弃时, 应该 join 所有线程以确保他们完成其操作
+    fn drop(&mut self) {
+        // 已经通过 shutdown 关闭过的线程池, 这里没什么要做的了
+        if self.shared.shutting_down.load(Ordering::SeqCst) {
+            return;
+        }
 
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+        let workers = self.terminate_workers();
 
+        for worker in &workers {
             // 我们不能调用 join, 因为每个 worker 只有一个可变借用, 而 join 会获取它所有的参数的所有权
             // 因此需要将 thread 移动出拥有其所有权的 Worker 实例以便 join 可以消费这个线程
             // worker.thread.join().unwrap();
 
             // 可以将 thread 变成可选的
             // 如果 worker 的线程已然是 None, 就知道此时这个 worker 已经清理了其线程所以无需做任何操作
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+            let thread = lock(&worker.thread).take();
+            if let Some(thread) = thread {
+                // 线程是 panic 退出的话 join 会返回 Err, drop 里再 panic 会直接 abort, 所以忽略它
+                let _ = thread.join();
             }
         }
     }
@@ -123,5 +536,259 @@ impl Drop for ThreadPool {
 // 为 ThreadPool 和其公有方法增加更多文档
 // 为库的功能增加测试
 // 将 unwrap 调用改为更健壮的错误处理
-// 使用 ThreadPool 进行其他不同于处理网络请求的任务
 // 在 crates.io 上寻找一个线程池 crate 并使用它实现一个类似的 web server, 将其 API 和鲁棒性与我们的实现做对比
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shutdown_reports_workers_missing_the_deadline() {
+        let pool = ThreadPool::new(2).unwrap();
+
+        pool.execute(|| thread::sleep(Duration::from_secs(2)));
+        // 给 worker 一点时间取走这个慢任务
+        thread::sleep(Duration::from_millis(100));
+
+        let report = pool.shutdown(Duration::from_millis(200));
+
+        assert_eq!(1, report.finished.len());
+        assert_eq!(1, report.timed_out.len());
+    }
+
+    #[test]
+    fn survives_panicking_jobs() {
+        let pool = ThreadPool::new(1).unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        let panics = tx.clone();
+        pool.on_panic(move |id, msg| panics.send(format!("{}: {}", id, msg)).unwrap());
+
+        pool.execute(|| panic!("bad request"));
+        pool.execute(move || tx.send("next job".to_string()).unwrap());
+
+        assert_eq!("0: bad request", rx.recv().unwrap());
+        assert_eq!("next job", rx.recv().unwrap());
+    }
+
+    #[test]
+    fn spawn_returns_results_and_panics() {
+        let pool = ThreadPool::new(2).unwrap();
+
+        let ok = pool.spawn(|| 40 + 2);
+        let bad = pool.spawn(|| -> i32 { panic!("division by zero") });
+
+        assert_eq!(Ok(42), ok.join());
+        assert_eq!(
+            Err(JobError::Panicked("division by zero".to_string())),
+            bad.join()
+        );
+    }
+
+    #[test]
+    fn join_timeout_waits_for_slow_jobs() {
+        let pool = ThreadPool::new(1).unwrap();
+
+        let mut handle = pool.spawn(|| {
+            thread::sleep(Duration::from_millis(200));
+            "done"
+        });
+
+        assert_eq!(None, handle.try_join());
+        assert_eq!(None, handle.join_timeout(Duration::from_millis(10)));
+        assert_eq!(
+            Some(Ok("done")),
+            handle.join_timeout(Duration::from_secs(1))
+        );
+    }
+
+    // 让唯一的 worker 卡住, 返回用来放行它的 sender
+    fn block_worker(pool: &ThreadPool) -> mpsc::Sender<()> {
+        let (release, blocked) = mpsc::channel::<()>();
+        let (started, wait_started) = mpsc::channel();
+
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = blocked.recv();
+        });
+        wait_started.recv().unwrap();
+
+        release
+    }
+
+    #[test]
+    fn rejects_jobs_when_queue_is_full() {
+        let pool = ThreadPool::builder()
+            .size(1)
+            .queue_capacity(1)
+            .rejection_policy(RejectionPolicy::Reject)
+            .build()
+            .unwrap();
+        let release = block_worker(&pool);
+
+        assert_eq!(Ok(()), pool.try_execute(|| {}));
+        assert_eq!(Err(ExecuteError::QueueFull), pool.try_execute(|| {}));
+        assert_eq!(1, pool.queue_depth());
+        assert_eq!(1, pool.rejected_count());
+
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn drops_oldest_job_when_queue_is_full() {
+        let pool = ThreadPool::builder()
+            .size(1)
+            .queue_capacity(1)
+            .rejection_policy(RejectionPolicy::DropOldest)
+            .build()
+            .unwrap();
+        let release = block_worker(&pool);
+
+        let oldest = pool.spawn(|| "oldest");
+        let newest = pool.spawn(|| "newest");
+        release.send(()).unwrap();
+
+        assert_eq!(Err(JobError::Canceled), oldest.join());
+        assert_eq!(Ok("newest"), newest.join());
+        assert_eq!(1, pool.rejected_count());
+    }
+
+    #[test]
+    fn runs_on_caller_thread_when_queue_is_full() {
+        let pool = ThreadPool::builder()
+            .size(1)
+            .queue_capacity(1)
+            .rejection_policy(RejectionPolicy::CallerRuns)
+            .build()
+            .unwrap();
+        let release = block_worker(&pool);
+
+        pool.execute(|| {});
+        let caller = thread::current().id();
+        let handle = pool.spawn(move || thread::current().id() == caller);
+        release.send(()).unwrap();
+
+        assert_eq!(Ok(true), handle.join());
+    }
+
+    #[test]
+    fn grows_under_load_and_reaps_idle_workers() {
+        let pool = ThreadPool::builder()
+            .min_size(1)
+            .max_size(3)
+            .keep_alive(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = Arc::new(Mutex::new(blocked));
+
+        for _ in 0..3 {
+            let blocked = Arc::clone(&blocked);
+            pool.execute(move || {
+                let _ = blocked.lock().unwrap().recv();
+            });
+        }
+        assert_eq!(3, pool.current_size());
+
+        for _ in 0..3 {
+            release.send(()).unwrap();
+        }
+        thread::sleep(Duration::from_millis(500));
+
+        assert_eq!(1, pool.current_size());
+    }
+
+    #[test]
+    fn rejects_min_size_above_max_size() {
+        let result = ThreadPool::builder().min_size(4).max_size(2).build();
+
+        assert_eq!(-3, result.err().unwrap().code);
+    }
+
+    #[test]
+    fn respawns_dead_workers() {
+        let pool = ThreadPool::new(1).unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        // panic hook 里再 panic 会越过 catch_unwind, 直接带走 worker 线程
+        pool.on_panic(|_, _| panic!("hook failed"));
+        pool.execute(|| panic!("bad request"));
+        pool.execute(move || tx.send(()).unwrap());
+
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn stats_count_completed_and_panicked_jobs() {
+        let pool = ThreadPool::new(2).unwrap();
+        pool.on_panic(|_, _| {});
+
+        let handles: Vec<_> = (0..5).map(|n| pool.spawn(move || n)).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let failed = pool.spawn(|| panic!("bad request"));
+        assert!(failed.join().is_err());
+        pool.execute(|| panic!("bad request"));
+
+        // execute 的任务没有句柄, 等计数追上来
+        let start = Instant::now();
+        while pool.stats().completed < 7 && start.elapsed() < Duration::from_secs(1) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let stats = pool.stats();
+        assert_eq!(7, stats.completed);
+        // spawn 自己捕获了 panic, 线程池只看到 execute 的那一个
+        assert_eq!(1, stats.panicked);
+        assert_eq!(2, stats.workers);
+        assert_eq!(7, stats.queue_wait.count);
+        assert_eq!(7, stats.execution.count);
+    }
+
+    #[test]
+    fn observer_receives_pool_events() {
+        #[derive(Default)]
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        impl Observer for Recorder {
+            fn on_event(&self, event: PoolEvent<'_>) {
+                let name = match event {
+                    PoolEvent::WorkerStarted { .. } => "started".to_string(),
+                    PoolEvent::WorkerStopped { reason, .. } => format!("stopped {:?}", reason),
+                    PoolEvent::JobStarted { .. } => "job".to_string(),
+                    PoolEvent::JobPanicked { message, .. } => format!("panicked {}", message),
+                    PoolEvent::ShutdownStarted { workers } => format!("shutdown {}", workers),
+                    _ => return,
+                };
+                lock(&self.0).push(name);
+            }
+        }
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let pool = ThreadPool::builder()
+            .size(1)
+            .observer(Recorder(Arc::clone(&events)))
+            .build()
+            .unwrap();
+        pool.on_panic(|_, _| {});
+
+        let (tx, rx) = mpsc::channel();
+        pool.execute(|| panic!("bad request"));
+        pool.execute(move || tx.send(()).unwrap());
+        rx.recv().unwrap();
+        pool.shutdown(Duration::from_secs(1));
+
+        assert_eq!(
+            vec![
+                "started",
+                "job",
+                "panicked bad request",
+                "job",
+                "shutdown 1",
+                "stopped Terminated"
+            ],
+            *lock(&events)
+        );
+    }
+}
@@ -1,104 +1,364 @@
-use std::io::prelude::*; // 用于获取读写流所需的特定 trait
-use std::net::{TcpListener, TcpStream};
+use rustls::ServerConfig;
+use std::env;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
-use std::{fs, thread};
-use web_server::{PoolCreationError, ThreadPool};
+use web_server::access_log::{self, AccessLog, LogFormat};
+use web_server::config::{Config, ConfigError, Listener, ProxyRoute};
+use web_server::proxy::Proxy;
+use web_server::shutdown::{ReloadSignal, ShutdownSignal};
+use web_server::{event_loop, server, site, tls, PoolCreationError, ThreadPool};
 
-/*
-单线程 server 会依次处理每一个请求,
-意味着它在完成第一个连接的处理之前不会处理第二个连接.
-如果 server 正接收越来越多的请求, 这类串行操作会使性能越来越差
-*/
+// --cert/--key 时 HTTPS 的 listener 和 HTTP 的并排监听
+const HTTPS_ADDR: &str = "127.0.0.1:7879";
 
+// cargo run -- [single|threads|pool|epoll], 默认是 pool
+// cargo run -- pool --config web_server.toml  从配置文件读取监听地址, 线程数, 路由等, pool 模式下收到 SIGHUP 会重新加载
+// cargo run -- pool --cert cert.pem --key key.pem [--redirect]  同时监听 HTTPS, --redirect 让 HTTP 都跳转到 HTTPS
+// cargo run -- pool --proxy /api=127.0.0.1:10086  把 /api 开头的请求转发给上游(比如 actix 的 mock API), 可以写多个, 上游之间用逗号分隔
+// cargo run -- pool --access-log access.log [--log-format combined|common|json]  写访问日志, 超过 10MB 或者每天轮转一次
+// cargo run -- gen-cert [cert.pem key.pem]  生成本地开发用的自签名证书
 fn main() {
-    thread_poll();
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.first().map(String::as_str) == Some("gen-cert") {
+        let cert = args.get(1).map_or("cert.pem", String::as_str);
+        let key = args.get(2).map_or("key.pem", String::as_str);
+        tls::generate_self_signed(Path::new(cert), Path::new(key), &["localhost", "127.0.0.1"])
+            .unwrap();
+        return println!("Wrote {} and {}", cert, key);
+    }
+
+    // 配置有问题时把所有问题都列出来再退出
+    let config = load_config(&args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    site::install(config.site());
+
+    let log = open_access_log(&args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    if let Some(log) = log {
+        // 只在这里设置一次, 不会失败
+        let _ = access_log::install(log);
+    }
+
+    // 第一个参数不是选项时是模式
+    let mode = args
+        .first()
+        .map(String::as_str)
+        .filter(|arg| !arg.starts_with("--"));
+    match mode {
+        Some("single") => server::single_thread_mode(bind(&config.listener[0])),
+        Some("threads") => server::finite_number_of_multi_threads(bind(&config.listener[0])),
+        Some("epoll") => epoll_mode(&config),
+        Some("pool") | None => thread_poll(config, &args),
+        Some(mode) => eprintln!(
+            "Unknown mode `{}`, expected single, threads, pool or epoll",
+            mode
+        ),
+    }
+}
+
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
 }
 
-#[allow(unused)]
-fn single_thread_mode() {
-    // bind 函数类似于 new 函数, 在这里它返回一个新的 TcpListener 实例
-    // 在网络领域, 连接到监听端口被称为绑定到一个端口(binding to a port)
-    let listener = match TcpListener::bind("127.0.0.1:7878") {
+// 有 --config 时读配置文件, 否则用默认配置, 再叠加上命令行里的 --cert, --proxy 等选项
+fn load_config(args: &[String]) -> Result<Config, ConfigError> {
+    let mut config = match flag_value(args, "--config") {
+        Some(path) => Config::load(Path::new(path))?,
+        None => Config::default(),
+    };
+
+    // 没有同时给出 --cert 和 --key 时只监听 HTTP
+    if let (Some(cert), Some(key)) = (flag_value(args, "--cert"), flag_value(args, "--key")) {
+        if args.iter().any(|arg| arg == "--redirect") {
+            for listener in &mut config.listener {
+                listener.redirect_to_https = true;
+            }
+        }
+        config.listener.push(Listener {
+            cert: Some(PathBuf::from(cert)),
+            key: Some(PathBuf::from(key)),
+            ..Listener::new(HTTPS_ADDR)
+        });
+    }
+
+    // 命令行选项本身的问题和配置的问题一起列出来
+    let mut problems = Vec::new();
+
+    // 每个 --proxy 的值是 前缀=上游[,上游...]
+    for route in args
+        .windows(2)
+        .filter(|pair| pair[0] == "--proxy")
+        .map(|pair| &pair[1])
+    {
+        let Some((prefix, upstreams)) = route.split_once('=') else {
+            problems.push(format!(
+                "--proxy: `{}` is not valid, expected PREFIX=HOST:PORT",
+                route
+            ));
+            continue;
+        };
+        config.proxy.push(ProxyRoute {
+            prefix: prefix.to_string(),
+            upstreams: upstreams.split(',').map(str::to_string).collect(),
+        });
+    }
+
+    if let Some(name) = flag_value(args, "--log-format") {
+        if LogFormat::parse(name).is_none() {
+            problems.push(format!(
+                "--log-format: unknown format `{}`, expected common, combined or json",
+                name
+            ));
+        }
+    }
+
+    // 命令行叠加的部分也要校验
+    if let Err(mut invalid) = config.validate() {
+        problems.append(&mut invalid);
+    }
+    if !problems.is_empty() {
+        return Err(ConfigError {
+            path: PathBuf::from(flag_value(args, "--config").unwrap_or("command line")),
+            problems,
+        });
+    }
+
+    Ok(config)
+}
+
+// bind 函数类似于 new 函数, 在这里它返回一个新的 TcpListener 实例
+// 在网络领域, 连接到监听端口被称为绑定到一个端口(binding to a port)
+fn bind(listener: &Listener) -> TcpListener {
+    match TcpListener::bind(listener.socket_addr()) {
         Ok(listener) => listener,
-        Err(err) => panic!("{}", err),
+        Err(err) => panic!("{}: {}", listener.addr, err),
+    }
+}
+
+// --log-format 已经在 load_config 里校验过了, 这里只会因为打不开文件失败
+fn open_access_log(args: &[String]) -> Result<Option<AccessLog>, ConfigError> {
+    let Some(path) = flag_value(args, "--access-log") else {
+        return Ok(None);
     };
+    let format = flag_value(args, "--log-format")
+        .and_then(LogFormat::parse)
+        .unwrap_or(LogFormat::Combined);
 
-    // incoming 方法返回一个迭代器, 提供 stream 流
-    // 这个遍历叫做连接尝试(connection attempts), 因为有很多原因就挂了
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+    let log = AccessLog::builder(path)
+        .format(format)
+        .max_size(10 * 1024 * 1024)
+        .rotate_every(Duration::from_secs(24 * 60 * 60))
+        .build()
+        .map_err(|e| ConfigError {
+            path: PathBuf::from("command line"),
+            problems: vec![format!("--access-log: cannot open {}: {}", path, e)],
+        })?;
 
-        handle_connection(stream);
+    Ok(Some(log))
+}
 
-        // println!("Connection established!");
+// 配置了反向代理时先看要不要转发, 否则按原来的方式处理
+fn handle<S: server::Connection>(
+    proxy: &Option<Arc<Proxy>>,
+    stream: S,
+    client: Option<SocketAddr>,
+    proto: &str,
+) {
+    match proxy {
+        Some(proxy) => proxy.handle_connection(stream, client, proto),
+        None => server::handle_connection(stream, client),
     }
 }
 
-#[allow(unused)]
-fn finite_number_of_multi_threads() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+// 一个 listener 上的连接怎么处理
+#[derive(Clone)]
+enum Handler {
+    Http,
+    Https(Arc<ServerConfig>),
+    RedirectToHttps(u16),
+}
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+impl Handler {
+    fn handle(&self, proxy: &Option<Arc<Proxy>>, stream: TcpStream) {
+        let client = stream.peer_addr().ok();
 
-        thread::spawn(|| {
-            handle_connection(stream);
-        });
+        match self {
+            Handler::Http => handle(proxy, stream, client, "http"),
+            Handler::Https(config) => match tls::accept(config, stream) {
+                Ok(mut stream) => {
+                    handle(proxy, &mut stream, client, "https");
+                    tls::close(stream);
+                }
+                Err(e) => eprintln!("TLS handshake failed: {}", e),
+            },
+            Handler::RedirectToHttps(port) => server::redirect_to_https(stream, client, *port),
+        }
     }
 }
 
-fn thread_poll() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+// 按同一份配置运行的一组 listener 和线程池, 重新加载配置时整体换掉
+struct Generation {
+    config: Config,
+    listeners: Vec<(TcpListener, Handler)>,
+    poll: ThreadPool,
+    proxy: Option<Arc<Proxy>>,
+}
+
+impl Generation {
+    // 地址没变的 listener 从上一代复制过来, 不用重新 bind, 排队中的连接不会丢
+    fn new(config: Config, previous: Option<&Generation>) -> Result<Generation, String> {
+        let https_port = config
+            .listener
+            .iter()
+            .find(|listener| listener.is_tls())
+            .map(|listener| listener.socket_addr().port());
 
-    let poll = match ThreadPool::new(4) {
-        Ok(thread_poll) => thread_poll,
-        Err(e) => {
+        let mut listeners = Vec::new();
+        for listener in &config.listener {
+            let addr = listener.socket_addr();
+            let reused = previous.and_then(|previous| {
+                previous
+                    .listeners
+                    .iter()
+                    .find(|(old, _)| old.local_addr().is_ok_and(|old| old == addr))
+            });
+            let socket = match reused {
+                Some((old, _)) => old.try_clone(),
+                None => TcpListener::bind(addr),
+            }
+            .map_err(|e| format!("{}: {}", listener.addr, e))?;
+
+            let handler = match (&listener.cert, &listener.key) {
+                (Some(cert), Some(key)) => Handler::Https(
+                    tls::load_config(cert, key).map_err(|e| format!("{}: {}", listener.addr, e))?,
+                ),
+                // 校验过的配置里需要重定向时一定有 HTTPS listener
+                _ if listener.redirect_to_https => {
+                    Handler::RedirectToHttps(https_port.unwrap_or(443))
+                }
+                _ => Handler::Http,
+            };
+            listeners.push((socket, handler));
+        }
+
+        // 队列有上限, 突发流量时 accept 循环会被阻塞住, 而不是让内存无限增长
+        // 慢请求把常驻 worker 占满时, 临时扩到 workers.max 个
+        let poll = config.pool().map_err(|e| {
             let PoolCreationError { error_msg, code } = e;
-            panic!("[{}] {}", code, error_msg);
+            format!("[{}] {}", code, error_msg)
+        })?;
+
+        let proxy = config.proxy().map(Arc::new);
+        if let Some(proxy) = &proxy {
+            proxy.start_health_checks(&poll, config.health_check_interval());
         }
-    };
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        poll.execute(|| handle_connection(stream));
+        Ok(Generation {
+            config,
+            listeners,
+            poll,
+            proxy,
+        })
+    }
+
+    // 所有 listener 共用同一个线程池, 收到关闭或者重新加载的信号后返回
+    fn serve(&self, shutdown: &ShutdownSignal, reload: &ReloadSignal) {
+        let stop = ShutdownSignal::new();
+
+        thread::scope(|s| {
+            for (listener, handler) in &self.listeners {
+                let listener = listener.try_clone().unwrap();
+                let handler = handler.clone();
+                let proxy = self.proxy.clone();
+                let stop = &stop;
+                s.spawn(move || {
+                    server::serve(listener, &self.poll, stop, move |stream| {
+                        handler.handle(&proxy, stream)
+                    })
+                });
+            }
+
+            while !shutdown.is_triggered() && !reload.take() {
+                thread::sleep(Duration::from_millis(100));
+            }
+            stop.trigger();
+        });
+    }
+
+    // 关掉 listener, 等进行中的请求处理完
+    fn shutdown(self) {
+        let Generation {
+            config,
+            listeners,
+            poll,
+            ..
+        } = self;
+        drop(listeners);
+
+        let report = poll.shutdown(config.shutdown_timeout());
+        for id in report.timed_out {
+            eprintln!("Worker {} did not finish before the shutdown deadline", id);
+        }
     }
 }
 
-// 一般读取是不可变的, 但 stream 是可变的, 这里得用 mut
-fn handle_connection(mut stream: TcpStream) {
-    // 创建了一个 1024 字节的缓冲区(数组), 元素缺省为 0
-    let mut buffer = [0; 1024];
-
-    // 把流的数据存放到缓冲区中
-    stream.read(&mut buffer).unwrap();
-
-    // 把缓冲区的数据卷成字符串, 用 from_utf8_lossy 是因为数据中可能有一些非 UTF-8 的序列
-    // 这种被转换成 �
-    // println!("Request: {}", String::from_utf8_lossy(&buffer[..]));
-
-    let get_html = b"GET / HTTP/1.1\r\n";
-    let get_json = b"GET /api/posts HTTP/1.1\r\n";
-
-    let (status_line, filename, content_type) = if buffer.starts_with(get_html) {
-        ("HTTP/1.1 200 OK", "index.html", "text/html")
-    } else if buffer.starts_with(get_json) {
-        // 模拟单线程如果有一个耗时的, 其他的就慢了
-        thread::sleep(Duration::from_secs(10));
-        ("HTTP/1.1 200 OK", "data.json", "application/json")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND", "404.html", "text/html")
-    };
+fn thread_poll(config: Config, args: &[String]) {
+    // 收到 SIGINT/SIGTERM 后停止 accept, 等进行中的请求处理完再退出
+    let shutdown = ShutdownSignal::register().unwrap();
+    // 收到 SIGHUP 后重新读配置, 已经建立的连接继续在旧的线程池上处理完
+    let reload = ReloadSignal::register().unwrap();
 
-    let file = fs::read_to_string(filename).unwrap();
+    let mut current = Generation::new(config, None).unwrap_or_else(|e| panic!("{}", e));
 
-    let response = format!(
-        "{}\r\nContent-Length: {}\r\nContent-Type: {}\r\n\r\n{}",
-        status_line,
-        file.len(),
-        content_type,
-        file
-    );
+    loop {
+        current.serve(&shutdown, &reload);
+        if shutdown.is_triggered() {
+            break;
+        }
 
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+        let next = load_config(args)
+            .map_err(|e| e.to_string())
+            .and_then(|config| Generation::new(config, Some(&current)));
+        match next {
+            Ok(next) => {
+                site::install(next.config.site());
+                let previous = std::mem::replace(&mut current, next);
+                thread::spawn(move || previous.shutdown());
+                println!("Reloaded config");
+            }
+            Err(e) => eprintln!("Failed to reload config, keeping the old one: {}", e),
+        }
+    }
+
+    current.shutdown();
+    access_log::close();
+}
+
+fn epoll_mode(config: &Config) {
+    // 连接的读写都在事件循环线程上, worker 只负责生成响应, 不会被慢客户端占住
+    let poll = ThreadPool::builder()
+        .min_size(config.workers.min)
+        .max_size(config.workers.max)
+        .build()
+        .unwrap();
+
+    let shutdown = ShutdownSignal::register().unwrap();
+
+    let listener = bind(&config.listener[0]);
+    let report = event_loop::run(listener, poll, &shutdown, config.shutdown_timeout()).unwrap();
+    for id in report.timed_out {
+        eprintln!("Worker {} did not finish before the shutdown deadline", id);
+    }
+    access_log::close();
 }
@@ -0,0 +1,159 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+// 第 i 个桶统计耗时在 [2^(i-1), 2^i) 微秒之间的样本(0 微秒算在第 0 个桶), 最后一个桶放所有更大的值
+// 2^26 微秒大约是 67 秒, 对一个请求来说足够了
+const BUCKETS: usize = 27;
+
+/// 按 2 的幂次分桶的耗时直方图, 记录时不需要加锁
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub(crate) fn new() -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+            max_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, duration: Duration) {
+        let micros = duration.as_micros().min(u64::MAX as u128) as u64;
+        let index = ((u64::BITS - micros.leading_zeros()) as usize).min(BUCKETS - 1);
+
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+            count: self.count.load(Ordering::Relaxed),
+            sum: Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)),
+            max: Duration::from_micros(self.max_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// 直方图在某一时刻的快照
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    buckets: Vec<u64>,
+    pub count: u64,
+    pub sum: Duration,
+    pub max: Duration,
+}
+
+impl HistogramSnapshot {
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => Duration::from_nanos((self.sum.as_nanos() / count as u128) as u64),
+        }
+    }
+
+    /// 估算的分位数, 比如 `percentile(0.99)`, 返回所在桶的上界, 不会超过记录到的最大值
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+
+        let target = ((self.count as f64 * p.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0;
+
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Duration::from_micros(1 << index).min(self.max);
+            }
+        }
+
+        self.max
+    }
+
+    /// 每个桶的上界和样本数, 空桶会被跳过
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(index, count)| (Duration::from_micros(1 << index), *count))
+    }
+}
+
+// 线程池内部的计数器
+pub(crate) struct Metrics {
+    pub(crate) active: AtomicUsize,
+    pub(crate) completed: AtomicU64,
+    pub(crate) panicked: AtomicU64,
+    pub(crate) queue_wait: Histogram,
+    pub(crate) execution: Histogram,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Metrics {
+        Metrics {
+            active: AtomicUsize::new(0),
+            completed: AtomicU64::new(0),
+            panicked: AtomicU64::new(0),
+            queue_wait: Histogram::new(),
+            execution: Histogram::new(),
+        }
+    }
+}
+
+/// `ThreadPool::stats` 返回的快照
+#[derive(Debug, Clone, Default)]
+pub struct PoolStats {
+    /// 排队等待执行的任务数
+    pub queued: usize,
+    /// 当前存活的 worker 数
+    pub workers: usize,
+    /// 正在执行任务的 worker 数
+    pub active_workers: usize,
+    /// 执行完的任务数, 包括 panic 的
+    pub completed: u64,
+    /// 执行时 panic 的任务数
+    pub panicked: u64,
+    /// 因为队列满被拒绝或被挤掉的任务数
+    pub rejected: usize,
+    /// 任务从提交到开始执行的等待时间
+    pub queue_wait: HistogramSnapshot,
+    /// 任务的执行时间
+    pub execution: HistogramSnapshot,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_use_bucket_upper_bounds() {
+        let histogram = Histogram::new();
+        for micros in [2, 5, 6, 7, 100] {
+            histogram.record(Duration::from_micros(micros));
+        }
+
+        let snapshot = histogram.snapshot();
+
+        assert_eq!(5, snapshot.count);
+        assert_eq!(Duration::from_micros(24), snapshot.mean());
+        // 2 落在 [2, 4), 5/6/7 落在 [4, 8), 100 落在 [64, 128)
+        assert_eq!(Duration::from_micros(4), snapshot.percentile(0.2));
+        assert_eq!(Duration::from_micros(8), snapshot.percentile(0.5));
+        assert_eq!(Duration::from_micros(100), snapshot.percentile(1.0));
+        assert_eq!(3, snapshot.buckets().count());
+    }
+}
@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+/// worker 为什么退出了
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// 线程池关闭时收到了 Terminate
+    Terminated,
+    /// 空闲超过 keep_alive, 线程池缩容
+    Retired,
+    /// 线程意外退出, 随后会被重新拉起
+    Died,
+}
+
+/// 线程池内部发生的事件, 取代原来直接 println 到标准输出的日志
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolEvent<'a> {
+    WorkerStarted {
+        worker_id: usize,
+    },
+    WorkerStopped {
+        worker_id: usize,
+        reason: StopReason,
+    },
+    JobStarted {
+        worker_id: usize,
+        queue_wait: Duration,
+    },
+    JobFinished {
+        worker_id: usize,
+        elapsed: Duration,
+    },
+    JobPanicked {
+        worker_id: usize,
+        message: &'a str,
+    },
+    /// 队列满了, 任务被拒绝或者挤掉了更早的任务
+    JobRejected,
+    /// 开始关闭线程池, 即将通知这么多个 worker 退出
+    ShutdownStarted {
+        workers: usize,
+    },
+}
+
+/// 接收线程池事件的观察者, 通过 `ThreadPoolBuilder::observer` 设置
+///
+/// 事件是在 worker 线程上同步调用的, 实现里不要做耗时的操作.
+pub trait Observer: Send + Sync {
+    fn on_event(&self, event: PoolEvent<'_>);
+}
+
+/// 默认的观察者, 什么都不做
+#[derive(Debug, Default)]
+pub struct NoopObserver;
+
+impl Observer for NoopObserver {
+    fn on_event(&self, _event: PoolEvent<'_>) {}
+}
+
+/// 把事件打印到标准输出, 和原来的 println 输出差不多, 调试时用
+#[derive(Debug, Default)]
+pub struct LogObserver;
+
+impl Observer for LogObserver {
+    fn on_event(&self, event: PoolEvent<'_>) {
+        match event {
+            PoolEvent::WorkerStarted { worker_id } => println!("Worker {} started.", worker_id),
+            PoolEvent::WorkerStopped { worker_id, reason } => {
+                println!("Worker {} stopped: {:?}.", worker_id, reason)
+            }
+            PoolEvent::JobStarted { worker_id, .. } => {
+                println!("Worker {} got a job; executing.", worker_id)
+            }
+            PoolEvent::JobFinished { .. } => {}
+            PoolEvent::JobPanicked { worker_id, message } => {
+                println!(
+                    "Worker {} panicked while executing a job: {}",
+                    worker_id, message
+                )
+            }
+            PoolEvent::JobRejected => println!("Job rejected: queue is full."),
+            PoolEvent::ShutdownStarted { .. } => {
+                println!("Sending terminate message to all workers.")
+            }
+        }
+    }
+}
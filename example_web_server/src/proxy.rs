@@ -0,0 +1,833 @@
+use crate::access_log;
+use crate::cgi;
+use crate::http::{self, Limits, Request, RequestError, Response};
+use crate::server;
+use crate::site;
+use crate::{ScheduledHandle, ThreadPool};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// 上游的响应头最多读这么多
+const MAX_UPSTREAM_HEAD: usize = 64 * 1024;
+
+// 逐跳(hop-by-hop)的头只对当前这一段连接有效, 不能转发给上游
+// Connection 里列出的头也是逐跳的, 见 `upstream_head`
+// Host 和 X-Forwarded-* 会被重写, chunked 的请求体会重新加上 Transfer-Encoding
+const SKIPPED_HEADERS: [&str; 12] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+    "Host",
+    "X-Forwarded-For",
+    "X-Forwarded-Host",
+    "X-Forwarded-Proto",
+];
+
+/// 一个上游地址, 连接失败或者健康检查不通过时被标记为不健康
+#[derive(Debug)]
+pub struct Upstream {
+    addr: String,
+    healthy: AtomicBool,
+}
+
+impl Upstream {
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    fn connect(&self, timeout: Duration) -> io::Result<TcpStream> {
+        let addrs: Vec<SocketAddr> = self.addr.to_socket_addrs()?.collect();
+
+        let mut last_error = io::Error::new(ErrorKind::NotFound, "upstream has no address");
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    // 只要回了 HTTP 响应就算健康, 状态码不管
+    //
+    // 上游不一定有 `health_path` 这个路由, 回 404 也说明它还活着.
+    fn probe(&self, path: &str, timeout: Duration) -> bool {
+        let probe = || -> io::Result<bool> {
+            let mut stream = self.connect(timeout)?;
+            stream.set_read_timeout(Some(timeout))?;
+            write!(
+                stream,
+                "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                path, self.addr
+            )?;
+
+            let mut status_line = String::new();
+            BufReader::new(stream).read_line(&mut status_line)?;
+
+            Ok(status_line.starts_with("HTTP/"))
+        };
+
+        probe().unwrap_or(false)
+    }
+}
+
+#[derive(Debug)]
+struct Route {
+    prefix: String,
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+}
+
+impl Route {
+    // 轮询选上游, 先只试健康的, 都连不上时再把不健康的也试一遍, 免得健康检查没开时一个都用不了
+    // 在健康的上游里取模, 按全部上游取模的话排在不健康的上游后面的那个会被多选一次
+    fn connect(&self, timeout: Duration) -> Option<TcpStream> {
+        let start = self.next.fetch_add(1, Ordering::SeqCst);
+        let (healthy, unhealthy): (Vec<_>, Vec<_>) = self
+            .upstreams
+            .iter()
+            .partition(|upstream| upstream.is_healthy());
+
+        for candidates in [healthy, unhealthy] {
+            for i in 0..candidates.len() {
+                let upstream = candidates[(start + i) % candidates.len()];
+
+                match upstream.connect(timeout) {
+                    Ok(stream) => {
+                        upstream.healthy.store(true, Ordering::SeqCst);
+                        return Some(stream);
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to connect to upstream {}: {}", upstream.addr, e);
+                        upstream.healthy.store(false, Ordering::SeqCst);
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// 把匹配路径前缀的请求转发给上游 HTTP server 的反向代理
+///
+/// ```no_run
+/// use std::net::TcpListener;
+/// use std::sync::Arc;
+/// use std::time::{Duration, Instant};
+/// use web_server::proxy::Proxy;
+/// use web_server::shutdown::ShutdownSignal;
+/// use web_server::{server, ThreadPool};
+///
+/// let proxy = Arc::new(Proxy::new().route("/api", &["127.0.0.1:8080", "127.0.0.1:8081"]));
+/// let pool = ThreadPool::new(4).unwrap();
+/// proxy.start_health_checks(&pool, Duration::from_secs(5));
+///
+/// let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+/// server::serve(listener, &pool, &ShutdownSignal::new(), move |stream| {
+///     let client = stream.peer_addr().ok();
+///     proxy.handle_connection(stream, client, "http")
+/// });
+/// ```
+#[derive(Debug)]
+pub struct Proxy {
+    routes: Vec<Route>,
+    health_path: String,
+    timeout: Duration,
+}
+
+impl Proxy {
+    pub fn new() -> Proxy {
+        Proxy {
+            routes: Vec::new(),
+            health_path: "/".to_string(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// 把 `prefix` 下的路径转发到 `upstreams`, 多个上游之间轮询, 路径原样转发
+    ///
+    /// 按路径段匹配, `/api` 匹配 `/api` 和 `/api/posts`, 不匹配 `/apiary`.
+    ///
+    /// 多个前缀都匹配时用最长的那个.
+    pub fn route(mut self, prefix: &str, upstreams: &[&str]) -> Proxy {
+        assert!(!upstreams.is_empty(), "`upstreams` should not be empty");
+
+        self.routes.push(Route {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            upstreams: upstreams
+                .iter()
+                .map(|addr| Upstream {
+                    addr: addr.to_string(),
+                    healthy: AtomicBool::new(true),
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+        });
+        self.routes
+            .sort_by_key(|route| std::cmp::Reverse(route.prefix.len()));
+        self
+    }
+
+    /// 健康检查请求的路径, 默认是 `/`
+    ///
+    /// 上游回了任何 HTTP 响应都算健康, 连不上, 超时或者回的不是 HTTP 才算不健康.
+    pub fn health_path(mut self, path: &str) -> Proxy {
+        self.health_path = path.to_string();
+        self
+    }
+
+    /// 连接上游和等待上游响应的超时时间, 默认 30 秒
+    pub fn timeout(mut self, timeout: Duration) -> Proxy {
+        self.timeout = timeout;
+        self
+    }
+
+    fn route_for(&self, path: &str) -> Option<&Route> {
+        // 和 Site 一样按路径段匹配, /api 不能匹配 /apiary
+        self.routes
+            .iter()
+            .find(|route| cgi::strip_prefix(path, &route.prefix).is_some())
+    }
+
+    /// 所有上游和它们当前是否健康
+    pub fn upstreams(&self) -> impl Iterator<Item = &Upstream> {
+        self.routes.iter().flat_map(|route| &route.upstreams)
+    }
+
+    /// 对所有上游做一次健康检查
+    pub fn check_health(&self) {
+        // 健康检查不需要等那么久
+        let timeout = self.timeout.min(Duration::from_secs(2));
+
+        for upstream in self.upstreams() {
+            let healthy = upstream.probe(&self.health_path, timeout);
+            if healthy != upstream.healthy.swap(healthy, Ordering::SeqCst) {
+                eprintln!(
+                    "Upstream {} is now {}",
+                    upstream.addr,
+                    if healthy { "healthy" } else { "unhealthy" }
+                );
+            }
+        }
+    }
+
+    /// 在线程池上定期做健康检查, 取消返回的句柄或关闭线程池后停止
+    pub fn start_health_checks(
+        self: &Arc<Self>,
+        pool: &ThreadPool,
+        interval: Duration,
+    ) -> ScheduledHandle {
+        let proxy = Arc::clone(self);
+
+        pool.execute_every(interval, move || proxy.check_health())
+    }
+
+    /// 处理一个客户端连接, 匹配的请求转发给上游, 其余的交给 `server::respond`
+    ///
+    /// `client` 和 `proto` 用来填 `X-Forwarded-For` 和 `X-Forwarded-Proto`.
+    pub fn handle_connection<S: Read + Write>(
+        &self,
+        stream: S,
+        client: Option<SocketAddr>,
+        proto: &str,
+    ) {
+        let mut reader = BufReader::new(stream);
+        let site = site::current();
+        let limits = site.request_limits();
+        let started = Instant::now();
+
+        // 请求头的限制和 server::handle_connection 一样, body 边读边转发, 只限制总大小
+        let request = read_request_head(&mut reader, limits).and_then(|request| {
+            // 同时带着两种长度时, 代理和上游可能会在不同的地方切开请求(request smuggling)
+            let lengths = request
+                .headers
+                .iter()
+                .filter(|(name, _)| {
+                    name.eq_ignore_ascii_case("Content-Length")
+                        || name.eq_ignore_ascii_case("Transfer-Encoding")
+                })
+                .count();
+            if lengths > 1 {
+                return Err(RequestError::Malformed("ambiguous request body length"));
+            }
+            if request.header("Transfer-Encoding").is_some() && !is_chunked(&request) {
+                return Err(RequestError::Unsupported("only chunked is supported"));
+            }
+            let length = request.content_length()?;
+            if length > limits.max_body_size {
+                return Err(RequestError::BodyTooLarge);
+            }
+            Ok((request, length))
+        });
+        let (request, length) = match request {
+            Ok(request) => request,
+            Err(e) => {
+                if let Some(response) = e.response() {
+                    let _ = response.write_to(reader.get_mut());
+                    server::log_response(client, b"", &response, started);
+                }
+                return;
+            }
+        };
+
+        let Some(route) = self.route_for(request.path()) else {
+            // CGI 脚本要用到 body, 所以先把整个请求读完
+            let mut response = match read_rest(&mut reader, request.raw(), length, limits) {
+                // 客户端收到 101 之前不会发帧, reader 里没有缓冲着的数据
+                Ok(request)
+                    if server::serve_websocket(reader.get_mut(), client, &request, started) =>
+                {
+                    return;
+                }
+                Ok(request) => server::respond_to(&request, client),
+                Err(e) => match e.response() {
+                    Some(response) => response,
+                    None => return,
+                },
+            };
+            // 代理模式下每个连接只处理一个请求
+            response.set_header("Connection", "close");
+            let _ = response.write_to(reader.get_mut());
+            server::log_response(client, request.raw(), &response, started);
+            return;
+        };
+
+        match self.forward(route, &request, &mut reader, client, proto, limits) {
+            Ok((status, bytes)) => {
+                access_log::record(client, request.raw(), status, bytes, started)
+            }
+            Err(e) => eprintln!("Failed to proxy {}: {}", request.target, e),
+        }
+    }
+
+    fn forward<S: Read + Write>(
+        &self,
+        route: &Route,
+        request: &Request,
+        reader: &mut BufReader<S>,
+        client: Option<SocketAddr>,
+        proto: &str,
+        limits: &Limits,
+    ) -> io::Result<(u16, u64)> {
+        let Some(mut upstream) = route.connect(self.timeout) else {
+            return reply(reader.get_mut(), "HTTP/1.1 502 BAD GATEWAY");
+        };
+        upstream.set_read_timeout(Some(self.timeout))?;
+
+        let head = upstream_head(request, upstream.peer_addr()?, client, proto);
+        upstream.write_all(head.as_bytes())?;
+
+        // 请求体边读边转发, 不在内存里攒整个 body
+        if is_chunked(request) {
+            match copy_chunked(reader, &mut upstream, limits.max_body_size) {
+                // 还没给客户端写过任何东西, 可以回 413, 上游那边的请求直接丢掉
+                Err(e) if e.kind() == ErrorKind::InvalidInput => {
+                    return reply(reader.get_mut(), "HTTP/1.1 413 PAYLOAD TOO LARGE");
+                }
+                result => result?,
+            }
+        } else {
+            // handle_connection 已经检查过 Content-Length
+            let length = request.content_length().unwrap_or_default();
+            io::copy(&mut reader.by_ref().take(length), &mut upstream)?;
+        }
+        upstream.flush()?;
+
+        // 先读出响应头拿到状态码, 访问日志要用
+        let stream = reader.get_mut();
+        let mut upstream = BufReader::new(upstream);
+        let head = match read_response_head(&mut upstream, MAX_UPSTREAM_HEAD, self.timeout) {
+            Ok(head) => head,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                return reply(stream, "HTTP/1.1 504 GATEWAY TIMEOUT");
+            }
+            Err(_) => return reply(stream, "HTTP/1.1 502 BAD GATEWAY"),
+        };
+        let status = String::from_utf8_lossy(&head)
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .unwrap_or_default();
+        stream.write_all(&head)?;
+
+        // 发给上游的是 Connection: close, 响应读到 EOF 就结束了, 同样是边读边写回客户端
+        let bytes = io::copy(&mut upstream, stream)?;
+        stream.flush()?;
+
+        Ok((status, bytes))
+    }
+}
+
+// 代理自己生成的错误响应, 返回状态码和 body 长度给访问日志
+fn reply<W: Write>(stream: &mut W, status_line: &str) -> io::Result<(u16, u64)> {
+    let response = Response::new(status_line, Vec::new());
+    response.write_to(stream)?;
+
+    Ok((response.status_code(), 0))
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Proxy::new()
+    }
+}
+
+// 用 http::parse_head 读客户端的请求头, body 留在 BufReader 里
+//
+// 一次读一行, 每读一行就按 `limits` 检查一遍, 超长的路径和请求头不用等读完就能拒绝.
+// 单次读的超时由 socket 控制, 这里只能在每读完一行后检查总时间.
+fn read_request_head<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Request, RequestError> {
+    let started = Instant::now();
+    let mut head = Vec::new();
+
+    loop {
+        if let Some((request, _)) = http::parse_head(&head, limits)? {
+            return Ok(request);
+        }
+        if started.elapsed() > limits.request_timeout {
+            return Err(RequestError::Timeout);
+        }
+
+        // take 防止一行没有换行符的超长数据被整个读进内存
+        let limit = (limits.max_header_size + 1).saturating_sub(head.len()) as u64;
+        match reader.by_ref().take(limit).read_until(b'\n', &mut head) {
+            Ok(0) if head.is_empty() => return Err(RequestError::Closed),
+            Ok(0) => return Err(RequestError::Malformed("connection closed mid-request")),
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// 一直读到空行, 只用来读上游的响应头, 响应体留在 BufReader 里
+//
+// 超过 `max_size` 时返回 InvalidData, 超过 `timeout` 还没读完时返回 TimedOut.
+fn read_response_head<R: BufRead>(
+    reader: &mut R,
+    max_size: usize,
+    timeout: Duration,
+) -> io::Result<Vec<u8>> {
+    let started = Instant::now();
+    let mut head = Vec::new();
+
+    loop {
+        let limit = (max_size + 1).saturating_sub(head.len()) as u64;
+        let read = reader.by_ref().take(limit).read_until(b'\n', &mut head)?;
+        if read == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        if head.ends_with(b"\r\n\r\n") {
+            return Ok(head);
+        }
+        if head.len() > max_size {
+            return Err(io::Error::new(ErrorKind::InvalidData, "head too large"));
+        }
+        if started.elapsed() > timeout {
+            return Err(ErrorKind::TimedOut.into());
+        }
+    }
+}
+
+// 读完请求头后面 `length` 字节的 body, 和请求头一起解析成完整的请求
+fn read_rest<R: Read>(
+    reader: &mut R,
+    head: &[u8],
+    length: u64,
+    limits: &Limits,
+) -> Result<Request, RequestError> {
+    let mut raw = head.to_vec();
+    raw.resize(head.len() + length as usize, 0);
+    reader.read_exact(&mut raw[head.len()..])?;
+
+    http::parse_request(&raw, limits)?.ok_or(RequestError::Malformed("incomplete request"))
+}
+
+// 按 chunked 编码的格式原样转发, 只是为了知道 body 在哪里结束
+//
+// 块数据加起来超过 `max_size` 时返回 InvalidInput.
+fn copy_chunked<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    max_size: u64,
+) -> io::Result<()> {
+    let mut line = Vec::new();
+    let mut total = 0u64;
+
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        writer.write_all(&line)?;
+
+        let size = String::from_utf8_lossy(&line);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "invalid chunk size"))?;
+
+        total = total.saturating_add(size);
+        if total > max_size {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "body too large"));
+        }
+
+        if size == 0 {
+            // 最后一个块之后是可选的 trailer, 以空行结束
+            loop {
+                line.clear();
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                writer.write_all(&line)?;
+                if line == b"\r\n" {
+                    return Ok(());
+                }
+            }
+        }
+
+        // 块数据后面跟着 \r\n
+        io::copy(&mut reader.by_ref().take(size + 2), writer)?;
+    }
+}
+
+fn is_chunked(request: &Request) -> bool {
+    request
+        .header("Transfer-Encoding")
+        .is_some_and(|value| value.to_ascii_lowercase().contains("chunked"))
+}
+
+// 发给上游的请求头
+fn upstream_head(
+    request: &Request,
+    upstream: SocketAddr,
+    client: Option<SocketAddr>,
+    proto: &str,
+) -> String {
+    let mut head = format!(
+        "{} {} {}\r\n",
+        request.method, request.target, request.version
+    );
+
+    let connection = request.header("Connection").unwrap_or_default();
+    let hop_by_hop = |name: &str| {
+        SKIPPED_HEADERS
+            .iter()
+            .copied()
+            .chain(connection.split(',').map(str::trim))
+            .any(|skipped| skipped.eq_ignore_ascii_case(name))
+    };
+    for (name, value) in &request.headers {
+        if !hop_by_hop(name) {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    // 请求体原样按 chunked 转发
+    if is_chunked(request) {
+        head.push_str("Transfer-Encoding: chunked\r\n");
+    }
+
+    head.push_str(&format!("Host: {}\r\n", upstream));
+    // 经过多层代理时 X-Forwarded-For 依次追加
+    let forwarded_for = match (request.header("X-Forwarded-For"), client) {
+        (Some(previous), Some(client)) => Some(format!("{}, {}", previous, client.ip())),
+        (Some(previous), None) => Some(previous.to_string()),
+        (None, Some(client)) => Some(client.ip().to_string()),
+        (None, None) => None,
+    };
+    if let Some(forwarded_for) = forwarded_for {
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+    }
+    if let Some(host) = request.header("Host") {
+        head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+    }
+    head.push_str(&format!("X-Forwarded-Proto: {}\r\n", proto));
+    head.push_str("Connection: close\r\n\r\n");
+
+    head
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // 上游的桩: 把收到的请求头和 body 原样放进响应里, 前面加上自己的名字
+    fn stub(name: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let request = read_request_head(&mut reader, &Limits::default()).unwrap();
+
+                let mut body = Vec::new();
+                if is_chunked(&request) {
+                    copy_chunked(&mut reader, &mut body, u64::MAX).unwrap();
+                } else {
+                    let length = request.content_length().unwrap();
+                    reader.by_ref().take(length).read_to_end(&mut body).unwrap();
+                }
+
+                let mut echo = format!("{}\n", name).into_bytes();
+                echo.extend_from_slice(request.raw());
+                echo.extend_from_slice(&body);
+                Response::new("HTTP/1.1 200 OK", echo)
+                    .write_to(reader.get_mut())
+                    .unwrap();
+            }
+        });
+
+        addr
+    }
+
+    // 起一个只处理一个连接的代理, 发出请求并返回完整响应
+    fn send(proxy: &Arc<Proxy>, request: &[u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let proxy = Arc::clone(proxy);
+        let server = thread::spawn(move || {
+            let (stream, client) = listener.accept().unwrap();
+            proxy.handle_connection(stream, Some(client), "https");
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        server.join().unwrap();
+
+        response
+    }
+
+    #[test]
+    fn forwards_rewritten_headers_and_bodies() {
+        let upstream = stub("api").to_string();
+        let proxy = Arc::new(Proxy::new().route("/api", &[&upstream]));
+
+        let response = send(
+            &proxy,
+            b"POST /api/posts HTTP/1.1\r\nHost: example.com\r\nConnection: keep-alive\r\n\
+              Content-Length: 5\r\n\r\nhello",
+        );
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("api\nPOST /api/posts HTTP/1.1\r\n"));
+        assert!(response.contains(&format!("Host: {}\r\n", upstream)));
+        assert!(response.contains("X-Forwarded-For: 127.0.0.1\r\n"));
+        assert!(response.contains("X-Forwarded-Host: example.com\r\n"));
+        assert!(response.contains("X-Forwarded-Proto: https\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(!response.contains("keep-alive"));
+        assert!(response.ends_with("\r\n\r\nhello"));
+
+        let response = send(
+            &proxy,
+            b"POST /api/posts HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n",
+        );
+        assert!(response.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(response.ends_with("3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn drops_hop_by_hop_headers() {
+        let upstream = stub("api").to_string();
+        let proxy = Arc::new(Proxy::new().route("/api", &[&upstream]));
+
+        let response = send(
+            &proxy,
+            b"GET /api HTTP/1.1\r\nConnection: Upgrade, X-Hop\r\nUpgrade: websocket\r\n\
+              X-Hop: 1\r\nTrailer: X-Sum\r\nProxy-Authorization: Basic YTpi\r\n\
+              X-End: 1\r\n\r\n",
+        );
+
+        let forwarded = response.split_once("\r\n\r\napi\n").unwrap().1;
+        for header in ["Upgrade", "X-Hop", "Trailer", "Proxy-Authorization"] {
+            assert!(!forwarded.contains(header), "{}", forwarded);
+        }
+        assert!(forwarded.contains("X-End: 1\r\n"));
+    }
+
+    #[test]
+    fn rejects_requests_with_ambiguous_body_lengths() {
+        let upstream = stub("api").to_string();
+        let proxy = Arc::new(Proxy::new().route("/api", &[&upstream]));
+
+        let response = send(
+            &proxy,
+            b"POST /api HTTP/1.1\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n\
+              0\r\n\r\n",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 400 BAD REQUEST\r\n"),
+            "{}",
+            response
+        );
+
+        let response = send(
+            &proxy,
+            b"POST /api HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 400 BAD REQUEST\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn matches_prefixes_on_segment_boundaries() {
+        let upstream = stub("api").to_string();
+        let proxy = Arc::new(Proxy::new().route("/api/", &[&upstream]));
+
+        assert!(send(&proxy, b"GET /api HTTP/1.1\r\n\r\n").contains("\r\n\r\napi\n"));
+        assert!(send(&proxy, b"GET /api/posts HTTP/1.1\r\n\r\n").contains("\r\n\r\napi\n"));
+
+        // 不在 /api 下的路径交给站点, 默认站点上没有 /apiary
+        let response = send(&proxy, b"GET /apiary HTTP/1.1\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 404 NOT FOUND\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn applies_request_limits_before_forwarding() {
+        let upstream = stub("api").to_string();
+        let proxy = Arc::new(Proxy::new().route("/api", &[&upstream]));
+
+        let long_path = format!("GET /api/{} HTTP/1.1\r\n\r\n", "a".repeat(4096));
+        let response = send(&proxy, long_path.as_bytes());
+        assert!(
+            response.starts_with("HTTP/1.1 414 URI TOO LONG\r\n"),
+            "{}",
+            response
+        );
+
+        let many_headers = format!("GET /api HTTP/1.1\r\n{}\r\n", "X-A: 1\r\n".repeat(200));
+        let response = send(&proxy, many_headers.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 431 "), "{}", response);
+
+        let response = send(&proxy, b"POST /api HTTP/1.1\r\nContent-Length: abc\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 400 BAD REQUEST\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn counts_any_http_response_as_healthy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                read_request_head(&mut reader, &Limits::default()).unwrap();
+                Response::new("HTTP/1.1 404 NOT FOUND", Vec::new())
+                    .write_to(reader.get_mut())
+                    .unwrap();
+            }
+        });
+        let proxy = Proxy::new().route("/api", &[&addr]);
+
+        proxy.check_health();
+
+        assert!(proxy.upstreams().all(Upstream::is_healthy));
+    }
+
+    #[test]
+    fn round_robins_and_skips_unhealthy_upstreams() {
+        let (first, second) = (stub("first").to_string(), stub("second").to_string());
+        // 绑定后马上释放, 这个端口上没有人监听
+        let dead = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let proxy = Arc::new(Proxy::new().route("/api", &[&first, &dead, &second]));
+
+        proxy.check_health();
+        let healthy: Vec<_> = proxy.upstreams().map(Upstream::is_healthy).collect();
+        assert_eq!(vec![true, false, true], healthy);
+
+        let names: Vec<_> = (0..4)
+            .map(|_| {
+                let response = send(&proxy, b"GET /api HTTP/1.1\r\n\r\n");
+                let body = response.split_once("\r\n\r\n").unwrap().1;
+                body.lines().next().unwrap().to_string()
+            })
+            .collect();
+
+        assert_eq!(vec!["first", "second", "first", "second"], names);
+    }
+
+    #[test]
+    fn hands_websocket_upgrades_outside_the_routes_to_the_site() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = Arc::new(Proxy::new().route("/api", &["127.0.0.1:1"]));
+        let server = thread::spawn(move || {
+            let (stream, client) = listener.accept().unwrap();
+            proxy.handle_connection(stream, Some(client), "http");
+        });
+
+        // 默认站点上 /ws 是回显
+        let mut stream = BufReader::new(TcpStream::connect(addr).unwrap());
+        stream
+            .get_mut()
+            .write_all(
+                b"GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                  Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+        let head = read_response_head(&mut stream, 8 * 1024, Duration::from_secs(5)).unwrap();
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 "), "{}", head);
+        assert!(!head.contains("Connection: close"));
+
+        // 带掩码的 close 帧, 掩码全是 0
+        stream
+            .get_mut()
+            .write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xE8])
+            .unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(vec![0x88, 2, 0x03, 0xE8], reply);
+    }
+
+    #[test]
+    fn answers_bad_gateway_without_upstreams() {
+        let dead = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let proxy = Arc::new(Proxy::new().route("/api", &[&dead]));
+
+        let response = send(&proxy, b"GET /api/posts HTTP/1.1\r\n\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 502 BAD GATEWAY\r\n"));
+    }
+}
@@ -0,0 +1,444 @@
+use crate::{lock, Job, Message};
+use crossbeam_deque::{Injector, Steal, Stealer, Worker as Deque};
+use std::cell::RefCell;
+use std::fmt;
+use std::iter;
+use std::sync::atomic::{self, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// worker 找不到任务时, 睡下去之前先重试的次数
+const SPIN_ROUNDS: usize = 16;
+
+/// 队列满了之后, 新提交的任务怎么处理
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RejectionPolicy {
+    /// 阻塞提交任务的线程, 直到队列有空位
+    #[default]
+    Block,
+    /// 直接拒绝, `try_execute` 返回 `ExecuteError::QueueFull`
+    Reject,
+    /// 丢掉队列里最老的任务, 给新任务腾位置
+    ///
+    /// 用了优先级或者具名队列时, 丢掉的是优先级最低的车道里最老的任务.
+    DropOldest,
+    /// 在提交任务的线程上直接执行, 提交方自然就慢下来了
+    CallerRuns,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecuteError {
+    /// 队列已满且拒绝策略为 `RejectionPolicy::Reject`
+    QueueFull,
+    /// `try_execute_in` 指定的具名队列没有在 `ThreadPoolBuilder::task_queue` 里配置过
+    UnknownQueue(String),
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::QueueFull => write!(f, "job queue is full"),
+            ExecuteError::UnknownQueue(name) => write!(f, "unknown task queue `{}`", name),
+        }
+    }
+}
+
+impl std::error::Error for ExecuteError {}
+
+// 队列里的消息, 带上入队时间用来统计排队等待的时长
+pub(crate) struct Queued {
+    pub(crate) message: Message,
+    pub(crate) queued_at: Instant,
+}
+
+impl Queued {
+    fn new(message: Message) -> Queued {
+        Queued {
+            message,
+            queued_at: Instant::now(),
+        }
+    }
+}
+
+/// 任务被接受之后是怎么处理的
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Pushed {
+    Queued,
+    /// 放进队列了, 但为此丢掉了一个更早的任务
+    DroppedOldest,
+    /// 队列满了, 已经在提交方的线程上执行完了
+    RanOnCaller,
+}
+
+// 每个 worker 线程自己的本地队列, 放在线程局部变量里, 这样 worker 里的任务再提交任务时也能直接放进去
+struct Local {
+    // 用队列的地址区分是哪个线程池的 worker
+    owner: usize,
+    deque: Deque<Queued>,
+}
+
+thread_local! {
+    static LOCAL: RefCell<Option<Local>> = const { RefCell::new(None) };
+}
+
+// 原来所有 worker 都抢同一个 Mutex<Receiver>, 取任务是串行的
+// 现在外部提交的任务先进全局的 injector, worker 一次从里面拿走一批放进自己的本地队列,
+// 本地队列空了再去偷别的 worker 的任务, 大部分时候 worker 之间不需要争同一把锁
+pub(crate) struct JobQueue {
+    injector: Injector<Queued>,
+    stealers: RwLock<Vec<(usize, Stealer<Queued>)>>,
+    // 队列里 NewJob 的数量(包括各个本地队列里的), Terminate 不占容量
+    jobs: AtomicUsize,
+    // 正在等任务的 worker 数, 线程池靠它判断要不要扩容, 提交任务时也靠它判断要不要唤醒 worker
+    idle: AtomicUsize,
+    // 只用来让没活干的 worker 睡眠, 取任务本身不需要这把锁
+    sleep: Mutex<()>,
+    not_empty: Condvar,
+    // 被 Block 策略挡住的提交方
+    blocked: AtomicUsize,
+    not_full: Condvar,
+    capacity: Option<usize>,
+    policy: RejectionPolicy,
+    rejected: AtomicUsize,
+}
+
+impl JobQueue {
+    pub(crate) fn new(capacity: Option<usize>, policy: RejectionPolicy) -> JobQueue {
+        JobQueue {
+            injector: Injector::new(),
+            stealers: RwLock::new(Vec::new()),
+            jobs: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            sleep: Mutex::new(()),
+            not_empty: Condvar::new(),
+            blocked: AtomicUsize::new(0),
+            not_full: Condvar::new(),
+            capacity,
+            policy,
+            rejected: AtomicUsize::new(0),
+        }
+    }
+
+    fn id(&self) -> usize {
+        self as *const JobQueue as usize
+    }
+
+    /// 把当前线程注册为 worker, 之后它的本地队列可以被其他 worker 偷
+    pub(crate) fn attach(&self, worker_id: usize) {
+        let deque = Deque::new_fifo();
+
+        self.stealers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((worker_id, deque.stealer()));
+
+        LOCAL.with(|local| {
+            *local.borrow_mut() = Some(Local {
+                owner: self.id(),
+                deque,
+            })
+        });
+    }
+
+    /// worker 线程退出(包括 panic)时调用, 本地队列里剩下的任务还回全局队列
+    pub(crate) fn detach(&self, worker_id: usize) {
+        self.stealers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(id, _)| *id != worker_id);
+
+        if let Some(local) = LOCAL.with(|local| local.borrow_mut().take()) {
+            while let Some(queued) = local.deque.pop() {
+                self.injector.push(queued);
+            }
+            self.wake_one();
+        }
+    }
+
+    pub(crate) fn push(&self, job: Job) -> Result<Pushed, ExecuteError> {
+        // worker 里提交的任务直接进自己的本地队列, 不受容量限制,
+        // 否则所有 worker 都可能阻塞在等自己腾出空位上
+        let job = match self.push_local(job) {
+            Ok(()) => {
+                self.jobs.fetch_add(1, Ordering::SeqCst);
+                self.wake_one();
+                return Ok(Pushed::Queued);
+            }
+            Err(job) => job,
+        };
+
+        let mut pushed = Pushed::Queued;
+
+        if let Some(capacity) = self.capacity {
+            if !self.reserve(capacity) {
+                match self.policy {
+                    RejectionPolicy::Block => self.wait_for_space(capacity),
+                    RejectionPolicy::Reject => {
+                        self.rejected.fetch_add(1, Ordering::SeqCst);
+                        return Err(ExecuteError::QueueFull);
+                    }
+                    RejectionPolicy::DropOldest => {
+                        if self.drop_oldest() {
+                            pushed = Pushed::DroppedOldest;
+                        }
+                        self.jobs.fetch_add(1, Ordering::SeqCst);
+                    }
+                    RejectionPolicy::CallerRuns => {
+                        job();
+                        return Ok(Pushed::RanOnCaller);
+                    }
+                }
+            }
+        } else {
+            self.jobs.fetch_add(1, Ordering::SeqCst);
+        }
+
+        self.injector.push(Queued::new(Message::NewJob(job)));
+        self.wake_one();
+
+        Ok(pushed)
+    }
+
+    fn push_local(&self, job: Job) -> Result<(), Job> {
+        LOCAL.with(|local| match &*local.borrow() {
+            Some(local) if local.owner == self.id() => {
+                local.deque.push(Queued::new(Message::NewJob(job)));
+                Ok(())
+            }
+            _ => Err(job),
+        })
+    }
+
+    // 队列没满时占一个位置
+    fn reserve(&self, capacity: usize) -> bool {
+        self.jobs
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |jobs| {
+                (jobs < capacity).then_some(jobs + 1)
+            })
+            .is_ok()
+    }
+
+    fn wait_for_space(&self, capacity: usize) {
+        self.blocked.fetch_add(1, Ordering::SeqCst);
+
+        let mut guard = lock(&self.sleep);
+        while !self.reserve(capacity) {
+            // 带超时只是兜底, 正常情况下 worker 取走任务时会唤醒这里
+            guard = self
+                .not_full
+                .wait_timeout(guard, Duration::from_millis(10))
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        drop(guard);
+
+        self.blocked.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // 返回是否真的丢掉了一个任务
+    fn drop_oldest(&self) -> bool {
+        // 关闭时排在前面的 Terminate 不是任务, 先放到一边, 只丢它们后面最老的任务
+        let mut skipped = Vec::new();
+        let dropped = loop {
+            match self.injector.steal() {
+                Steal::Success(Queued {
+                    message: Message::NewJob(_),
+                    ..
+                }) => {
+                    self.jobs.fetch_sub(1, Ordering::SeqCst);
+                    self.rejected.fetch_add(1, Ordering::SeqCst);
+                    break true;
+                }
+                Steal::Success(queued) => skipped.push(queued),
+                Steal::Retry => continue,
+                // 全局队列里已经没有了, 剩下的都在 worker 的本地队列里, 只好超出容量放进去
+                Steal::Empty => break false,
+            }
+        };
+
+        if !skipped.is_empty() {
+            // injector 只能从后面放, 把剩下的也取出来一起按原来的顺序放回去,
+            // 这样 Terminate 不会排到更新的任务后面
+            loop {
+                match self.injector.steal() {
+                    Steal::Success(queued) => skipped.push(queued),
+                    Steal::Retry => continue,
+                    Steal::Empty => break,
+                }
+            }
+            for queued in skipped {
+                self.injector.push(queued);
+                // 取出来的这段时间里可能有 worker 以为队列空了睡下去了
+                self.wake_one();
+            }
+        }
+
+        dropped
+    }
+
+    // Terminate 必须能放进去, 不受容量限制
+    pub(crate) fn push_terminate(&self) {
+        self.injector.push(Queued::new(Message::Terminate));
+        self.wake_one();
+    }
+
+    fn wake_one(&self) {
+        // 没有 worker 在睡就不用去拿锁, 这是分发任务不再串行的关键
+        // worker 在检查队列之前就已经把 idle 加上了, 所以这里读到 0 时它一定能看到刚放进去的任务
+        // 两边各有一个 SeqCst 屏障, 保证"放任务再读 idle"和"加 idle 再查队列"不会被重排
+        atomic::fence(Ordering::SeqCst);
+        if self.idle.load(Ordering::SeqCst) > 0 {
+            let _guard = lock(&self.sleep);
+            self.not_empty.notify_one();
+        }
+    }
+
+    pub(crate) fn pop(&self) -> Queued {
+        loop {
+            if let Some(queued) = self.pop_timeout(Duration::from_secs(3600)) {
+                return queued;
+            }
+        }
+    }
+
+    /// 最多等待 `timeout`, 期间一直没有任务就返回 None
+    pub(crate) fn pop_timeout(&self, timeout: Duration) -> Option<Queued> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            // 睡眠和唤醒都要走系统调用, 先让出几次 CPU 再看看, 任务密集时基本不用真的睡下去
+            for _ in 0..SPIN_ROUNDS {
+                if let Some(message) = self.find_message() {
+                    return Some(self.taken(message));
+                }
+                thread::yield_now();
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+
+            let guard = lock(&self.sleep);
+            self.idle.fetch_add(1, Ordering::SeqCst);
+            atomic::fence(Ordering::SeqCst);
+
+            // 拿着锁再确认一次, 提交方 notify 前也要拿这把锁, 这样就不会漏掉唤醒
+            if self.is_empty() {
+                let _ = self
+                    .not_empty
+                    .wait_timeout(guard, remaining)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+
+            self.idle.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    // 先取自己的本地队列, 再从全局队列拿一批, 最后去偷别的 worker 的
+    fn find_message(&self) -> Option<Queued> {
+        LOCAL.with(|local| {
+            let local = local.borrow();
+            let local = local.as_ref().filter(|local| local.owner == self.id())?;
+
+            local.deque.pop().or_else(|| {
+                iter::repeat_with(|| {
+                    self.injector
+                        .steal_batch_and_pop(&local.deque)
+                        .or_else(|| self.steal_from_others(&local.deque))
+                })
+                .find(|steal| !steal.is_retry())
+                .and_then(|steal| steal.success())
+            })
+        })
+    }
+
+    fn steal_from_others(&self, deque: &Deque<Queued>) -> Steal<Queued> {
+        self.stealers
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(_, stealer)| stealer.steal_batch_and_pop(deque))
+            .collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.injector.is_empty()
+            && self
+                .stealers
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .all(|(_, stealer)| stealer.is_empty())
+    }
+
+    fn taken(&self, queued: Queued) -> Queued {
+        if let Message::NewJob(_) = queued.message {
+            self.jobs.fetch_sub(1, Ordering::SeqCst);
+
+            if self.blocked.load(Ordering::SeqCst) > 0 {
+                let _guard = lock(&self.sleep);
+                self.not_full.notify_one();
+            }
+        }
+
+        queued
+    }
+
+    /// 排队等待执行的任务数
+    pub(crate) fn len(&self) -> usize {
+        self.jobs.load(Ordering::SeqCst)
+    }
+
+    /// 空闲等待任务的 worker 数
+    pub(crate) fn idle(&self) -> usize {
+        self.idle.load(Ordering::SeqCst)
+    }
+
+    /// 被拒绝或被挤掉的任务数
+    pub(crate) fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn job(order: &Arc<Mutex<Vec<char>>>, c: char) -> Job {
+        let order = Arc::clone(order);
+        Box::new(move || lock(&order).push(c))
+    }
+
+    #[test]
+    fn drop_oldest_skips_terminates_without_reordering_them() {
+        let queue = JobQueue::new(Some(2), RejectionPolicy::DropOldest);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        // 关闭的过程中, 排在前面的 Terminate 之后还有任务
+        queue.push_terminate();
+        queue.push(job(&order, 'a')).unwrap();
+        queue.push(job(&order, 'b')).unwrap();
+        assert_eq!(Ok(Pushed::DroppedOldest), queue.push(job(&order, 'c')));
+        assert_eq!(2, queue.len());
+        assert_eq!(1, queue.rejected());
+
+        queue.attach(0);
+        let mut messages = Vec::new();
+        while let Some(queued) = queue.pop_timeout(Duration::ZERO) {
+            match queued.message {
+                Message::NewJob(job) => {
+                    job();
+                    messages.push('j');
+                }
+                Message::Terminate => messages.push('t'),
+            }
+        }
+        queue.detach(0);
+
+        assert_eq!(vec!['t', 'j', 'j'], messages);
+        assert_eq!(vec!['b', 'c'], *lock(&order));
+    }
+}
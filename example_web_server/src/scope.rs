@@ -0,0 +1,239 @@
+use crate::{lock, ThreadPool};
+use std::any::Any;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+/// `ThreadPool::scope` 里用来提交任务的作用域, 和 `std::thread::Scope` 类似
+///
+/// 通过它提交的任务可以借用 `'env` 里的数据, 不需要 `'static`.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    data: Arc<ScopeData>,
+    // 和 std::thread::Scope 一样, 让两个生命周期都是不变的(invariant), 防止被编译器缩短或延长
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+struct ScopeData {
+    pending: Mutex<usize>,
+    all_done: Condvar,
+    // 只保留第一个 panic, 在 scope 结束时重新抛出
+    panic: Mutex<Option<Box<dyn Any + Send + 'static>>>,
+    // 有任务被拒绝策略丢掉了, 没有执行
+    dropped: AtomicBool,
+}
+
+impl ScopeData {
+    fn wait_all(&self) {
+        let mut pending = lock(&self.pending);
+        while *pending > 0 {
+            pending = self
+                .all_done
+                .wait(pending)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+// 作用域里的一个任务, 不管是执行完还是没执行就被丢掉, drop 时都会把 pending 减一
+struct ScopedJob {
+    data: Arc<ScopeData>,
+    f: Option<Box<dyn FnOnce() + Send + 'static>>,
+}
+
+impl ScopedJob {
+    fn run(mut self) {
+        if let Some(f) = self.f.take() {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                lock(&self.data.panic).get_or_insert(payload);
+            }
+        }
+    }
+}
+
+impl Drop for ScopedJob {
+    fn drop(&mut self) {
+        // 先释放闭包, 它借用的数据在 pending 归零之后就可能失效了
+        if self.f.take().is_some() {
+            self.data.dropped.store(true, Ordering::SeqCst);
+        }
+
+        let mut pending = lock(&self.data.pending);
+        *pending -= 1;
+        if *pending == 0 {
+            self.data.all_done.notify_all();
+        }
+    }
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// 在线程池上执行一个可以借用作用域外数据的任务
+    ///
+    /// 队列已满且任务被拒绝时, 任务会直接在当前线程上执行.
+    pub fn spawn<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *lock(&self.data.pending) += 1;
+
+        let f: Box<dyn FnOnce() + Send + 'scope> = Box::new(f);
+        // SAFETY: `ThreadPool::scope` 在返回之前会等到所有 ScopedJob 被执行或被丢弃,
+        // 所以闭包借用的 'scope 数据在它可能被用到的期间一直有效
+        let f: Box<dyn FnOnce() + Send + 'static> = unsafe { mem::transmute(f) };
+
+        let job = Arc::new(Mutex::new(Some(ScopedJob {
+            data: Arc::clone(&self.data),
+            f: Some(f),
+        })));
+
+        let queued = Arc::clone(&job);
+        let result = self.pool.try_execute(move || {
+            if let Some(job) = lock(&queued).take() {
+                job.run();
+            }
+        });
+
+        // 被拒绝的话闭包已经被丢掉了, 任务还留在这里, 就地执行
+        if result.is_err() {
+            if let Some(job) = lock(&job).take() {
+                job.run();
+            }
+        }
+    }
+}
+
+impl ThreadPool {
+    /// Run jobs that may borrow from the caller's stack, like `std::thread::scope`.
+    ///
+    /// All jobs spawned on the scope are run by the pool's workers, and `scope`
+    /// blocks until every one of them has finished. If any job panicked, the
+    /// panic is resumed here once all jobs are done.
+    ///
+    /// Calling `scope` from inside a job of the same pool blocks that worker,
+    /// so the pool needs at least one other worker to make progress.
+    ///
+    /// ```
+    /// let pool = web_server::ThreadPool::new(4).unwrap();
+    /// let numbers: Vec<u64> = (1..=100).collect();
+    /// let mut sums = vec![0; 4];
+    ///
+    /// pool.scope(|s| {
+    ///     for (chunk, sum) in numbers.chunks(25).zip(sums.iter_mut()) {
+    ///         s.spawn(move || *sum = chunk.iter().sum());
+    ///     }
+    /// });
+    ///
+    /// assert_eq!(5050, sums.iter().sum::<u64>());
+    /// ```
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope {
+            pool: self,
+            data: Arc::new(ScopeData {
+                pending: Mutex::new(0),
+                all_done: Condvar::new(),
+                panic: Mutex::new(None),
+                dropped: AtomicBool::new(false),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+
+        // f 自己 panic 了也要先等已经提交的任务结束, 否则它们借用的数据会失效
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+
+        scope.data.wait_all();
+
+        let result = match result {
+            Ok(result) => result,
+            Err(payload) => panic::resume_unwind(payload),
+        };
+
+        if let Some(payload) = lock(&scope.data.panic).take() {
+            panic::resume_unwind(payload);
+        }
+
+        if scope.data.dropped.load(Ordering::SeqCst) {
+            panic!("a scoped job was dropped by the rejection policy before it could run");
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{RejectionPolicy, ThreadPool};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+
+    #[test]
+    fn jobs_can_borrow_and_mutate_local_data() {
+        let pool = ThreadPool::new(3).unwrap();
+        let words = ["hello", "scoped", "jobs"];
+        let mut lengths = vec![0; words.len()];
+
+        pool.scope(|s| {
+            for (word, length) in words.iter().zip(lengths.iter_mut()) {
+                s.spawn(move || *length = word.len());
+            }
+        });
+
+        assert_eq!(vec![5, 6, 4], lengths);
+    }
+
+    #[test]
+    fn resumes_job_panic_after_all_jobs_finish() {
+        let pool = ThreadPool::new(2).unwrap();
+        let finished = AtomicUsize::new(0);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|| panic!("scoped job failed"));
+                s.spawn(|| {
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+            })
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(1, finished.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn runs_rejected_jobs_on_caller_thread() {
+        let pool = ThreadPool::builder()
+            .size(1)
+            .queue_capacity(1)
+            .rejection_policy(RejectionPolicy::Reject)
+            .build()
+            .unwrap();
+        let (release, blocked) = mpsc::channel::<()>();
+        let (started, wait_started) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = blocked.recv();
+        });
+        wait_started.recv().unwrap();
+
+        let counter = AtomicUsize::new(0);
+        pool.scope(|s| {
+            // 第一个进队列, 第二个被拒绝后在当前线程执行
+            s.spawn(|| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+            s.spawn(|| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+            assert_eq!(1, counter.load(Ordering::SeqCst));
+            release.send(()).unwrap();
+        });
+
+        assert_eq!(2, counter.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,424 @@
+use crate::access_log;
+use crate::compression;
+use crate::http::{self, Request, Response};
+use crate::shutdown::ShutdownSignal;
+use crate::site;
+use crate::websocket;
+use crate::{ShutdownReport, ThreadPool};
+use std::io::prelude::*; // 用于获取读写流所需的特定 trait
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::{fs, thread};
+
+/*
+单线程 server 会依次处理每一个请求,
+意味着它在完成第一个连接的处理之前不会处理第二个连接.
+如果 server 正接收越来越多的请求, 这类串行操作会使性能越来越差
+*/
+
+pub fn single_thread_mode(listener: TcpListener) {
+    // incoming 方法返回一个迭代器, 提供 stream 流
+    // 这个遍历叫做连接尝试(connection attempts), 因为有很多原因就挂了
+    for stream in listener.incoming() {
+        let stream = stream.unwrap();
+        let client = stream.peer_addr().ok();
+        // 单线程模式下一个不发数据的客户端会卡住所有人, 超时尤其重要
+        set_timeouts(&stream, site::current().request_limits());
+
+        handle_connection(stream, client);
+
+        // println!("Connection established!");
+    }
+}
+
+// 每个连接一个线程, 连接多了线程数没有上限
+pub fn finite_number_of_multi_threads(listener: TcpListener) {
+    for stream in listener.incoming() {
+        let stream = stream.unwrap();
+
+        thread::spawn(|| {
+            let client = stream.peer_addr().ok();
+            set_timeouts(&stream, site::current().request_limits());
+            handle_connection(stream, client);
+        });
+    }
+}
+
+/// 用线程池处理连接, 收到关闭信号后停止 accept, 等进行中的请求处理完再返回
+pub fn thread_poll(
+    listener: TcpListener,
+    poll: ThreadPool,
+    shutdown: &ShutdownSignal,
+    timeout: Duration,
+) -> ShutdownReport {
+    serve(listener, &poll, shutdown, |stream| {
+        let client = stream.peer_addr().ok();
+        handle_connection(stream, client)
+    });
+
+    poll.shutdown(timeout)
+}
+
+/// 线程池模式的 accept 循环, 每个连接交给 `handler` 在 `poll` 上处理, 收到关闭信号后返回
+///
+/// 不接管线程池, 这样 HTTP 和 HTTPS 两个 listener 可以共用同一个线程池.
+pub fn serve<F>(listener: TcpListener, poll: &ThreadPool, shutdown: &ShutdownSignal, handler: F)
+where
+    F: Fn(TcpStream) + Clone + Send + 'static,
+{
+    // incoming() 会一直阻塞在 accept 上, 没机会检查关闭标记, 所以改成非阻塞再轮询
+    listener.set_nonblocking(true).unwrap();
+
+    while !shutdown.is_triggered() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // accept 出来的连接会继承非阻塞模式, 这里改回阻塞
+                stream.set_nonblocking(false).unwrap();
+                set_timeouts(&stream, site::current().request_limits());
+                let handler = handler.clone();
+                poll.execute(move || handler(stream));
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(e) => eprintln!("Failed to accept connection: {}", e),
+        }
+    }
+
+    // 先关掉 listener, 新连接会直接被拒绝
+    drop(listener);
+}
+
+/// 设置连接的读写超时, 不发数据或者不收数据的客户端不会一直占着线程
+pub fn set_timeouts(stream: &TcpStream, limits: &http::Limits) {
+    // 只有超时为 0 时才会失败, 配置校验时已经排除了
+    let _ = stream.set_read_timeout(Some(limits.read_timeout));
+    let _ = stream.set_write_timeout(Some(limits.write_timeout));
+}
+
+/// `handle_connection` 能处理的连接, 保持连接时要能临时改掉读超时
+pub trait Connection: Read + Write {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, Some(timeout))
+    }
+}
+
+impl<C: Connection + ?Sized> Connection for &mut C {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+// 在保持的连接上等下一个请求的第一批数据, 客户端没再发请求或者断开了时返回 false
+//
+// 空闲的连接也占着一个 worker, 所以只等 `keep_alive_timeout`, 收到数据后换回 `read_timeout`.
+fn wait_for_request<S: Connection>(stream: &mut S, buffer: &mut Vec<u8>) -> bool {
+    // 客户端连着发的请求(pipelining)已经在 buffer 里了
+    if !buffer.is_empty() {
+        return true;
+    }
+
+    let site = site::current();
+    let limits = site.request_limits();
+    let mut chunk = [0; 1024];
+    let _ = stream.set_read_timeout(limits.keep_alive_timeout);
+    let read = loop {
+        match stream.read(&mut chunk) {
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            read => break read,
+        }
+    };
+    let _ = stream.set_read_timeout(limits.read_timeout);
+
+    match read {
+        Ok(n) if n > 0 => {
+            buffer.extend_from_slice(&chunk[..n]);
+            true
+        }
+        _ => false,
+    }
+}
+
+// 按站点的限制读一个请求, 失败时回错误响应(连接还在的话)并返回 None
+fn read_request<S: Read + Write>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    client: Option<SocketAddr>,
+) -> Option<Request> {
+    let started = Instant::now();
+
+    match http::read_next_request(stream, buffer, site::current().request_limits()) {
+        Ok(request) => Some(request),
+        Err(e) => {
+            if let Some(response) = e.response() {
+                // 对端可能已经不在了, 写失败也没关系
+                let _ = response.write_to(stream);
+                log_response(client, b"", &response, started);
+            }
+            None
+        }
+    }
+}
+
+// 一般读取是不可变的, 但 stream 是可变的, 这里得用 mut
+// 泛型是为了 TLS 连接也能用, 它同样实现了 Read 和 Write
+// client 是对端地址, 用来记访问日志和传给 CGI 脚本
+// HTTP/1.1 的连接默认保持, 一直处理到客户端要求关闭, 空闲超时或者到了 max_keep_alive_requests
+pub fn handle_connection<S: Connection>(mut stream: S, client: Option<SocketAddr>) {
+    let max_requests = site::current().request_limits().max_keep_alive_requests;
+    let mut buffer = Vec::new();
+
+    for served in 1..=max_requests {
+        // 空闲超时只是不用这个连接了, 不回 408
+        if served > 1 && !wait_for_request(&mut stream, &mut buffer) {
+            return;
+        }
+        // 请求头, body 的大小和读的总时间都有上限, 超了就回 4xx, 连接断了就直接返回
+        let Some(request) = read_request(&mut stream, &mut buffer, client) else {
+            return;
+        };
+        let keep_alive = request.keep_alive() && served < max_requests;
+
+        match handle_request(&mut stream, client, request, keep_alive) {
+            Some(()) if keep_alive => continue,
+            _ => return,
+        }
+    }
+}
+
+// 处理一个请求并写回响应, 连接不能再用了(写失败或者升级成了 WebSocket)时返回 None
+fn handle_request<S: Read + Write>(
+    stream: &mut S,
+    client: Option<SocketAddr>,
+    request: Request,
+    keep_alive: bool,
+) -> Option<()> {
+    let started = Instant::now();
+
+    if serve_websocket(stream, client, &request, started) {
+        return None;
+    }
+
+    let mut response = respond_to(&request, client);
+    if !keep_alive {
+        response.set_header("Connection", "close");
+    } else if request.version == "HTTP/1.0" {
+        // HTTP/1.0 默认不保持连接, 要明确告诉客户端
+        response.set_header("Connection", "keep-alive");
+    }
+
+    // 客户端在我们写响应之前断开或者一直不收, 都不是 server 的错误, 不让 worker panic
+    response.write_to(stream).ok()?;
+    log_response(client, request.raw(), &response, started);
+    Some(())
+}
+
+// 正在处理的 WebSocket 连接数, 见 `Limits::max_websockets`
+static OPEN_WEBSOCKETS: AtomicUsize = AtomicUsize::new(0);
+
+// 占着一个 WebSocket 名额, drop 时(包括处理函数 panic 时)还回去
+struct WebSocketSlot;
+
+impl WebSocketSlot {
+    fn acquire(max: usize) -> Option<WebSocketSlot> {
+        OPEN_WEBSOCKETS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < max).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| WebSocketSlot)
+    }
+}
+
+impl Drop for WebSocketSlot {
+    fn drop(&mut self) {
+        OPEN_WEBSOCKETS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// 请求是站点上注册过的路径的 WebSocket 升级请求时, 完成握手, 把连接交给处理函数, 返回 true
+///
+/// 处理函数会一直占着这个线程, 直到连接关闭, 之后这个连接就不能再用了.
+/// 同时打开的连接已经有 `max_websockets` 个时回 503, 同样返回 true.
+/// 不是升级请求或者路径没有注册时返回 false, 调用方按普通请求处理.
+pub fn serve_websocket<S: Read + Write>(
+    stream: &mut S,
+    client: Option<SocketAddr>,
+    request: &Request,
+    started: Instant,
+) -> bool {
+    let raw = String::from_utf8_lossy(request.raw());
+    if request.method != "GET" || !websocket::is_upgrade(&raw) {
+        return false;
+    }
+    let Some(handler) = site::current().websocket_handler(request.path()) else {
+        return false;
+    };
+    // 回应 ping 的客户端可以一直不关连接, 不设上限的话几个空闲的标签页就能占满线程池
+    let max = site::current().request_limits().max_websockets;
+    let Some(_slot) = WebSocketSlot::acquire(max) else {
+        let mut response = Response::new("HTTP/1.1 503 SERVICE UNAVAILABLE", Vec::new());
+        response.set_header("Connection", "close");
+        let _ = response.write_to(stream);
+        log_response(client, request.raw(), &response, started);
+        return true;
+    };
+
+    // 握手结果记一条 101(或者 400), 之后的消息不记
+    let stream: &mut dyn websocket::Stream = stream;
+    match websocket::upgrade(stream, &raw) {
+        Ok(socket) => {
+            access_log::record(client, request.raw(), 101, 0, started);
+            handler(socket);
+        }
+        Err(_) => access_log::record(client, request.raw(), 400, 0, started),
+    }
+    true
+}
+
+/// 把一个已经写回客户端的响应记到访问日志里
+pub fn log_response(
+    client: Option<SocketAddr>,
+    request: &[u8],
+    response: &Response,
+    started: Instant,
+) {
+    access_log::record(
+        client,
+        request,
+        response.status_code(),
+        response.body.len() as u64,
+        started,
+    );
+}
+
+/// 把 HTTP 请求重定向到 `https_port` 上的同一个地址
+pub fn redirect_to_https<S: Read + Write>(
+    mut stream: S,
+    client: Option<SocketAddr>,
+    https_port: u16,
+) {
+    let Some(request) = read_request(&mut stream, &mut Vec::new(), client) else {
+        return;
+    };
+    let started = Instant::now();
+
+    let mut response = redirect_response(request.raw(), https_port);
+    // 跳转之后客户端会去连 HTTPS, 这个连接不用保持
+    response.set_header("Connection", "close");
+
+    if response.write_to(&mut stream).is_ok() {
+        log_response(client, request.raw(), &response, started);
+    }
+}
+
+fn redirect_response(request: &[u8], https_port: u16) -> Response {
+    let request = String::from_utf8_lossy(request);
+
+    // 请求行是 "GET /path HTTP/1.1", 取中间的路径
+    let path = request
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or("/");
+    // Host 可能带着 HTTP 的端口, 换成 HTTPS 的
+    let host = http::header_value(&request, "Host")
+        .map(|host| match host.rsplit_once(':') {
+            // 不带端口的 IPv6 地址里也有冒号, 比如 [::1]
+            Some((name, port)) if !port.ends_with(']') => name,
+            _ => host,
+        })
+        .unwrap_or("localhost");
+
+    let location = match https_port {
+        443 => format!("https://{}{}", host, path),
+        port => format!("https://{}:{}{}", host, port, path),
+    };
+
+    let mut response = Response::new("HTTP/1.1 301 MOVED PERMANENTLY", Vec::new());
+    response.set_header("Location", &location);
+    response
+}
+
+/// 和 `respond` 一样, 但先看请求是不是要交给 CGI 或 FastCGI 处理
+///
+/// 脚本要用到请求的 body 和客户端地址, 所以需要解析好的请求.
+pub fn respond_to(request: &Request, client: Option<SocketAddr>) -> Response {
+    match site::current().run_script(request, client) {
+        Some(response) => {
+            compression::negotiate_response(response, request.header("Accept-Encoding"))
+        }
+        None => respond(request.raw()),
+    }
+}
+
+/// 根据请求生成响应, 四种 server 模式共用, 路由见 `site::current()`
+pub fn respond(request: &[u8]) -> Response {
+    // 请求由调用方写到访问日志里(见 log_response), 这里只负责生成响应
+    // 用 from_utf8_lossy 是因为数据中可能有一些非 UTF-8 的序列, 这种被转换成 �
+    let request = String::from_utf8_lossy(request);
+    let site = site::current();
+
+    // 请求行是 "GET /path?query HTTP/1.1", 查询参数不参与路由
+    let mut request_line = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    let resolved = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(target)) => {
+            site.resolve(target.split(['?', '#']).next().unwrap_or_default())
+        }
+        _ => None,
+    };
+
+    // 静态目录里不存在的文件同样是 404
+    let found = resolved.and_then(|resolved| {
+        thread::sleep(resolved.delay);
+        let file = fs::read(&resolved.file).ok()?;
+        Some((file, resolved.content_type))
+    });
+    let response = match found {
+        Some((file, content_type)) => {
+            let mut response = Response::new("HTTP/1.1 200 OK", file);
+            response.set_header("Content-Type", content_type);
+            response
+        }
+        None => {
+            let file = site
+                .not_found_file()
+                .and_then(|file| fs::read(file).ok())
+                .unwrap_or_default();
+            let mut response = Response::new("HTTP/1.1 404 NOT FOUND", file);
+            response.set_header("Content-Type", "text/html");
+            response
+        }
+    };
+
+    // 客户端声明支持 gzip 或 br 时, 对文本类的 body 进行压缩
+    compression::negotiate_response(response, http::header_value(&request, "Accept-Encoding"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirects_to_same_host_and_path_on_https_port() {
+        let request = b"GET /api/posts?page=2 HTTP/1.1\r\nHost: localhost:7878\r\n\r\n";
+
+        let response = redirect_response(request, 7879);
+
+        assert_eq!("HTTP/1.1 301 MOVED PERMANENTLY", response.status_line);
+        assert_eq!(
+            Some("https://localhost:7879/api/posts?page=2"),
+            response.header("Location")
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// 收到 SIGINT(Ctrl+C) 或 SIGTERM 后会被置为 true 的标记
+///
+/// 信号处理函数里能安全做的事情很少, 所以这里只设置一个原子标记, 由 accept 循环自己去检查.
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    flag: Arc<AtomicBool>,
+}
+
+impl ShutdownSignal {
+    pub fn new() -> ShutdownSignal {
+        ShutdownSignal {
+            flag: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// 注册 SIGINT 和 SIGTERM, 收到后只置位标记, 不再直接结束进程
+    pub fn register() -> io::Result<ShutdownSignal> {
+        let signal = ShutdownSignal::new();
+
+        for sig in [SIGINT, SIGTERM] {
+            signal_hook::flag::register(sig, Arc::clone(&signal.flag))?;
+        }
+
+        Ok(signal)
+    }
+
+    /// 手动触发关闭, 和收到信号的效果一样
+    pub fn trigger(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for ShutdownSignal {
+    fn default() -> Self {
+        ShutdownSignal::new()
+    }
+}
+
+/// 收到 SIGHUP 后会被置为 true 的标记, 用来通知重新加载配置
+#[derive(Clone, Default)]
+pub struct ReloadSignal {
+    flag: Arc<AtomicBool>,
+}
+
+impl ReloadSignal {
+    pub fn new() -> ReloadSignal {
+        ReloadSignal::default()
+    }
+
+    pub fn register() -> io::Result<ReloadSignal> {
+        let signal = ReloadSignal::new();
+        signal_hook::flag::register(SIGHUP, Arc::clone(&signal.flag))?;
+
+        Ok(signal)
+    }
+
+    /// 手动触发重新加载, 和收到 SIGHUP 的效果一样
+    pub fn trigger(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    /// 是否收到过 SIGHUP, 同时清掉标记, 连着收到的几次只算一次
+    pub fn take(&self) -> bool {
+        self.flag.swap(false, Ordering::SeqCst)
+    }
+}
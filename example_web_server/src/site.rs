@@ -0,0 +1,283 @@
+use crate::cgi::Cgi;
+use crate::fastcgi::FastCgi;
+use crate::http::{Limits, Request, Response};
+use crate::websocket::{self, Handler};
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+
+static SITE: OnceLock<RwLock<Arc<Site>>> = OnceLock::new();
+
+/// `server::respond` 用的路由表: 精确路径映射到文件, 路径前缀映射到静态目录
+///
+/// 也可以把路径前缀交给 CGI 脚本或者 FastCGI 应用处理, 见 `server::respond_to`,
+/// 或者把某个路径上的 WebSocket 连接交给处理函数, 见 `server::serve_websocket`.
+///
+/// 默认的路由和最早写死在 `respond` 里的一样:
+/// `/` 是 index.html, `/api/posts` 是 data.json(故意慢 10 秒), 其他的是 404.html,
+/// 另外 `/ws` 是回显的 WebSocket.
+///
+/// ```
+/// use std::time::Duration;
+/// use web_server::site::{self, Site};
+/// use web_server::websocket;
+///
+/// let site = Site::new()
+///     .route("/", "index.html")
+///     .delayed_route("/slow", "data.json", Duration::from_secs(1))
+///     .static_dir("/assets", "public")
+///     .websocket("/echo", websocket::echo)
+///     .not_found("404.html");
+/// site::install(site);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Site {
+    routes: Vec<Route>,
+    statics: Vec<StaticDir>,
+    not_found: Option<PathBuf>,
+    limits: Limits,
+    cgi: Vec<Cgi>,
+    fastcgi: Vec<FastCgi>,
+    websockets: Vec<(String, Handler)>,
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    path: String,
+    file: PathBuf,
+    delay: Duration,
+}
+
+#[derive(Debug, Clone)]
+struct StaticDir {
+    prefix: String,
+    dir: PathBuf,
+}
+
+/// 一个请求路径对应的文件
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolved {
+    pub file: PathBuf,
+    pub content_type: &'static str,
+    /// 响应前先等这么久, 用来模拟耗时的请求
+    pub delay: Duration,
+}
+
+impl Site {
+    /// 没有任何路由的空站点, 所有请求都是 404
+    pub fn new() -> Site {
+        Site {
+            routes: Vec::new(),
+            statics: Vec::new(),
+            not_found: None,
+            limits: Limits::default(),
+            cgi: Vec::new(),
+            fastcgi: Vec::new(),
+            websockets: Vec::new(),
+        }
+    }
+
+    /// 把 `path` 映射到 `file`, 同一个路径后加的覆盖先加的
+    pub fn route(self, path: &str, file: impl Into<PathBuf>) -> Site {
+        self.delayed_route(path, file, Duration::ZERO)
+    }
+
+    /// 和 `route` 一样, 但响应前先等 `delay`
+    pub fn delayed_route(mut self, path: &str, file: impl Into<PathBuf>, delay: Duration) -> Site {
+        self.routes.retain(|route| route.path != path);
+        self.routes.push(Route {
+            path: path.to_string(),
+            file: file.into(),
+            delay,
+        });
+        self
+    }
+
+    /// 把以 `prefix` 开头的路径映射到 `dir` 下的文件, 多个前缀都匹配时用最长的那个
+    pub fn static_dir(mut self, prefix: &str, dir: impl Into<PathBuf>) -> Site {
+        self.statics.push(StaticDir {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            dir: dir.into(),
+        });
+        self.statics
+            .sort_by_key(|root| std::cmp::Reverse(root.prefix.len()));
+        self
+    }
+
+    /// 404 响应的 body
+    pub fn not_found(mut self, file: impl Into<PathBuf>) -> Site {
+        self.not_found = Some(file.into());
+        self
+    }
+
+    /// 请求的大小限制和超时, 默认是 `Limits::default()`
+    pub fn limits(mut self, limits: Limits) -> Site {
+        self.limits = limits;
+        self
+    }
+
+    /// 用 CGI 脚本处理 `Cgi` 前缀下的请求, 优先于静态文件
+    pub fn cgi(mut self, cgi: Cgi) -> Site {
+        self.cgi.push(cgi);
+        self
+    }
+
+    /// 用 FastCGI 应用处理 `FastCgi` 前缀下的请求, 优先于静态文件
+    pub fn fastcgi(mut self, fastcgi: FastCgi) -> Site {
+        self.fastcgi.push(fastcgi);
+        self
+    }
+
+    /// 把 `path` 上的 WebSocket 连接交给 `handler`, 同一个路径后加的覆盖先加的
+    pub fn websocket(mut self, path: &str, handler: Handler) -> Site {
+        self.websockets.retain(|(existing, _)| existing != path);
+        self.websockets.push((path.to_string(), handler));
+        self
+    }
+
+    pub fn request_limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn not_found_file(&self) -> Option<&Path> {
+        self.not_found.as_deref()
+    }
+
+    /// `path`(不带查询参数)上注册的 WebSocket 处理函数
+    pub fn websocket_handler(&self, path: &str) -> Option<Handler> {
+        self.websockets
+            .iter()
+            .find(|(existing, _)| existing == path)
+            .map(|(_, handler)| *handler)
+    }
+
+    /// 请求路径在 CGI 或 FastCGI 的前缀下时, 运行脚本并返回它的响应
+    ///
+    /// 脚本可能很慢, 调用方要在 worker 线程上调用.
+    pub fn run_script(&self, request: &Request, client: Option<SocketAddr>) -> Option<Response> {
+        self.cgi
+            .iter()
+            .find_map(|cgi| cgi.handle(request, client))
+            .or_else(|| {
+                self.fastcgi
+                    .iter()
+                    .find_map(|fastcgi| fastcgi.handle(request, client))
+            })
+    }
+
+    /// 找到 `path`(不带查询参数)对应的文件, 精确路由优先于静态目录
+    ///
+    /// 静态目录下的文件不一定存在, 调用方读文件失败时按 404 处理.
+    pub fn resolve(&self, path: &str) -> Option<Resolved> {
+        if let Some(route) = self.routes.iter().find(|route| route.path == path) {
+            return Some(Resolved {
+                content_type: content_type(&route.file),
+                file: route.file.clone(),
+                delay: route.delay,
+            });
+        }
+
+        let (root, rest) = self.statics.iter().find_map(|root| {
+            let rest = path.strip_prefix(&root.prefix)?;
+            // /assets 不能匹配 /assetsfoo
+            (rest.is_empty() || rest.starts_with('/')).then_some((root, rest))
+        })?;
+
+        // 不允许用 .. 跳出静态目录
+        let relative = Path::new(rest.trim_start_matches('/'));
+        if relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            return None;
+        }
+
+        let mut file = root.dir.join(relative);
+        if rest.is_empty() || rest.ends_with('/') || file.is_dir() {
+            file.push("index.html");
+        }
+
+        Some(Resolved {
+            content_type: content_type(&file),
+            file,
+            delay: Duration::ZERO,
+        })
+    }
+}
+
+impl Default for Site {
+    fn default() -> Self {
+        Site::new()
+            .route("/", "index.html")
+            // 模拟单线程如果有一个耗时的, 其他的就慢了
+            .delayed_route("/api/posts", "data.json", Duration::from_secs(10))
+            .websocket("/ws", websocket::echo)
+            .not_found("404.html")
+    }
+}
+
+/// 换掉当前的站点配置, 已经在处理的请求继续用旧的
+pub fn install(site: Site) {
+    *slot().write().unwrap() = Arc::new(site);
+}
+
+/// 当前的站点配置, 没有调用过 `install` 时是 `Site::default()`
+pub fn current() -> Arc<Site> {
+    Arc::clone(&slot().read().unwrap())
+}
+
+fn slot() -> &'static RwLock<Arc<Site>> {
+    SITE.get_or_init(|| RwLock::new(Arc::new(Site::default())))
+}
+
+fn content_type(file: &Path) -> &'static str {
+    let extension = file
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" => "application/javascript",
+        "json" => "application/json",
+        "txt" => "text/plain",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_routes_before_static_dirs_and_rejects_parent_dirs() {
+        let site = Site::new()
+            .route("/", "index.html")
+            .static_dir("/", "public")
+            .static_dir("/assets/", "static");
+
+        assert_eq!(Path::new("index.html"), site.resolve("/").unwrap().file);
+
+        let resolved = site.resolve("/assets/css/site.css").unwrap();
+        assert_eq!(Path::new("static/css/site.css"), resolved.file);
+        assert_eq!("text/css", resolved.content_type);
+
+        assert_eq!(
+            Path::new("public/assetsfoo.txt"),
+            site.resolve("/assetsfoo.txt").unwrap().file
+        );
+        assert_eq!(
+            Path::new("static/docs/index.html"),
+            site.resolve("/assets/docs/").unwrap().file
+        );
+        assert_eq!(None, site.resolve("/assets/../Cargo.toml"));
+    }
+}
@@ -0,0 +1,326 @@
+use crate::{lock, Job, Shared, ThreadPool};
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// `execute_after` 和 `execute_every` 返回的句柄, 用来取消还没触发的任务
+#[derive(Debug, Clone)]
+pub struct ScheduledHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl ScheduledHandle {
+    /// 取消任务, 已经交给 worker 执行的那一次不受影响
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+enum Task {
+    Once(Job),
+    Every {
+        f: Arc<dyn Fn() + Send + Sync + 'static>,
+        period: Duration,
+        // 上一次还没执行完时跳过这一次, 避免慢任务在池里越堆越多
+        running: Arc<AtomicBool>,
+    },
+}
+
+struct Entry {
+    at: Instant,
+    // 同一时刻的任务按提交顺序触发
+    seq: u64,
+    cancelled: Arc<AtomicBool>,
+    task: Task,
+}
+
+// BinaryHeap 是最大堆, 反过来比较, 最早到期的排在堆顶
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other
+            .at
+            .cmp(&self.at)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+#[derive(Default)]
+struct TimerState {
+    entries: BinaryHeap<Entry>,
+    next_seq: u64,
+    shutdown: bool,
+}
+
+#[derive(Default)]
+struct TimerInner {
+    state: Mutex<TimerState>,
+    changed: Condvar,
+}
+
+// 定时任务不再占着 worker 睡眠, 而是由一个单独的计时线程在到期时把任务交给线程池
+// 计时线程在第一次调度任务时才创建
+#[derive(Default)]
+pub(crate) struct Timer {
+    inner: Arc<TimerInner>,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Timer {
+    fn schedule(&self, shared: &Arc<Shared>, at: Instant, task: Task) -> ScheduledHandle {
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        {
+            let mut state = lock(&self.inner.state);
+            if state.shutdown {
+                // 线程池已经关闭, 任务永远不会触发
+                cancelled.store(true, Ordering::SeqCst);
+                return ScheduledHandle { cancelled };
+            }
+
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.entries.push(Entry {
+                at,
+                seq,
+                cancelled: Arc::clone(&cancelled),
+                task,
+            });
+        }
+
+        self.ensure_started(shared);
+        self.inner.changed.notify_one();
+
+        ScheduledHandle { cancelled }
+    }
+
+    fn ensure_started(&self, shared: &Arc<Shared>) {
+        let mut thread = lock(&self.thread);
+
+        if thread.is_none() {
+            let inner = Arc::clone(&self.inner);
+            // 用 Weak 避免计时线程让线程池永远无法释放
+            let shared = Arc::downgrade(shared);
+
+            *thread = Some(thread::spawn(move || run(&inner, &shared)));
+        }
+    }
+
+    /// 停止计时线程, 还没到期的任务全部丢弃
+    pub(crate) fn shutdown(&self) {
+        {
+            let mut state = lock(&self.inner.state);
+            state.shutdown = true;
+            for entry in state.entries.drain() {
+                entry.cancelled.store(true, Ordering::SeqCst);
+            }
+        }
+        self.inner.changed.notify_one();
+
+        let thread = lock(&self.thread).take();
+        if let Some(thread) = thread {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(inner: &TimerInner, shared: &Weak<Shared>) {
+    let mut state = lock(&inner.state);
+
+    loop {
+        if state.shutdown {
+            return;
+        }
+
+        let now = Instant::now();
+        let wait = match state.entries.peek() {
+            Some(entry) if entry.at <= now => None,
+            Some(entry) => Some(entry.at - now),
+            // 没有任务时一直等, 有新任务或关闭时会被唤醒
+            None => Some(Duration::from_secs(3600)),
+        };
+
+        if let Some(wait) = wait {
+            state = inner
+                .changed
+                .wait_timeout(state, wait)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+            continue;
+        }
+
+        let entry = state.entries.pop().unwrap();
+        if entry.cancelled.load(Ordering::SeqCst) {
+            continue;
+        }
+
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+
+        match entry.task {
+            Task::Once(job) => {
+                // 提交时可能因为队列满而阻塞, 不能拿着计时器的锁
+                drop(state);
+                let _ = shared.submit(job);
+                state = lock(&inner.state);
+            }
+            Task::Every { f, period, running } => {
+                // 按固定频率触发, 错过的就跳过, 不会一下子补上好几次
+                let mut next = entry.at + period;
+                while next <= now {
+                    next += period;
+                }
+
+                let seq = state.next_seq;
+                state.next_seq += 1;
+                state.entries.push(Entry {
+                    at: next,
+                    seq,
+                    cancelled: Arc::clone(&entry.cancelled),
+                    task: Task::Every {
+                        f: Arc::clone(&f),
+                        period,
+                        running: Arc::clone(&running),
+                    },
+                });
+
+                if !running.swap(true, Ordering::SeqCst) {
+                    drop(state);
+                    let _ = shared.submit(Box::new(move || {
+                        // 用 guard 保证任务 panic 时也能清掉 running 标记
+                        let _guard = RunningGuard(running);
+                        f();
+                    }));
+                    state = lock(&inner.state);
+                }
+            }
+        }
+    }
+}
+
+struct RunningGuard(Arc<AtomicBool>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl ThreadPool {
+    /// Run `f` on the pool once `delay` has passed.
+    ///
+    /// The delay is tracked by a timer thread, so no worker sleeps while waiting.
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> ScheduledHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.timer.schedule(
+            &self.shared,
+            Instant::now() + delay,
+            Task::Once(Box::new(f)),
+        )
+    }
+
+    /// Run `f` on the pool every `period`, starting one `period` from now.
+    ///
+    /// Ticks are skipped while the previous run is still executing, and when
+    /// the pool falls behind. The task runs until its handle is cancelled or
+    /// the pool shuts down.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is zero.
+    pub fn execute_every<F>(&self, period: Duration, f: F) -> ScheduledHandle
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        assert!(!period.is_zero(), "`period` should more than 0");
+
+        self.shared.timer.schedule(
+            &self.shared,
+            Instant::now() + period,
+            Task::Every {
+                f: Arc::new(f),
+                period,
+                running: Arc::new(AtomicBool::new(false)),
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ThreadPool;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn runs_delayed_jobs_in_order() {
+        let pool = ThreadPool::new(1).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let start = Instant::now();
+
+        let later = tx.clone();
+        pool.execute_after(Duration::from_millis(100), move || later.send(2).unwrap());
+        pool.execute_after(Duration::from_millis(50), move || tx.send(1).unwrap());
+
+        assert_eq!(1, rx.recv().unwrap());
+        assert_eq!(2, rx.recv().unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn cancelled_jobs_never_run() {
+        let pool = ThreadPool::new(1).unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        let handle = pool.execute_after(Duration::from_millis(50), move || tx.send(()).unwrap());
+        handle.cancel();
+
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn periodic_jobs_repeat_until_cancelled() {
+        let pool = ThreadPool::new(2).unwrap();
+        let ticks = Arc::new(AtomicUsize::new(0));
+
+        let counter = Arc::clone(&ticks);
+        let handle = pool.execute_every(Duration::from_millis(20), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        thread::sleep(Duration::from_millis(200));
+        handle.cancel();
+        let after_cancel = ticks.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(100));
+
+        assert!(after_cancel >= 3);
+        // 取消时可能正好有一次已经交给 worker 了
+        assert!(ticks.load(Ordering::SeqCst) <= after_cancel + 1);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::server;
+use rustls::{Certificate, PrivateKey, ServerConfig, ServerConnection, StreamOwned};
+use std::fs::{self, File};
+use std::io::{self, BufReader, ErrorKind, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// 从 PEM 文件读取证书链和私钥, 生成 rustls 的服务端配置
+///
+/// 私钥支持 PKCS#8, PKCS#1(RSA) 和 SEC1(EC) 格式, 文件里有多个私钥时用第一个.
+pub fn load_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "no certificate found in {}",
+            cert_path.display()
+        )));
+    }
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key_path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid_data(format!("no private key found in {}", key_path.display())))?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid_data(e.to_string()))?;
+
+    Ok(Arc::new(config))
+}
+
+/// 生成本地开发用的自签名证书, 写到 `cert_path` 和 `key_path`
+///
+/// `hostnames` 是证书里的 subject alt names, 比如 `["localhost", "127.0.0.1"]`.
+/// 浏览器不会信任这个证书, 第一次访问时需要手动确认.
+pub fn generate_self_signed(
+    cert_path: &Path,
+    key_path: &Path,
+    hostnames: &[&str],
+) -> io::Result<()> {
+    let names: Vec<String> = hostnames.iter().map(|name| name.to_string()).collect();
+    let cert =
+        rcgen::generate_simple_self_signed(names).map_err(|e| invalid_data(e.to_string()))?;
+    let cert_pem = cert
+        .serialize_pem()
+        .map_err(|e| invalid_data(e.to_string()))?;
+
+    fs::write(cert_path, cert_pem)?;
+    fs::write(key_path, cert.serialize_private_key_pem())
+}
+
+/// 完成 TLS 握手后的连接
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// 在 `stream` 上完成服务端的 TLS 握手
+pub fn accept(config: &Arc<ServerConfig>, stream: TcpStream) -> io::Result<TlsStream> {
+    let connection = ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+    let mut stream = StreamOwned::new(connection, stream);
+
+    // 单独完成握手, 这样握手失败能和请求处理的错误区分开
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+
+    Ok(stream)
+}
+
+/// 发送 close_notify, 告诉客户端响应已经完整发完, 否则客户端会把连接关闭当成被截断
+pub fn close(mut stream: TlsStream) {
+    stream.conn.send_close_notify();
+    let _ = stream.flush();
+}
+
+impl server::Connection for TlsStream {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.sock.set_read_timeout(Some(timeout))
+    }
+}
+
+/// 在 TLS 握手完成后按普通 HTTP 连接处理, 线程池模式下作为 `server::serve` 的 handler
+pub fn handle_connection(config: &Arc<ServerConfig>, stream: TcpStream) {
+    let client = stream.peer_addr().ok();
+    // 客户端不信任自签名证书之类的握手失败只打一行日志, 不让 worker panic
+    let mut stream = match accept(config, stream) {
+        Ok(stream) => stream,
+        Err(e) => return eprintln!("TLS handshake failed: {}", e),
+    };
+
+    server::handle_connection(&mut stream, client);
+
+    close(stream);
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shutdown::ShutdownSignal;
+    use crate::ThreadPool;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn serves_https_with_self_signed_certificate() {
+        let dir = std::env::temp_dir().join(format!("web_server_tls_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        generate_self_signed(&cert_path, &key_path, &["localhost"]).unwrap();
+        let config = load_config(&cert_path, &key_path).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = ShutdownSignal::new();
+        let signal = shutdown.clone();
+        let server = thread::spawn(move || {
+            let pool = ThreadPool::new(1).unwrap();
+            server::serve(listener, &pool, &signal, move |stream| {
+                handle_connection(&config, stream)
+            });
+        });
+
+        // 客户端只信任刚生成的这张证书
+        let mut roots = RootCertStore::empty();
+        let der = rustls_pemfile::certs(&mut BufReader::new(File::open(&cert_path).unwrap()))
+            .unwrap()
+            .remove(0);
+        roots.add(&Certificate(der)).unwrap();
+        let client = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connection =
+            ClientConnection::new(Arc::new(client), "localhost".try_into().unwrap()).unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(addr).unwrap());
+
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        shutdown.trigger();
+        server.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
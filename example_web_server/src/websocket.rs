@@ -0,0 +1,537 @@
+use crate::http::{self, Response};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::io::{self, ErrorKind, Read, Write};
+
+// RFC 6455 里规定的固定 GUID, 和客户端的 key 拼起来算 Sec-WebSocket-Accept
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// 一条消息(包括分片拼起来之后)最大的长度, 超过了以 1009 关闭连接
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// 关闭码, 见 RFC 6455 7.4.1
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+/// 升级之后的底层连接, TCP 和 TLS 的连接都是
+pub trait Stream: Read + Write {}
+
+impl<S: Read + Write> Stream for S {}
+
+/// 用 `Site::websocket` 注册的处理函数, 返回之后连接就关掉了
+pub type Handler = fn(WebSocket<&mut dyn Stream>);
+
+/// 一条完整的 WebSocket 消息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// 对端发起关闭, 带着关闭码和原因
+    Close(Option<(u16, String)>),
+}
+
+/// 请求是不是 WebSocket 的升级请求
+pub fn is_upgrade(request: &str) -> bool {
+    let has_token = |name: &str, token: &str| {
+        http::header_value(request, name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|part| part.trim().eq_ignore_ascii_case(token))
+        })
+    };
+
+    request.starts_with("GET ")
+        && has_token("Upgrade", "websocket")
+        && has_token("Connection", "upgrade")
+}
+
+/// 根据客户端的 Sec-WebSocket-Key 计算 Sec-WebSocket-Accept
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.trim().as_bytes());
+    sha1.update(GUID.as_bytes());
+
+    BASE64.encode(sha1.finalize())
+}
+
+/// 完成握手, 写回 101 Switching Protocols, 之后 `stream` 上就是 WebSocket 帧了
+///
+/// 请求不合法时写回 400 并返回错误.
+pub fn upgrade<S: Read + Write>(mut stream: S, request: &str) -> io::Result<WebSocket<S>> {
+    let key = http::header_value(request, "Sec-WebSocket-Key");
+    let version = http::header_value(request, "Sec-WebSocket-Version");
+
+    let key = match (key, version) {
+        (Some(key), Some("13")) if is_upgrade(request) => key,
+        _ => {
+            let mut response = Response::new("HTTP/1.1 400 BAD REQUEST", Vec::new());
+            response.set_header("Sec-WebSocket-Version", "13");
+            response.write_to(&mut stream)?;
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "invalid websocket handshake",
+            ));
+        }
+    };
+
+    // 101 响应没有 body, 不能用 Response::write_to, 它会加上 Content-Length
+    write!(
+        stream,
+        "HTTP/1.1 101 SWITCHING PROTOCOLS\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )?;
+    stream.flush()?;
+
+    Ok(WebSocket {
+        stream,
+        closed: false,
+        pinged: false,
+    })
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// 握手之后的 WebSocket 连接, 服务端这一侧
+///
+/// ping 会自动回复 pong, 分片的消息会拼好再交给调用方.
+///
+/// 底层连接设置了读超时时, 超时就是心跳的间隔: 空闲了一个读超时先发一个 ping,
+/// 再过一个读超时对端还是什么都没发, 就以 1001 关闭连接.
+pub struct WebSocket<S> {
+    stream: S,
+    closed: bool,
+    // 发了 ping 之后还没有收到对端的任何帧
+    pinged: bool,
+}
+
+impl<S: Read + Write> WebSocket<S> {
+    /// 阻塞等待下一条消息
+    ///
+    /// 对端发来 close 时会回一个 close, 然后返回 `Message::Close`, 之后就不能再收发了.
+    /// 协议错误会以对应的关闭码关掉连接, 并返回 `ErrorKind::InvalidData`.
+    /// 对端不回应心跳时返回 `ErrorKind::TimedOut`.
+    pub fn recv(&mut self) -> io::Result<Message> {
+        if self.closed {
+            return Err(ErrorKind::NotConnected.into());
+        }
+
+        match self.read_message() {
+            Ok(message) => Ok(message),
+            Err(ReadError::Io(e)) => Err(e),
+            Err(ReadError::Idle) => {
+                let _ = self.close(CLOSE_GOING_AWAY, "ping timed out");
+                Err(io::Error::new(ErrorKind::TimedOut, "ping timed out"))
+            }
+            Err(ReadError::Protocol(code, reason)) => {
+                let _ = self.close(code, reason);
+                Err(io::Error::new(ErrorKind::InvalidData, reason))
+            }
+        }
+    }
+
+    fn read_message(&mut self) -> Result<Message, ReadError> {
+        // 正在拼的分片消息, 第一片决定是文本还是二进制
+        let mut fragmented: Option<(u8, Vec<u8>)> = None;
+
+        loop {
+            let frame = match self.read_frame() {
+                Ok(frame) => frame,
+                Err(ReadError::Idle) if !self.pinged => {
+                    self.write_frame(OP_PING, &[])?;
+                    self.pinged = true;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            match frame.opcode {
+                OP_PING => self.write_frame(OP_PONG, &frame.payload)?,
+                OP_PONG => {}
+                OP_CLOSE => {
+                    let close = parse_close(&frame.payload)?;
+                    // 回一个同样的关闭码, 完成关闭握手
+                    let code = close.as_ref().map_or(CLOSE_NORMAL, |(code, _)| *code);
+                    self.close(code, "")?;
+                    return Ok(Message::Close(close));
+                }
+                OP_TEXT | OP_BINARY => {
+                    if fragmented.is_some() {
+                        return Err(ReadError::Protocol(
+                            CLOSE_PROTOCOL_ERROR,
+                            "new message before the fragmented one finished",
+                        ));
+                    }
+                    if frame.fin {
+                        return to_message(frame.opcode, frame.payload);
+                    }
+                    fragmented = Some((frame.opcode, frame.payload));
+                }
+                OP_CONTINUATION => {
+                    let Some((opcode, mut payload)) = fragmented.take() else {
+                        return Err(ReadError::Protocol(
+                            CLOSE_PROTOCOL_ERROR,
+                            "continuation frame without a message",
+                        ));
+                    };
+                    if payload.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                        return Err(ReadError::Protocol(CLOSE_TOO_BIG, "message too big"));
+                    }
+                    payload.extend_from_slice(&frame.payload);
+
+                    if frame.fin {
+                        return to_message(opcode, payload);
+                    }
+                    fragmented = Some((opcode, payload));
+                }
+                _ => return Err(ReadError::Protocol(CLOSE_PROTOCOL_ERROR, "unknown opcode")),
+            }
+        }
+    }
+
+    fn read_frame(&mut self) -> Result<Frame, ReadError> {
+        let mut header = [0; 2];
+        // 还没读到新帧的第一个字节就超时了, 只是连接空闲, 不是错误;
+        // 读到一半超时的话流已经不完整了, 只能当作错误
+        if let Err(e) = self.stream.read_exact(&mut header[..1]) {
+            return Err(match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => ReadError::Idle,
+                _ => e.into(),
+            });
+        }
+        self.stream.read_exact(&mut header[1..])?;
+        self.pinged = false;
+
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0F;
+        let masked = header[1] & 0x80 != 0;
+
+        if header[0] & 0x70 != 0 {
+            return Err(ReadError::Protocol(
+                CLOSE_PROTOCOL_ERROR,
+                "reserved bits are set",
+            ));
+        }
+        // 客户端发来的帧必须带掩码
+        if !masked {
+            return Err(ReadError::Protocol(
+                CLOSE_PROTOCOL_ERROR,
+                "client frame is not masked",
+            ));
+        }
+        let is_control = opcode & 0x8 != 0;
+        if is_control && (!fin || header[1] & 0x7F > 125) {
+            return Err(ReadError::Protocol(
+                CLOSE_PROTOCOL_ERROR,
+                "control frames must not be fragmented or longer than 125 bytes",
+            ));
+        }
+
+        let length = match header[1] & 0x7F {
+            126 => {
+                let mut length = [0; 2];
+                self.stream.read_exact(&mut length)?;
+                u16::from_be_bytes(length) as u64
+            }
+            127 => {
+                let mut length = [0; 8];
+                self.stream.read_exact(&mut length)?;
+                u64::from_be_bytes(length)
+            }
+            length => length as u64,
+        };
+        if length > MAX_MESSAGE_SIZE as u64 {
+            return Err(ReadError::Protocol(CLOSE_TOO_BIG, "message too big"));
+        }
+
+        let mut mask = [0; 4];
+        self.stream.read_exact(&mut mask)?;
+        let mut payload = vec![0; length as usize];
+        self.stream.read_exact(&mut payload)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        })
+    }
+
+    /// 发送一条消息, 服务端发出的帧不带掩码, 也不分片
+    pub fn send(&mut self, message: Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.write_frame(OP_TEXT, text.as_bytes()),
+            Message::Binary(data) => self.write_frame(OP_BINARY, &data),
+            Message::Close(None) => self.close(CLOSE_NORMAL, ""),
+            Message::Close(Some((code, reason))) => self.close(code, &reason),
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.write_frame(OP_TEXT, text.as_bytes())
+    }
+
+    /// 主动关闭, 只发送 close 帧, 不等对端的回复
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+
+        let mut payload = code.to_be_bytes().to_vec();
+        // close 帧的 payload 不能超过 125 字节, 关闭码占了 2 个
+        payload.extend(reason.bytes().take(123));
+        let result = self.write_frame(OP_CLOSE, &payload);
+        self.closed = true;
+
+        result
+    }
+
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if self.closed {
+            return Err(ErrorKind::NotConnected.into());
+        }
+
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            length @ 0..=125 => frame.push(length as u8),
+            length @ 126..=0xFFFF => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+enum ReadError {
+    Io(io::Error),
+    // 在两帧之间读超时了
+    Idle,
+    // 对端违反了协议, 用这个关闭码和原因关掉连接
+    Protocol(u16, &'static str),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+fn to_message(opcode: u8, payload: Vec<u8>) -> Result<Message, ReadError> {
+    if opcode == OP_BINARY {
+        return Ok(Message::Binary(payload));
+    }
+
+    String::from_utf8(payload)
+        .map(Message::Text)
+        .map_err(|_| ReadError::Protocol(CLOSE_INVALID_DATA, "text message is not valid UTF-8"))
+}
+
+fn parse_close(payload: &[u8]) -> Result<Option<(u16, String)>, ReadError> {
+    match payload {
+        [] => Ok(None),
+        [_] => Err(ReadError::Protocol(
+            CLOSE_PROTOCOL_ERROR,
+            "invalid close payload",
+        )),
+        [high, low, reason @ ..] => {
+            let reason = std::str::from_utf8(reason).map_err(|_| {
+                ReadError::Protocol(CLOSE_INVALID_DATA, "close reason is not valid UTF-8")
+            })?;
+
+            Ok(Some((
+                u16::from_be_bytes([*high, *low]),
+                reason.to_string(),
+            )))
+        }
+    }
+}
+
+/// 把收到的文本和二进制消息原样发回去, 直到对端关闭连接
+pub fn echo(mut socket: WebSocket<&mut dyn Stream>) {
+    loop {
+        match socket.recv() {
+            Ok(Message::Close(_)) | Err(_) => return,
+            Ok(message) => {
+                if socket.send(message).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // 输入是预先准备好的客户端帧, 输出收集服务端写出的内容
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+        // 读到这些位置时先返回一次读超时
+        stalls: Vec<u64>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.stalls.first() == Some(&self.input.position()) {
+                self.stalls.remove(0);
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![(fin as u8) << 7 | opcode];
+        match payload.len() {
+            length @ 0..=125 => frame.push(0x80 | length as u8),
+            length => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn socket(frames: &[Vec<u8>]) -> WebSocket<Duplex> {
+        WebSocket {
+            stream: Duplex {
+                input: Cursor::new(frames.concat()),
+                output: Vec::new(),
+                stalls: Vec::new(),
+            },
+            closed: false,
+            pinged: false,
+        }
+    }
+
+    #[test]
+    fn computes_accept_key_from_rfc_example() {
+        assert_eq!(
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+            accept_key("dGhlIHNhbXBsZSBub25jZQ==")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_handshake() {
+        let mut output = Duplex {
+            input: Cursor::new(Vec::new()),
+            output: Vec::new(),
+            stalls: Vec::new(),
+        };
+        let request = "GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+
+        assert!(upgrade(&mut output, request).is_err());
+        assert!(output.output.starts_with(b"HTTP/1.1 400 BAD REQUEST\r\n"));
+    }
+
+    #[test]
+    fn assembles_fragments_and_answers_pings_in_between() {
+        let long = "x".repeat(300);
+        let mut socket = socket(&[
+            client_frame(false, OP_TEXT, b"hel"),
+            client_frame(true, OP_PING, b"are you there"),
+            client_frame(true, OP_CONTINUATION, b"lo"),
+            client_frame(true, OP_BINARY, long.as_bytes()),
+            client_frame(true, OP_CLOSE, &[0x03, 0xE8, b'b', b'y', b'e']),
+        ]);
+
+        assert_eq!(Message::Text("hello".to_string()), socket.recv().unwrap());
+        assert_eq!(Message::Binary(long.into_bytes()), socket.recv().unwrap());
+        assert_eq!(
+            Message::Close(Some((CLOSE_NORMAL, "bye".to_string()))),
+            socket.recv().unwrap()
+        );
+        assert!(socket.recv().is_err());
+
+        // 先是 pong, 然后是回应的 close
+        let mut expected = vec![0x80 | OP_PONG, 13];
+        expected.extend_from_slice(b"are you there");
+        expected.extend_from_slice(&[0x80 | OP_CLOSE, 2, 0x03, 0xE8]);
+        assert_eq!(expected, socket.stream.output);
+    }
+
+    #[test]
+    fn closes_with_protocol_error_on_unmasked_frames() {
+        let mut socket = socket(&[vec![0x80 | OP_TEXT, 2, b'h', b'i']]);
+
+        let error = socket.recv().unwrap_err();
+
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+        let output = &socket.stream.output;
+        assert_eq!(0x80 | OP_CLOSE, output[0]);
+        assert_eq!([0x03, 0xEA], output[2..4]);
+    }
+
+    #[test]
+    fn pings_idle_clients_and_keeps_the_connection_when_they_answer() {
+        let pong = client_frame(true, OP_PONG, b"");
+        let mut socket = socket(&[pong.clone(), client_frame(true, OP_TEXT, b"hi")]);
+        // 空闲一次发 ping, 收到 pong 之后又空闲一次, 还是只发 ping
+        socket.stream.stalls = vec![0, pong.len() as u64];
+
+        assert_eq!(Message::Text("hi".to_string()), socket.recv().unwrap());
+        assert_eq!(
+            vec![0x80 | OP_PING, 0, 0x80 | OP_PING, 0],
+            socket.stream.output
+        );
+    }
+
+    #[test]
+    fn closes_with_going_away_when_pings_go_unanswered() {
+        let mut socket = socket(&[client_frame(true, OP_TEXT, b"hi")]);
+        socket.stream.stalls = vec![0, 0];
+
+        let error = socket.recv().unwrap_err();
+
+        assert_eq!(ErrorKind::TimedOut, error.kind());
+        let output = &socket.stream.output;
+        assert_eq!([0x80 | OP_PING, 0, 0x80 | OP_CLOSE], output[..3]);
+        assert_eq!([0x03, 0xE9], output[4..6]);
+    }
+}
@@ -0,0 +1,331 @@
+// 集成测试用的 server 和 HTTP/1.1 客户端
+//
+// server 跑在同一个进程里, 监听 127.0.0.1 上随机的端口, 测试之间互不影响.
+// 站点配置是全局的, 所有测试共用 `install_site` 里的那一份.
+
+#![allow(dead_code)]
+
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::Once;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use web_server::cgi::Cgi;
+use web_server::fastcgi::{Address, FastCgi};
+use web_server::http::Limits;
+use web_server::shutdown::ShutdownSignal;
+use web_server::site::{self, Site};
+use web_server::websocket;
+use web_server::{event_loop, server, ShutdownReport, ThreadPool};
+
+/// `/slow` 响应前等这么久
+pub const SLOW_DELAY: Duration = Duration::from_millis(300);
+/// 一个连接上最多处理的请求数
+pub const MAX_KEEP_ALIVE_REQUESTS: usize = 5;
+/// 两次读之间最多等多久
+pub const READ_TIMEOUT: Duration = Duration::from_secs(1);
+/// 保持的连接空闲多久后被关掉
+pub const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_millis(200);
+/// 最多同时打开的 WebSocket 连接, 比 `TestServer::start` 的 4 个 worker 少
+pub const MAX_WEBSOCKETS: usize = 2;
+
+// 测试在 crate 目录下运行, 直接用仓库里的 index.html 等文件
+fn install_site() {
+    static INSTALL: Once = Once::new();
+
+    INSTALL.call_once(|| {
+        let limits = Limits {
+            max_uri_length: 256,
+            max_header_size: 1024,
+            max_headers: 10,
+            max_body_size: 1024,
+            read_timeout: READ_TIMEOUT,
+            write_timeout: Duration::from_secs(1),
+            request_timeout: Duration::from_secs(2),
+            max_keep_alive_requests: MAX_KEEP_ALIVE_REQUESTS,
+            keep_alive_timeout: KEEP_ALIVE_TIMEOUT,
+            max_websockets: MAX_WEBSOCKETS,
+        };
+
+        site::install(
+            Site::new()
+                .route("/", "index.html")
+                .route("/data", "data.json")
+                .delayed_route("/slow", "data.json", SLOW_DELAY)
+                .static_dir("/static", "tests/fixtures")
+                .cgi(Cgi::new("/cgi-bin", "cgi-bin"))
+                // 没有应用在监听, 能走到连接这一步的请求都是 502
+                .fastcgi(FastCgi::new(
+                    "/fcgi",
+                    Address::Unix("tests/fixtures/missing.sock".into()),
+                    "tests/fixtures",
+                ))
+                .websocket("/ws", websocket::echo)
+                .not_found("404.html")
+                .limits(limits),
+        );
+    });
+}
+
+/// 在后台线程上运行的 server, drop 时会关掉
+pub struct TestServer {
+    addr: SocketAddr,
+    shutdown: ShutdownSignal,
+    thread: Option<JoinHandle<ShutdownReport>>,
+}
+
+impl TestServer {
+    /// 线程池模式, 4 个 worker
+    pub fn start() -> TestServer {
+        TestServer::with_pool(ThreadPool::new(4).unwrap())
+    }
+
+    pub fn with_pool(pool: ThreadPool) -> TestServer {
+        TestServer::spawn(move |listener, shutdown| {
+            server::thread_poll(listener, pool, &shutdown, Duration::from_secs(5))
+        })
+    }
+
+    /// 事件循环模式, 4 个 worker
+    pub fn epoll() -> TestServer {
+        TestServer::spawn(|listener, shutdown| {
+            let pool = ThreadPool::new(4).unwrap();
+            event_loop::run(listener, pool, &shutdown, Duration::from_secs(5)).unwrap()
+        })
+    }
+
+    fn spawn<F>(run: F) -> TestServer
+    where
+        F: FnOnce(TcpListener, ShutdownSignal) -> ShutdownReport + Send + 'static,
+    {
+        install_site();
+
+        // 端口 0 让系统分配一个空闲的端口
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = ShutdownSignal::new();
+
+        let signal = shutdown.clone();
+        let thread = thread::spawn(move || run(listener, signal));
+
+        TestServer {
+            addr,
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn connect(&self) -> Client {
+        Client::connect(self.addr)
+    }
+
+    /// 发一个原始的请求, 发完就关掉写的一端, 返回读到 EOF 为止的全部响应
+    pub fn send(&self, request: &[u8]) -> Vec<u8> {
+        let mut client = self.connect();
+        client.send(request);
+        client.stream.get_ref().shutdown(Shutdown::Write).unwrap();
+        client.read_to_end()
+    }
+
+    /// 在新连接上发一个 `Connection: close` 的 GET 请求
+    pub fn get(&self, path: &str) -> HttpResponse {
+        let mut client = self.connect();
+        client.send(
+            format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                path
+            )
+            .as_bytes(),
+        );
+        client.read_response().unwrap()
+    }
+
+    /// 触发关闭并等 server 退出
+    pub fn stop(mut self) -> ShutdownReport {
+        self.shutdown.trigger();
+        self.thread.take().unwrap().join().unwrap()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.shutdown.trigger();
+            // 测试已经失败时不要再 panic 一次
+            let _ = thread.join();
+        }
+    }
+}
+
+/// 一个连接, 可以在上面连着发多个请求
+pub struct Client {
+    stream: BufReader<TcpStream>,
+}
+
+impl Client {
+    pub fn connect(addr: SocketAddr) -> Client {
+        let stream = TcpStream::connect(addr).unwrap();
+        // 测试里的 server 出问题时不会卡住整个测试
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+
+        Client {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    pub fn send(&mut self, bytes: &[u8]) {
+        self.stream.get_mut().write_all(bytes).unwrap();
+    }
+
+    pub fn get(&mut self, path: &str) -> HttpResponse {
+        self.send(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes());
+        self.read_response().unwrap()
+    }
+
+    /// 读一个响应, 连接在响应开始之前就关了时返回 `UnexpectedEof`
+    pub fn read_response(&mut self) -> io::Result<HttpResponse> {
+        read_response(&mut self.stream)
+    }
+
+    pub fn read_to_end(&mut self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.stream.read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// 发 WebSocket 升级请求, 返回握手的响应
+    pub fn upgrade(&mut self, path: &str) -> HttpResponse {
+        self.send(
+            format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                 Sec-WebSocket-Version: 13\r\n\r\n",
+                path
+            )
+            .as_bytes(),
+        );
+        self.read_response().unwrap()
+    }
+
+    /// 发一个带掩码的 WebSocket 帧, payload 不超过 125 字节
+    pub fn send_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        self.send(&frame);
+    }
+
+    /// 读一个 server 发来的 WebSocket 帧, 返回 opcode 和 payload
+    pub fn read_frame(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let mut header = [0; 2];
+        self.stream.read_exact(&mut header)?;
+        let length = match header[1] {
+            126 => {
+                let mut length = [0; 2];
+                self.stream.read_exact(&mut length)?;
+                u16::from_be_bytes(length) as usize
+            }
+            length => length as usize,
+        };
+        let mut payload = vec![0; length];
+        self.stream.read_exact(&mut payload)?;
+        Ok((header[0] & 0x0F, payload))
+    }
+
+    /// server 已经关掉了这个连接(读到 EOF 或者被重置)
+    pub fn is_closed(&mut self) -> bool {
+        let mut byte = [0; 1];
+        matches!(self.stream.read(&mut byte), Ok(0) | Err(_))
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn closes_connection(&self) -> bool {
+        self.header("Connection")
+            .is_some_and(|value| value.eq_ignore_ascii_case("close"))
+    }
+}
+
+/// 解析一个完整的原始响应, 后面不能再有别的数据
+pub fn parse_response(mut bytes: &[u8]) -> HttpResponse {
+    let response = read_response(&mut bytes).unwrap();
+    assert!(bytes.is_empty(), "unexpected data after the response");
+    response
+}
+
+// body 的长度按 Content-Length 算, server 的响应都带着它
+fn read_response<R: BufRead>(reader: &mut R) -> io::Result<HttpResponse> {
+    let mut status_line = String::new();
+    if reader.read_line(&mut status_line)? == 0 {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    let mut parts = status_line.trim_end().splitn(3, ' ');
+    let version = parts.next().unwrap_or_default().to_string();
+    let status = parts
+        .next()
+        .and_then(|status| status.parse().ok())
+        .unwrap_or_else(|| panic!("invalid status line {:?}", status_line));
+    let reason = parts.next().unwrap_or_default().to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .unwrap_or_else(|| panic!("invalid header {:?}", line));
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+
+    let mut response = HttpResponse {
+        version,
+        status,
+        reason,
+        headers,
+        body: Vec::new(),
+    };
+    // 101 之后连接上就不是 HTTP 了, 没有 body
+    let length = match response.status {
+        101 => 0,
+        _ => response
+            .header("Content-Length")
+            .expect("response without Content-Length")
+            .parse()
+            .unwrap(),
+    };
+    response.body = vec![0; length];
+    reader.read_exact(&mut response.body)?;
+
+    Ok(response)
+}
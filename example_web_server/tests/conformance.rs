@@ -0,0 +1,392 @@
+// 对着真正跑起来的 server 发请求, 检查 HTTP/1.1 的行为
+//
+// 路由见 common::install_site, 用的是 crate 目录下的 index.html, 404.html 和 data.json.
+
+mod common;
+
+use common::{
+    parse_response, Client, TestServer, KEEP_ALIVE_TIMEOUT, MAX_KEEP_ALIVE_REQUESTS, READ_TIMEOUT,
+    SLOW_DELAY,
+};
+use std::fs;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+use web_server::ThreadPool;
+
+// 路由
+
+#[test]
+fn serves_index_for_root_and_ignores_query_string() {
+    let server = TestServer::start();
+    let index = fs::read("index.html").unwrap();
+
+    for path in ["/", "/?page=2", "/#top"] {
+        let response = server.get(path);
+
+        assert_eq!(
+            ("HTTP/1.1", 200),
+            (response.version.as_str(), response.status)
+        );
+        assert_eq!(Some("text/html"), response.header("Content-Type"));
+        assert_eq!(
+            Some(index.len().to_string().as_str()),
+            response.header("Content-Length")
+        );
+        assert_eq!(index, response.body);
+    }
+}
+
+#[test]
+fn serves_routes_static_files_and_cgi_scripts() {
+    let server = TestServer::start();
+
+    let data = server.get("/data");
+    assert_eq!(200, data.status);
+    assert_eq!(Some("application/json"), data.header("Content-Type"));
+    assert_eq!(fs::read("data.json").unwrap(), data.body);
+
+    let file = server.get("/static/hello.txt");
+    assert_eq!(200, file.status);
+    assert_eq!(Some("text/plain"), file.header("Content-Type"));
+    assert_eq!("hello from a static file\n", file.text());
+
+    let mut client = server.connect();
+    client.send(
+        b"POST /cgi-bin/hello.sh?x=1 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\nabc",
+    );
+    let script = client.read_response().unwrap();
+    assert_eq!(200, script.status);
+    assert!(script.text().contains("method: POST\nquery: x=1\n"));
+    assert!(script.text().ends_with("body: abc\n"));
+}
+
+#[test]
+fn answers_unknown_paths_with_404_page() {
+    let server = TestServer::start();
+    let not_found = fs::read("404.html").unwrap();
+
+    for path in [
+        "/missing",
+        "/static/missing.txt",
+        "/static/../Cargo.toml",
+        "/cgi-bin/missing.sh",
+        "/fcgi/../../etc/passwd",
+        "/fcgi/./hello.txt",
+        "/fcgi//hello.txt",
+    ] {
+        let response = server.get(path);
+
+        assert_eq!(404, response.status, "{}", path);
+        assert_eq!(Some("text/html"), response.header("Content-Type"));
+    }
+    assert_eq!(not_found, server.get("/missing").body);
+    assert_eq!(502, server.get("/fcgi/hello.txt").status);
+}
+
+// 格式不对和超过限制的请求
+
+#[test]
+fn rejects_malformed_requests_with_400_and_closes() {
+    let server = TestServer::start();
+
+    for request in [
+        &b"GARBAGE\r\n\r\n"[..],
+        b"GET /\r\n\r\n",
+        b"GET index.html HTTP/1.1\r\n\r\n",
+        b"GET / HTTP/2.0\r\n\r\n",
+        b"GET / HTTP/1.1\r\nno colon here\r\n\r\n",
+        b"GET / HTTP/1.1\r\nBad Name: x\r\n\r\n",
+        b"POST / HTTP/1.1\r\nContent-Length: ten\r\n\r\n",
+        // 请求还没发完连接就关了
+        b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc",
+    ] {
+        let response = parse_response(&server.send(request));
+
+        assert_eq!(
+            400,
+            response.status,
+            "{:?}",
+            String::from_utf8_lossy(request)
+        );
+        assert!(response.closes_connection());
+    }
+}
+
+#[test]
+fn rejects_requests_over_the_limits() {
+    let server = TestServer::start();
+    let status = |request: Vec<u8>| parse_response(&server.send(&request)).status;
+
+    let long_uri = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(300));
+    assert_eq!(414, status(long_uri.into_bytes()));
+
+    let large_header = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(2000));
+    assert_eq!(431, status(large_header.into_bytes()));
+
+    let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: 1\r\n".repeat(11));
+    assert_eq!(431, status(many_headers.into_bytes()));
+
+    let large_body = b"POST / HTTP/1.1\r\nContent-Length: 2000\r\n\r\n".to_vec();
+    assert_eq!(413, status(large_body));
+
+    let chunked = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n".to_vec();
+    assert_eq!(501, status(chunked));
+}
+
+#[test]
+fn times_out_clients_that_never_finish_the_request() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    let started = Instant::now();
+
+    client.send(b"GET / HTTP/1.1\r\n");
+    let response = client.read_response().unwrap();
+
+    assert_eq!(408, response.status);
+    assert!(response.closes_connection());
+    assert!(started.elapsed() >= READ_TIMEOUT);
+    assert!(client.is_closed());
+}
+
+// 保持连接
+
+#[test]
+fn keeps_http_1_1_connections_alive() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+
+    for path in ["/", "/data", "/missing"] {
+        let response = client.get(path);
+
+        assert_ne!(0, response.body.len());
+        assert!(!response.closes_connection(), "{}", path);
+    }
+}
+
+#[test]
+fn answers_pipelined_requests_in_order() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+
+    client.send(b"GET /data HTTP/1.1\r\n\r\nGET /missing HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n");
+
+    let statuses: Vec<u16> = (0..3)
+        .map(|_| client.read_response().unwrap().status)
+        .collect();
+    assert_eq!(vec![200, 404, 200], statuses);
+}
+
+#[test]
+fn closes_when_the_client_asks_to() {
+    let server = TestServer::start();
+
+    let mut client = server.connect();
+    client.send(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(client.read_response().unwrap().closes_connection());
+    assert!(client.is_closed());
+
+    // HTTP/1.0 默认不保持连接
+    let mut client = server.connect();
+    client.send(b"GET / HTTP/1.0\r\n\r\n");
+    assert!(client.read_response().unwrap().closes_connection());
+    assert!(client.is_closed());
+
+    let mut client = server.connect();
+    client.send(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+    let response = client.read_response().unwrap();
+    assert_eq!(Some("keep-alive"), response.header("Connection"));
+    assert_eq!(200, client.get("/").status);
+}
+
+#[test]
+fn closes_after_max_keep_alive_requests() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+
+    for i in 1..=MAX_KEEP_ALIVE_REQUESTS {
+        let response = client.get("/");
+
+        assert_eq!(i == MAX_KEEP_ALIVE_REQUESTS, response.closes_connection());
+    }
+    assert!(client.is_closed());
+}
+
+#[test]
+fn closes_idle_connections_without_a_response() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    assert_eq!(200, client.get("/").status);
+
+    let started = Instant::now();
+
+    // 不是 408, 连接直接被关掉, 而且不用等到 read_timeout
+    assert!(client.read_to_end().is_empty());
+    assert!(started.elapsed() >= KEEP_ALIVE_TIMEOUT - Duration::from_millis(50));
+    assert!(started.elapsed() < READ_TIMEOUT);
+}
+
+#[test]
+fn serves_new_clients_while_idle_keep_alive_connections_outnumber_workers() {
+    let server = TestServer::with_pool(ThreadPool::new(2).unwrap());
+
+    // 两倍于 worker 的连接各发一个请求, 收到响应后保持着, 什么都不再发
+    let mut idle: Vec<_> = (0..4)
+        .map(|_| {
+            let mut client = server.connect();
+            client.send(b"GET / HTTP/1.1\r\n\r\n");
+            client
+        })
+        .collect();
+
+    // 排在它们后面的客户端要等它们空闲超时, 让出 worker, 但不用等到 read_timeout
+    let started = Instant::now();
+    let mut client = server.connect();
+    assert_eq!(200, client.get("/").status);
+    assert!(
+        started.elapsed() < READ_TIMEOUT,
+        "waited {:?}",
+        started.elapsed()
+    );
+
+    for client in &mut idle {
+        assert_eq!(200, client.read_response().unwrap().status);
+    }
+}
+
+#[test]
+fn keeps_idle_websockets_open_while_the_client_answers_pings() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    assert_eq!(101, client.upgrade("/ws").status);
+
+    // 空闲超过读超时之后 server 发 ping, 而不是关掉连接
+    thread::sleep(READ_TIMEOUT + READ_TIMEOUT / 2);
+    assert_eq!((0x9, Vec::new()), client.read_frame().unwrap());
+
+    client.send_frame(0xA, b"");
+    client.send_frame(0x1, b"still here");
+    assert_eq!((0x1, b"still here".to_vec()), client.read_frame().unwrap());
+}
+
+#[test]
+fn closes_websockets_that_ignore_pings() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    assert_eq!(101, client.upgrade("/ws").status);
+
+    let started = Instant::now();
+
+    assert_eq!((0x9, Vec::new()), client.read_frame().unwrap());
+    let (opcode, payload) = client.read_frame().unwrap();
+    assert_eq!(0x8, opcode);
+    assert_eq!([0x03, 0xE9], payload[..2]);
+    assert!(client.is_closed());
+    assert!(started.elapsed() >= READ_TIMEOUT * 2 - Duration::from_millis(100));
+}
+
+#[test]
+fn closes_connections_after_error_responses() {
+    let server = TestServer::start();
+    let mut client = server.connect();
+    assert_eq!(200, client.get("/").status);
+
+    client.send(b"BROKEN\r\n\r\n");
+    let response = client.read_response().unwrap();
+
+    assert_eq!(400, response.status);
+    assert!(client.is_closed());
+}
+
+// 并发
+
+#[test]
+fn handles_slow_requests_concurrently_on_the_pool() {
+    let server = TestServer::with_pool(ThreadPool::new(8).unwrap());
+    let started = Instant::now();
+
+    let clients: Vec<_> = (0..8)
+        .map(|_| {
+            let addr = server.addr();
+            thread::spawn(move || {
+                let mut client = Client::connect(addr);
+                client.get("/slow").status
+            })
+        })
+        .collect();
+    for client in clients {
+        assert_eq!(200, client.join().unwrap());
+    }
+
+    // 串行的话要 8 倍的时间
+    assert!(
+        started.elapsed() < SLOW_DELAY * 4,
+        "took {:?}",
+        started.elapsed()
+    );
+}
+
+#[test]
+fn queues_connections_when_all_workers_are_busy() {
+    let server = TestServer::with_pool(ThreadPool::new(2).unwrap());
+    let started = Instant::now();
+
+    let clients: Vec<_> = (0..6)
+        .map(|_| {
+            let addr = server.addr();
+            thread::spawn(move || {
+                let mut client = Client::connect(addr);
+                client.send(b"GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n");
+                client.read_response().unwrap().status
+            })
+        })
+        .collect();
+    for client in clients {
+        assert_eq!(200, client.join().unwrap());
+    }
+
+    // 两个 worker 处理 6 个请求, 至少要 3 轮
+    assert!(started.elapsed() >= SLOW_DELAY * 3);
+}
+
+// 关闭
+
+#[test]
+fn finishes_in_flight_requests_and_refuses_new_ones_on_shutdown() {
+    let server = TestServer::start();
+    let addr = server.addr();
+
+    let mut client = server.connect();
+    client.send(b"GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n");
+    // 等请求交给 worker 之后再关
+    thread::sleep(SLOW_DELAY / 3);
+
+    let report = server.stop();
+
+    assert!(report.timed_out.is_empty());
+    assert_eq!(200, client.read_response().unwrap().status);
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn event_loop_serves_requests_and_closes_after_each_response() {
+    let server = TestServer::epoll();
+
+    let response = server.get("/data");
+    assert_eq!(200, response.status);
+    assert_eq!(fs::read("data.json").unwrap(), response.body);
+
+    // 事件循环模式不保持连接, 要明确告诉 HTTP/1.1 的客户端
+    let mut client = server.connect();
+    let response = client.get("/missing");
+    assert_eq!(404, response.status);
+    assert!(response.closes_connection());
+    assert!(client.is_closed());
+
+    let response = parse_response(&server.send(b"GARBAGE\r\n\r\n"));
+    assert_eq!(400, response.status);
+
+    let addr = server.addr();
+    assert!(server.stop().timed_out.is_empty());
+    assert!(TcpStream::connect(addr).is_err());
+}
@@ -0,0 +1,38 @@
+// WebSocket 连接数的上限
+//
+// 名额是整个进程共用的, 和 conformance 里的 WebSocket 测试放在一起会互相抢名额,
+// 所以单独放在一个测试程序里.
+
+mod common;
+
+use common::{TestServer, MAX_WEBSOCKETS};
+
+#[test]
+fn rejects_websocket_upgrades_over_the_limit_with_503() {
+    let server = TestServer::start();
+    let mut sockets: Vec<_> = (0..MAX_WEBSOCKETS)
+        .map(|_| {
+            let mut client = server.connect();
+            assert_eq!(101, client.upgrade("/ws").status);
+            client
+        })
+        .collect();
+
+    let mut client = server.connect();
+    let response = client.upgrade("/ws");
+    assert_eq!(503, response.status);
+    assert!(response.closes_connection());
+    assert!(client.is_closed());
+
+    // 剩下的 worker 照样处理普通请求
+    assert_eq!(200, server.get("/").status);
+
+    // 正常关掉一个之后, 名额还回来了
+    let mut socket = sockets.pop().unwrap();
+    socket.send_frame(0x8, &1000u16.to_be_bytes());
+    assert_eq!(0x8, socket.read_frame().unwrap().0);
+    assert!(socket.is_closed());
+
+    let mut client = server.connect();
+    assert_eq!(101, client.upgrade("/ws").status);
+}